@@ -1,23 +1,48 @@
 use crate::error::AppError;
+use crate::library_watcher::{watch_library, WatchedSong};
 use crate::media_server::MediaServerState;
-use crate::ultrastar::filesystem::traverse_and_find_txt_files;
-use crate::ultrastar::parser::parse_local_txt_file;
-use crate::ultrastar::song::LocalSong;
+use crate::ultrastar::encoding::{convert_txt_to_utf8, encode_txt};
+use crate::ultrastar::filesystem::{
+    traverse_and_find_txt_files, FileEntry, FolderWarning, TraversalOptions,
+};
+use crate::ultrastar::library_index::{LibraryIndex, SongFileKey};
+use crate::ultrastar::lint::{lint_song, LintFinding};
+use crate::ultrastar::loudness::LoudnessCache;
+use crate::ultrastar::lyrics::{export_lyrics, LyricsFormat};
+use crate::ultrastar::midi::write_midi;
+use crate::ultrastar::parser::{parse_local_song_file, read_song_file};
+use crate::ultrastar::song::{LocalSong, Song};
+use crate::ultrastar::timeline::{build_song_timeline, SongTimeline};
+use crate::ultrastar::writer::write_ultrastar_txt;
+use crate::AppState;
 use log;
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
-use tauri::State;
+use std::time::{Duration, Instant};
+use tauri::{Manager, State};
 use tauri_plugin_fs::FsExt;
 use tauri_specta::Event;
+use tokio::sync::MutexGuard as TokioMutexGuard;
 use tokio::task;
 
+/// Sent at most every `PROGRESS_INTERVAL` while songs are parsed.
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
 pub struct ProgressEvent {
+    /// The song parsed last.
     pub song: String,
+    pub processed: u32,
+    pub total: u32,
+    /// Estimated time until all songs are parsed.
+    pub eta_ms: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
 pub struct StartParsingEvent {
     pub total_songs: i32,
 }
@@ -28,6 +53,131 @@ pub struct SongGroup {
     pub songs: Vec<LocalSong>,
 }
 
+/// A song that could not be loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct SongScanFailure {
+    pub path: String,
+    /// The `AppError` variant, e.g. `UltrastarParseError`.
+    pub kind: String,
+    pub message: String,
+}
+
+/// A folder below the song paths that could not be read.
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct SongScanWarning {
+    pub path: String,
+    pub message: String,
+}
+
+impl From<FolderWarning> for SongScanWarning {
+    fn from(warning: FolderWarning) -> Self {
+        Self {
+            path: warning.path,
+            message: warning.message,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct SongScanReport {
+    pub groups: Vec<SongGroup>,
+    pub failures: Vec<SongScanFailure>,
+    pub warnings: Vec<SongScanWarning>,
+    /// Set if the scan was cancelled, the groups then only hold the songs
+    /// parsed until then.
+    pub cancelled: bool,
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counts the parsed songs across the parsing workers and throttles the
+/// progress events.
+struct ScanProgress {
+    app_handle: tauri::AppHandle,
+    total: u32,
+    started_at: Instant,
+    processed: AtomicU32,
+    last_emitted_at: Mutex<Option<Instant>>,
+}
+
+impl ScanProgress {
+    fn new(app_handle: tauri::AppHandle, total: u32) -> Self {
+        Self {
+            app_handle,
+            total,
+            started_at: Instant::now(),
+            processed: AtomicU32::new(0),
+            last_emitted_at: Mutex::new(None),
+        }
+    }
+
+    fn advance(&self, song: &str) {
+        let processed = self.processed.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let Ok(mut last_emitted_at) = self.last_emitted_at.lock() else {
+            return;
+        };
+        let is_due = last_emitted_at.is_none_or(|at| at.elapsed() >= PROGRESS_INTERVAL);
+        if !is_due && processed < self.total {
+            return;
+        }
+        *last_emitted_at = Some(Instant::now());
+
+        let elapsed_ms = self.started_at.elapsed().as_millis() as f64;
+        let remaining = self.total.saturating_sub(processed);
+        let eta_ms = (elapsed_ms / processed as f64 * remaining as f64) as u32;
+
+        if let Err(e) = (ProgressEvent {
+            song: song.to_string(),
+            processed,
+            total: self.total,
+            eta_ms,
+        })
+        .emit(&self.app_handle)
+        {
+            log::warn!("Failed to emit progress event: {}", e);
+        }
+    }
+}
+
+/// Sent after each song of a loudness analysis. The gain and peak are `None`
+/// if the song's audio could not be analysed.
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessProgressEvent {
+    pub hash: String,
+    pub replay_gain_track_gain: Option<f32>,
+    pub replay_gain_track_peak: Option<f32>,
+    pub processed: u32,
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct LoudnessAnalysisReport {
+    pub analyzed: u32,
+    pub failures: Vec<SongScanFailure>,
+    /// Set if the analysis was cancelled, the songs analysed until then keep
+    /// their values.
+    pub cancelled: bool,
+}
+
+/// A song to export lyrics for, either already parsed or as the path of its song file.
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[serde(tag = "type", content = "data")]
+pub enum LyricsSource {
+    Song(Song),
+    Path(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct SongLintReport {
+    pub path: String,
+    pub findings: Vec<LintFinding>,
+    /// Set if the song could not be parsed or its folder could not be read,
+    /// in which case there are no findings.
+    pub error: Option<String>,
+}
+
 fn get_media_base_url(media_server_state: &State<Arc<Mutex<Option<MediaServerState>>>>) -> String {
     if let Ok(state) = media_server_state.lock() {
         if let Some(server_state) = state.as_ref() {
@@ -43,18 +193,10 @@ fn get_media_base_url(media_server_state: &State<Arc<Mutex<Option<MediaServerSta
     base.to_string()
 }
 
-#[tauri::command]
-#[specta::specta]
-pub async fn parse_songs_from_paths(
-    paths: Vec<String>,
-    app_handle: tauri::AppHandle,
-    media_server_state: State<'_, Arc<Mutex<Option<MediaServerState>>>>,
-) -> Result<Vec<SongGroup>, AppError> {
-    let media_base_url = get_media_base_url(&media_server_state);
-
+fn filter_allowed_paths(app_handle: &tauri::AppHandle, paths: Vec<String>) -> Vec<String> {
     let fs_scope = app_handle.fs_scope();
 
-    let allowed_paths: Vec<String> = paths
+    paths
         .into_iter()
         .filter(|path| {
             if !fs_scope.is_allowed(path) {
@@ -64,21 +206,75 @@ pub async fn parse_songs_from_paths(
                 true
             }
         })
+        .collect()
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn parse_songs_from_paths(
+    paths: Vec<String>,
+    max_depth: Option<u32>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+    media_server_state: State<'_, Arc<Mutex<Option<MediaServerState>>>>,
+) -> Result<SongScanReport, AppError> {
+    let media_base_url = get_media_base_url(&media_server_state);
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    *state
+        .song_scan_cancelled
+        .lock()
+        .map_err(|_| AppError::IoError("Failed to acquire song scan lock".to_string()))? =
+        Some(cancelled.clone());
+
+    let allowed_paths = filter_allowed_paths(&app_handle, paths);
+
+    let song_files =
+        traverse_and_find_txt_files(allowed_paths.clone(), TraversalOptions::new(max_depth));
+    let txt_files_map = song_files.songs;
+    let warnings = song_files
+        .warnings
+        .into_iter()
+        .map(SongScanWarning::from)
         .collect();
 
-    let txt_files_map = traverse_and_find_txt_files(allowed_paths.clone())?;
+    // Songs whose files did not change since the last scan are taken from the index
+    let index_path = app_handle
+        .path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| LibraryIndex::path(&dir));
+    let previous_index = Arc::new(match &index_path {
+        Some(index_path) => LibraryIndex::load(index_path, &media_base_url),
+        None => LibraryIndex::new(&media_base_url),
+    });
+    let mut index = LibraryIndex::new(&media_base_url);
+    let cache_dir = app_handle.path().app_cache_dir().ok();
+    let loudness_cache = Arc::new(
+        cache_dir
+            .as_deref()
+            .map(|dir| LoudnessCache::load(&LoudnessCache::path(dir))),
+    );
 
     let mut song_groups = Vec::new();
+    let mut failures = Vec::new();
+    let mut scanned_songs = HashMap::new();
 
-    StartParsingEvent {
+    if let Err(e) = (StartParsingEvent {
         total_songs: txt_files_map.len() as i32,
-    }
+    })
     .emit(&app_handle)
-    .unwrap();
+    {
+        log::warn!("Failed to emit start parsing event: {}", e);
+    }
+    let progress = Arc::new(ScanProgress::new(
+        app_handle.clone(),
+        txt_files_map.len() as u32,
+    ));
 
     let num_workers = num_cpus::get();
 
-    for start_path in allowed_paths {
+    for start_path in allowed_paths.iter().cloned() {
         let mut songs_for_path = Vec::new();
 
         let txt_files_for_path: Vec<_> = txt_files_map
@@ -105,24 +301,36 @@ pub async fn parse_songs_from_paths(
         let mut batch_tasks = Vec::new();
         for batch in batches {
             let media_base_url = media_base_url.clone();
-            let app_handle = app_handle.clone();
+            let previous_index = previous_index.clone();
+            let cache_dir = cache_dir.clone();
+            let loudness_cache = loudness_cache.clone();
+            let progress = progress.clone();
+            let cancelled = cancelled.clone();
 
             let batch_task = task::spawn_blocking(move || {
                 let mut batch_results = Vec::new();
 
                 for (txt_path, files_in_dir) in batch {
-                    match parse_local_txt_file(&txt_path, &files_in_dir, &media_base_url) {
-                        Ok(song) => {
-                            batch_results.push((txt_path.clone(), Ok(song)));
-                        }
-                        Err(e) => {
-                            log::error!("Failed to parse song at '{}': {}", txt_path, e);
-                            batch_results.push((txt_path.clone(), Err(e)));
-                        }
+                    if cancelled.load(Ordering::Relaxed) {
+                        break;
                     }
 
-                    // Emit progress event for each song
-                    ProgressEvent { song: txt_path }.emit(&app_handle).unwrap();
+                    let key = SongFileKey::new(&txt_path, &files_in_dir);
+                    let cached = key
+                        .as_ref()
+                        .and_then(|key| previous_index.get(&txt_path, key))
+                        .cloned();
+                    let result = cached.unwrap_or_else(|| {
+                        parse_local_song_file(
+                            &txt_path,
+                            &files_in_dir,
+                            &media_base_url,
+                            cache_dir.as_deref(),
+                            loudness_cache.as_ref().as_ref(),
+                        )
+                    });
+                    progress.advance(&txt_path);
+                    batch_results.push((txt_path, key, result));
                 }
 
                 batch_results
@@ -134,10 +342,28 @@ pub async fn parse_songs_from_paths(
         for batch_task in batch_tasks {
             match batch_task.await {
                 Ok(batch_results) => {
-                    for (txt_path, result) in batch_results {
-                        match result {
-                            Ok(song) => songs_for_path.push(song),
-                            Err(e) => log::error!("Failed to parse song at '{}': {}", txt_path, e),
+                    for (txt_path, key, result) in batch_results {
+                        let hash = result.as_ref().ok().map(|song| song.song.hash.clone());
+                        scanned_songs.insert(
+                            txt_path.clone(),
+                            WatchedSong {
+                                key: key.clone(),
+                                hash,
+                            },
+                        );
+                        match &result {
+                            Ok(song) => songs_for_path.push(song.clone()),
+                            Err(e) => {
+                                log::error!("Failed to parse song at '{}': {}", txt_path, e);
+                                failures.push(SongScanFailure {
+                                    path: txt_path.clone(),
+                                    kind: e.kind().to_string(),
+                                    message: e.to_string(),
+                                });
+                            }
+                        }
+                        if let Some(key) = key {
+                            index.insert(txt_path, key, result);
                         }
                     }
                 }
@@ -153,5 +379,407 @@ pub async fn parse_songs_from_paths(
         });
     }
 
-    Ok(song_groups)
+    let cancelled = cancelled.load(Ordering::Relaxed);
+
+    // The frontend drops the songs of a cancelled scan
+    if !cancelled {
+        let mut state_scanned_songs = state
+            .scanned_songs
+            .lock()
+            .map_err(|_| AppError::IoError("Failed to acquire scanned songs lock".to_string()))?;
+        state_scanned_songs.retain(|song_file, _| {
+            !allowed_paths
+                .iter()
+                .any(|path| Path::new(song_file).starts_with(path))
+        });
+        state_scanned_songs.extend(scanned_songs);
+    }
+
+    // The analysis would write back the index it loaded over this one
+    let _loudness_analysis = stop_loudness_analysis(&state).await?;
+    if let Some(index_path) = index_path {
+        // Songs a cancelled scan did not get to keep their previous entries
+        let scanned_paths: &[String] = if cancelled { &[] } else { &allowed_paths };
+        index.keep_songs_outside(&previous_index, scanned_paths);
+        match task::spawn_blocking(move || index.save(&index_path)).await {
+            Ok(Err(e)) => log::warn!("Failed to save library index: {}", e),
+            Err(e) => log::warn!("Failed to save library index: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    Ok(SongScanReport {
+        groups: song_groups,
+        failures,
+        warnings,
+        cancelled,
+    })
+}
+
+/// Stops the running `parse_songs_from_paths` scan, which then returns the
+/// songs parsed so far.
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_song_scan(state: State<'_, AppState>) -> Result<(), AppError> {
+    let song_scan_cancelled = state
+        .song_scan_cancelled
+        .lock()
+        .map_err(|_| AppError::IoError("Failed to acquire song scan lock".to_string()))?;
+    if let Some(cancelled) = song_scan_cancelled.as_ref() {
+        cancelled.store(true, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+/// Writes a song back to an UltraStar txt file, e.g. after fixing it in the UI.
+#[tauri::command]
+#[specta::specta]
+pub async fn save_song_txt(
+    path: String,
+    song: Song,
+    app_handle: tauri::AppHandle,
+) -> Result<(), AppError> {
+    if !app_handle.fs_scope().is_allowed(&path) {
+        return Err(AppError::IoError(format!("Path is not allowed: {}", path)));
+    }
+
+    let content = write_ultrastar_txt(&song)?;
+    std::fs::write(&path, encode_txt(&content, song.encoding.as_deref())?)?;
+
+    Ok(())
+}
+
+/// Rewrites a song's txt file as UTF-8, keeping the original next to it as
+/// `<file>.bak`. Returns the path of the backup, or `None` if the file was
+/// already UTF-8.
+#[tauri::command]
+#[specta::specta]
+pub async fn convert_song_txt_to_utf8(
+    path: String,
+    app_handle: tauri::AppHandle,
+) -> Result<Option<String>, AppError> {
+    if !app_handle.fs_scope().is_allowed(&path) {
+        return Err(AppError::IoError(format!("Path is not allowed: {}", path)));
+    }
+
+    let bytes = std::fs::read(&path)?;
+    let Some(content) = convert_txt_to_utf8(&bytes) else {
+        return Ok(None);
+    };
+
+    let mut backup_path = format!("{}.bak", path);
+    let mut counter = 1;
+    while std::path::Path::new(&backup_path).exists() {
+        backup_path = format!("{}.{}.bak", path, counter);
+        counter += 1;
+    }
+
+    std::fs::write(&backup_path, &bytes)?;
+    std::fs::write(&path, content)?;
+
+    Ok(Some(backup_path))
+}
+
+/// Turns a song into per-voice line and note events with absolute times in milliseconds.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_song_timeline(song: Song) -> Result<SongTimeline, AppError> {
+    build_song_timeline(&song)
+}
+
+/// Reads a song file of any supported format with the same importer as in the
+/// library scan.
+fn read_song_at_path(path: &str) -> Result<Song, AppError> {
+    let song_files =
+        traverse_and_find_txt_files(vec![path.to_string()], TraversalOptions::default());
+    let files = song_files
+        .songs
+        .get(path)
+        .ok_or_else(|| AppError::IoError(format!("Not a song file: {}", path)))?;
+    let filenames: Vec<String> = files.iter().map(|file| file.filename.clone()).collect();
+    let (_, (song, _)) = read_song_file(path, &filenames)?;
+    Ok(song)
+}
+
+/// Renders the lyrics of a song as LRC, enhanced LRC, SRT, WebVTT or ASS.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_song_lyrics(
+    source: LyricsSource,
+    format: LyricsFormat,
+    app_handle: tauri::AppHandle,
+) -> Result<String, AppError> {
+    let song = match source {
+        LyricsSource::Song(song) => song,
+        LyricsSource::Path(path) => {
+            if !app_handle.fs_scope().is_allowed(&path) {
+                return Err(AppError::IoError(format!("Path is not allowed: {}", path)));
+            }
+            read_song_at_path(&path)?
+        }
+    };
+
+    export_lyrics(&song, format)
+}
+
+/// Writes the vocal notes of a song to a Standard MIDI File, one track per voice.
+#[tauri::command]
+#[specta::specta]
+pub async fn export_song_midi(
+    song: Song,
+    path: String,
+    app_handle: tauri::AppHandle,
+) -> Result<(), AppError> {
+    if !app_handle.fs_scope().is_allowed(&path) {
+        return Err(AppError::IoError(format!("Path is not allowed: {}", path)));
+    }
+
+    std::fs::write(&path, write_midi(&song)?)?;
+
+    Ok(())
+}
+
+/// Lints a song file of any supported format, parsed with the same importer
+/// as in the library scan.
+fn lint_song_file(path: String, files: &[FileEntry]) -> SongLintReport {
+    let filenames: Vec<String> = files.iter().map(|file| file.filename.clone()).collect();
+    match read_song_file(&path, &filenames) {
+        Ok((_, (song, diagnostics))) => SongLintReport {
+            path,
+            findings: lint_song(&song, &diagnostics),
+            error: None,
+        },
+        Err(e) => SongLintReport {
+            path,
+            findings: Vec::new(),
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Lints single song files or every song below the given directories.
+#[tauri::command]
+#[specta::specta]
+pub async fn lint_songs(
+    paths: Vec<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<SongLintReport>, AppError> {
+    let allowed_paths = filter_allowed_paths(&app_handle, paths);
+
+    task::spawn_blocking(move || -> Result<Vec<SongLintReport>, AppError> {
+        let song_files = traverse_and_find_txt_files(allowed_paths, TraversalOptions::default());
+
+        let mut reports: Vec<SongLintReport> = song_files
+            .songs
+            .into_iter()
+            .map(|(path, files)| lint_song_file(path, &files))
+            .collect();
+        // Folders that could not be read are reported like songs that could not be parsed
+        reports.extend(
+            song_files
+                .warnings
+                .into_iter()
+                .map(|warning| SongLintReport {
+                    path: warning.path,
+                    findings: Vec::new(),
+                    error: Some(warning.message),
+                }),
+        );
+        reports.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(reports)
+    })
+    .await
+    .map_err(|e| AppError::IoError(e.to_string()))?
+}
+
+/// Watches the song paths for songs being added, changed or removed and emits
+/// `SongAddedEvent`, `SongUpdatedEvent` and `SongRemovedEvent` for them.
+/// Replaces the paths watched before.
+#[tauri::command]
+#[specta::specta]
+pub async fn watch_song_paths(
+    paths: Vec<String>,
+    max_depth: Option<u32>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+    media_server_state: State<'_, Arc<Mutex<Option<MediaServerState>>>>,
+) -> Result<(), AppError> {
+    let media_base_url = get_media_base_url(&media_server_state);
+    let allowed_paths = filter_allowed_paths(&app_handle, paths);
+    let mut library_watcher = state
+        .library_watcher
+        .lock()
+        .map_err(|_| AppError::IoError("Failed to acquire library watcher lock".to_string()))?;
+    library_watcher.take();
+
+    if !allowed_paths.is_empty() {
+        *library_watcher = Some(watch_library(
+            app_handle,
+            allowed_paths,
+            media_base_url,
+            TraversalOptions::new(max_depth),
+            state.scanned_songs.clone(),
+        )?);
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn stop_watching_song_paths(state: State<'_, AppState>) -> Result<(), AppError> {
+    let mut library_watcher = state
+        .library_watcher
+        .lock()
+        .map_err(|_| AppError::IoError("Failed to acquire library watcher lock".to_string()))?;
+    library_watcher.take();
+
+    Ok(())
+}
+
+/// Measures the loudness of the songs below the given paths that have no
+/// ReplayGain tags per EBU R128 and stores it as their ReplayGain values in
+/// the library index. Songs are analysed one at a time, so the game stays
+/// responsive while it runs in the background.
+#[tauri::command]
+#[specta::specta]
+pub async fn analyze_song_loudness(
+    paths: Vec<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+    media_server_state: State<'_, Arc<Mutex<Option<MediaServerState>>>>,
+) -> Result<LoudnessAnalysisReport, AppError> {
+    let media_base_url = get_media_base_url(&media_server_state);
+
+    drop(stop_loudness_analysis(&state).await?);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    *state
+        .loudness_analysis_cancelled
+        .lock()
+        .map_err(|_| AppError::IoError("Failed to acquire loudness analysis lock".to_string()))? =
+        Some(cancelled.clone());
+    let running = state.loudness_analysis_running.clone().lock_owned().await;
+
+    let allowed_paths = filter_allowed_paths(&app_handle, paths);
+    let index_path = LibraryIndex::path(
+        &app_handle
+            .path()
+            .app_data_dir()
+            .map_err(|e| AppError::IoError(e.to_string()))?,
+    );
+    let cache_path = LoudnessCache::path(
+        &app_handle
+            .path()
+            .app_cache_dir()
+            .map_err(|e| AppError::IoError(e.to_string()))?,
+    );
+
+    task::spawn_blocking(move || -> Result<LoudnessAnalysisReport, AppError> {
+        let _running = running;
+        let index = LibraryIndex::load(&index_path, &media_base_url);
+        let songs: Vec<(String, String, PathBuf)> = index
+            .songs()
+            .filter(|(song_file, song)| {
+                song.replay_gain_track_gain.is_none()
+                    && allowed_paths
+                        .iter()
+                        .any(|path| song_file.starts_with(path.as_str()))
+            })
+            .filter_map(|(song_file, song)| {
+                let audio_path = index.media_path(song.audio_url.as_ref()?)?;
+                Some((song_file.clone(), song.song.hash.clone(), audio_path))
+            })
+            .collect();
+
+        let mut cache = LoudnessCache::load(&cache_path);
+        let total = songs.len() as u32;
+        let mut results = Vec::new();
+        let mut failures = Vec::new();
+
+        for (processed, (song_file, hash, audio_path)) in songs.into_iter().enumerate() {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let replay_gain = match cache.analyze(&audio_path, &cancelled) {
+                Ok(Some(loudness)) => Some(loudness.replay_gain()),
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("Failed to analyse the loudness of '{}': {}", song_file, e);
+                    failures.push(SongScanFailure {
+                        path: song_file.clone(),
+                        kind: e.kind().to_string(),
+                        message: e.to_string(),
+                    });
+                    None
+                }
+            };
+
+            if let Err(e) = (LoudnessProgressEvent {
+                hash: hash.clone(),
+                replay_gain_track_gain: replay_gain.map(|(gain, _)| gain),
+                replay_gain_track_peak: replay_gain.map(|(_, peak)| peak),
+                processed: processed as u32 + 1,
+                total,
+            })
+            .emit(&app_handle)
+            {
+                log::warn!("Failed to emit loudness progress event: {}", e);
+            }
+
+            if let Some(replay_gain) = replay_gain {
+                results.push((song_file, hash, replay_gain));
+            }
+        }
+
+        if let Err(e) = cache.save(&cache_path) {
+            log::warn!("Failed to save loudness cache: {}", e);
+        }
+
+        // A scan can have written the index in the meantime, so the results
+        // go into the current one
+        let mut index = LibraryIndex::load(&index_path, &media_base_url);
+        for (song_file, hash, (gain, peak)) in &results {
+            index.set_replay_gain(song_file, hash, *gain, *peak);
+        }
+        if let Err(e) = index.save(&index_path) {
+            log::warn!("Failed to save library index: {}", e);
+        }
+
+        Ok(LoudnessAnalysisReport {
+            analyzed: results.len() as u32,
+            failures,
+            cancelled: cancelled.load(Ordering::Relaxed),
+        })
+    })
+    .await
+    .map_err(|e| AppError::IoError(e.to_string()))?
+}
+
+/// Cancels the running loudness analysis and waits until it ended. No
+/// analysis starts while the returned guard is held.
+async fn stop_loudness_analysis(state: &AppState) -> Result<TokioMutexGuard<'_, ()>, AppError> {
+    {
+        let loudness_analysis_cancelled =
+            state.loudness_analysis_cancelled.lock().map_err(|_| {
+                AppError::IoError("Failed to acquire loudness analysis lock".to_string())
+            })?;
+        if let Some(cancelled) = loudness_analysis_cancelled.as_ref() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    Ok(state.loudness_analysis_running.lock().await)
+}
+
+/// Stops the running `analyze_song_loudness` and returns once it saved the
+/// songs analysed so far.
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_loudness_analysis(state: State<'_, AppState>) -> Result<(), AppError> {
+    stop_loudness_analysis(&state).await?;
+
+    Ok(())
 }
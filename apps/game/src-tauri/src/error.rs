@@ -1,7 +1,9 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Error, Debug, Serialize, specta::Type)]
+use crate::ultrastar::diagnostics::ParseDiagnostic;
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize, specta::Type)]
 #[serde(tag = "type", content = "data")]
 pub enum AppError {
     #[error("io error: {0}")]
@@ -22,11 +24,48 @@ pub enum AppError {
     #[error("ultrastar error: {0}")]
     UltrastarError(String),
 
+    #[error("ultrastar parse error: {0}")]
+    UltrastarParseError(ParseDiagnostic),
+
     #[error("webrtc error: {0}")]
     WebRTCError(String),
 
     #[error("usdb error: {0}")]
     UsdbError(String),
+
+    #[error("archive error: {0}")]
+    ArchiveError(String),
+
+    #[error("watcher error: {0}")]
+    WatcherError(String),
+
+    #[error("decode error: {0}")]
+    DecodeError(String),
+
+    #[error("image error: {0}")]
+    ImageError(String),
+}
+
+impl AppError {
+    /// Name of the variant, e.g. `IoError`, as sent to the frontend in the
+    /// `type` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::IoError(_) => "IoError",
+            AppError::LoftyError(_) => "LoftyError",
+            AppError::RecorderError(_) => "RecorderError",
+            AppError::ProcessorError(_) => "ProcessorError",
+            AppError::CpalError(_) => "CpalError",
+            AppError::UltrastarError(_) => "UltrastarError",
+            AppError::UltrastarParseError(_) => "UltrastarParseError",
+            AppError::WebRTCError(_) => "WebRTCError",
+            AppError::UsdbError(_) => "UsdbError",
+            AppError::ArchiveError(_) => "ArchiveError",
+            AppError::WatcherError(_) => "WatcherError",
+            AppError::DecodeError(_) => "DecodeError",
+            AppError::ImageError(_) => "ImageError",
+        }
+    }
 }
 
 macro_rules! impl_from_errors {
@@ -44,5 +83,15 @@ macro_rules! impl_from_errors {
 impl_from_errors!(
     (std::io::Error, IoError),
     (lofty::error::LoftyError, LoftyError),
+    (zip::result::ZipError, ArchiveError),
+    (notify::Error, WatcherError),
+    (symphonia::core::errors::Error, DecodeError),
+    (image::ImageError, ImageError),
     (cpal::Error, CpalError)
 );
+
+impl From<ParseDiagnostic> for AppError {
+    fn from(diagnostic: ParseDiagnostic) -> Self {
+        AppError::UltrastarParseError(diagnostic)
+    }
+}
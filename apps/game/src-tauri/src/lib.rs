@@ -1,6 +1,7 @@
 mod audio;
 mod commands;
 mod error;
+mod library_watcher;
 mod media_server;
 mod ultrastar;
 mod usdb;
@@ -8,12 +9,13 @@ mod webrtc;
 
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, RwLock},
+    sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
 };
 use tokio::sync::Mutex as TokioMutex;
 
 use audio::{processor::Processor, recorder::Recorder};
 use commands::*;
+use library_watcher::WatchedSong;
 use media_server::create_media_server_plugin;
 use specta_typescript::Typescript;
 use tauri::Manager;
@@ -26,6 +28,13 @@ pub struct AppState {
     recorder: RwLock<Option<Recorder>>,
     processors: RwLock<HashMap<usize, Arc<Mutex<Processor>>>>,
     usdb_client: TokioMutex<Option<UsdbClient>>,
+    library_watcher: Mutex<Option<notify::RecommendedWatcher>>,
+    /// The song files the frontend loaded from scans and library watcher events.
+    scanned_songs: Arc<Mutex<HashMap<String, WatchedSong>>>,
+    song_scan_cancelled: Mutex<Option<Arc<AtomicBool>>>,
+    loudness_analysis_cancelled: Mutex<Option<Arc<AtomicBool>>>,
+    /// Held by the running loudness analysis until it saved the library index.
+    loudness_analysis_running: Arc<TokioMutex<()>>,
 }
 
 impl Default for AppState {
@@ -34,6 +43,11 @@ impl Default for AppState {
             recorder: RwLock::new(None),
             processors: RwLock::new(HashMap::new()),
             usdb_client: TokioMutex::new(None),
+            library_watcher: Mutex::new(None),
+            scanned_songs: Arc::new(Mutex::new(HashMap::new())),
+            song_scan_cancelled: Mutex::new(None),
+            loudness_analysis_cancelled: Mutex::new(None),
+            loudness_analysis_running: Arc::new(TokioMutex::new(())),
         }
     }
 }
@@ -49,6 +63,17 @@ pub fn run() {
             pitch::get_audio_levels,
             media_server::get_media_server_base_url,
             songs::parse_songs_from_paths,
+            songs::cancel_song_scan,
+            songs::save_song_txt,
+            songs::convert_song_txt_to_utf8,
+            songs::get_song_timeline,
+            songs::export_song_lyrics,
+            songs::export_song_midi,
+            songs::lint_songs,
+            songs::watch_song_paths,
+            songs::stop_watching_song_paths,
+            songs::analyze_song_loudness,
+            songs::cancel_loudness_analysis,
             webrtc::commands::webrtc_create_answer,
             webrtc::commands::webrtc_add_ice_candidate,
             webrtc::commands::webrtc_send_message,
@@ -64,6 +89,10 @@ pub fn run() {
         .events(collect_events![
             songs::ProgressEvent,
             songs::StartParsingEvent,
+            songs::LoudnessProgressEvent,
+            library_watcher::SongAddedEvent,
+            library_watcher::SongUpdatedEvent,
+            library_watcher::SongRemovedEvent,
             usdb::commands::UsdbSyncProgressEvent,
             webrtc::host::IceCandidateEvent,
             webrtc::host::ConnectionStateEvent,
@@ -123,6 +152,13 @@ pub fn run() {
                 Err(_) => {}
             }
 
+            // Covers embedded in audio files are served from the cache
+            if let Ok(cache_dir) = app.path().app_cache_dir() {
+                let cover_dir = ultrastar::meta::embedded_cover_dir(&cache_dir);
+                fs_scope.allow_directory(&cover_dir, false)?;
+                asset_scope.allow_directory(&cover_dir, false)?;
+            }
+
             app.manage(AppState::default());
             app.manage(webrtc::host::create_shared_host());
             builder.mount_events(app);
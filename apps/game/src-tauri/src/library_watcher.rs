@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+
+use crate::{
+    error::AppError,
+    ultrastar::{
+        filesystem::{find_songs_below, FileEntry, TraversalOptions, IGNORE_FILE_NAME},
+        library_index::SongFileKey,
+        loudness::LoudnessCache,
+        parser::parse_local_song_file,
+        song::LocalSong,
+    },
+};
+
+/// Changes are collected until the song paths were quiet for this long, so
+/// saving or copying a song folder is handled once.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(750);
+
+/// A song was added below one of the watched paths. `group_path` is the
+/// watched path it belongs to, like `SongGroup::path`.
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct SongAddedEvent {
+    pub group_path: String,
+    pub path: String,
+    pub song: LocalSong,
+}
+
+/// A song was changed, it replaces the song with `previous_hash`.
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct SongUpdatedEvent {
+    pub group_path: String,
+    pub path: String,
+    pub previous_hash: String,
+    pub song: LocalSong,
+}
+
+/// A song was deleted or can no longer be parsed.
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
+pub struct SongRemovedEvent {
+    pub group_path: String,
+    pub path: String,
+    pub hash: String,
+}
+
+/// A song file as the frontend knows it, from the last scan or event.
+#[derive(Debug, Clone)]
+pub struct WatchedSong {
+    pub key: Option<SongFileKey>,
+    /// Hash of the song the frontend knows, `None` if it failed to parse.
+    pub hash: Option<String>,
+}
+
+/// The songs below the watched paths, kept up to date from file system events.
+struct WatchedLibrary {
+    app_handle: AppHandle,
+    paths: Vec<String>,
+    media_base_url: String,
+    cache_dir: Option<PathBuf>,
+    options: TraversalOptions,
+    /// Shared with the scans, which replace the songs below the paths they scanned.
+    songs: Arc<Mutex<HashMap<String, WatchedSong>>>,
+}
+
+impl WatchedLibrary {
+    fn group_path(&self, path: impl AsRef<Path>) -> Option<&String> {
+        self.paths
+            .iter()
+            .find(|group_path| path.as_ref().starts_with(group_path))
+    }
+
+    /// Rescans the folders of the changed paths. Changed directories are
+    /// scanned with their subfolders, since they can be a new song folder,
+    /// and so are the folders of changed ignore files. The songs lock is
+    /// only held to compare against and apply the changes, not while the
+    /// folders are walked and the songs parsed.
+    fn update(&self, changed_paths: BTreeSet<PathBuf>) {
+        let mut folders = BTreeSet::new();
+        let mut trees = BTreeSet::new();
+        for path in &changed_paths {
+            if path.is_dir() {
+                trees.insert(path.clone());
+            } else if let Some(parent) = path.parent() {
+                if path
+                    .file_name()
+                    .is_some_and(|name| name == IGNORE_FILE_NAME)
+                {
+                    trees.insert(parent.to_path_buf());
+                } else {
+                    folders.insert(parent.to_path_buf());
+                }
+            }
+        }
+
+        let mut found: HashMap<String, Vec<FileEntry>> = HashMap::new();
+        for (folder, recursive) in folders
+            .iter()
+            .map(|folder| (folder, false))
+            .chain(trees.iter().map(|tree| (tree, true)))
+        {
+            if let Some(group_path) = self.group_path(folder) {
+                let song_files =
+                    find_songs_below(Path::new(group_path), folder, recursive, self.options);
+                found.extend(song_files.songs);
+            }
+        }
+
+        // Songs that were in the rescanned folders or below a changed path
+        // and were not found again are gone
+        let changed: Vec<(String, Vec<FileEntry>, Option<SongFileKey>)> = {
+            let Ok(mut songs) = self.songs.lock() else {
+                log::error!("Failed to acquire scanned songs lock");
+                return;
+            };
+            let removed: Vec<String> = songs
+                .keys()
+                .filter(|song_file| !found.contains_key(*song_file))
+                .filter(|song_file| {
+                    let path = Path::new(song_file);
+                    path.parent().is_some_and(|parent| folders.contains(parent))
+                        || trees.iter().any(|tree| path.starts_with(tree))
+                        || changed_paths
+                            .iter()
+                            .any(|changed| path.starts_with(changed))
+                })
+                .cloned()
+                .collect();
+            for song_file in removed {
+                if let Some(WatchedSong {
+                    hash: Some(hash), ..
+                }) = songs.remove(&song_file)
+                {
+                    self.emit_removed(song_file, hash);
+                }
+            }
+
+            found
+                .into_iter()
+                .filter_map(|(song_file, files_in_dir)| {
+                    let key = SongFileKey::new(&song_file, &files_in_dir);
+                    let unchanged = key.is_some()
+                        && songs
+                            .get(&song_file)
+                            .is_some_and(|previous| previous.key == key);
+                    (!unchanged).then_some((song_file, files_in_dir, key))
+                })
+                .collect()
+        };
+        if changed.is_empty() {
+            return;
+        }
+
+        let loudness_cache = self
+            .cache_dir
+            .as_deref()
+            .map(|dir| LoudnessCache::load(&LoudnessCache::path(dir)));
+        let parsed: Vec<(String, Option<SongFileKey>, Option<LocalSong>)> = changed
+            .into_iter()
+            .map(|(song_file, files_in_dir, key)| {
+                let song = match parse_local_song_file(
+                    &song_file,
+                    &files_in_dir,
+                    &self.media_base_url,
+                    self.cache_dir.as_deref(),
+                    loudness_cache.as_ref(),
+                ) {
+                    Ok(song) => Some(song),
+                    Err(e) => {
+                        log::error!("Failed to parse song at '{}': {}", song_file, e);
+                        None
+                    }
+                };
+                (song_file, key, song)
+            })
+            .collect();
+
+        // A scan can have replaced the songs in the meantime, so the events
+        // are based on the songs as they are now
+        let Ok(mut songs) = self.songs.lock() else {
+            log::error!("Failed to acquire scanned songs lock");
+            return;
+        };
+        for (song_file, key, song) in parsed {
+            let previous_hash = songs
+                .get(&song_file)
+                .and_then(|previous| previous.hash.clone());
+            let hash = song.as_ref().map(|song| song.song.hash.clone());
+
+            match (song, previous_hash) {
+                (Some(song), None) => self.emit_added(song_file.clone(), song),
+                (Some(song), Some(previous_hash)) => {
+                    self.emit_updated(song_file.clone(), previous_hash, song)
+                }
+                (None, Some(previous_hash)) => self.emit_removed(song_file.clone(), previous_hash),
+                (None, None) => {}
+            }
+
+            songs.insert(song_file, WatchedSong { key, hash });
+        }
+    }
+
+    fn emit_added(&self, path: String, song: LocalSong) {
+        if let Some(group_path) = self.group_path(&path) {
+            if let Err(e) = (SongAddedEvent {
+                group_path: group_path.clone(),
+                path,
+                song,
+            })
+            .emit(&self.app_handle)
+            {
+                log::warn!("Failed to emit song added event: {}", e);
+            }
+        }
+    }
+
+    fn emit_updated(&self, path: String, previous_hash: String, song: LocalSong) {
+        if let Some(group_path) = self.group_path(&path) {
+            if let Err(e) = (SongUpdatedEvent {
+                group_path: group_path.clone(),
+                path,
+                previous_hash,
+                song,
+            })
+            .emit(&self.app_handle)
+            {
+                log::warn!("Failed to emit song updated event: {}", e);
+            }
+        }
+    }
+
+    fn emit_removed(&self, path: String, hash: String) {
+        if let Some(group_path) = self.group_path(&path) {
+            if let Err(e) = (SongRemovedEvent {
+                group_path: group_path.clone(),
+                path,
+                hash,
+            })
+            .emit(&self.app_handle)
+            {
+                log::warn!("Failed to emit song removed event: {}", e);
+            }
+        }
+    }
+
+    fn run(self, events: Receiver<notify::Result<notify::Event>>) {
+        // Ends when the watcher is dropped
+        while let Ok(event) = events.recv() {
+            let mut changed_paths = BTreeSet::new();
+            collect_changed_paths(event, &mut changed_paths);
+            while let Ok(event) = events.recv_timeout(DEBOUNCE_DELAY) {
+                collect_changed_paths(event, &mut changed_paths);
+            }
+
+            if !changed_paths.is_empty() {
+                self.update(changed_paths);
+            }
+        }
+    }
+}
+
+fn collect_changed_paths(
+    event: notify::Result<notify::Event>,
+    changed_paths: &mut BTreeSet<PathBuf>,
+) {
+    match event {
+        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+            changed_paths.extend(event.paths);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Library watcher error: {}", e),
+    }
+}
+
+/// Watches the given song paths and emits song events for the songs that
+/// change below them. Changes are compared against `songs`, the song files
+/// the frontend knows, which are kept up to date. Dropping the watcher stops
+/// watching.
+pub fn watch_library(
+    app_handle: AppHandle,
+    paths: Vec<String>,
+    media_base_url: String,
+    options: TraversalOptions,
+    songs: Arc<Mutex<HashMap<String, WatchedSong>>>,
+) -> Result<RecommendedWatcher, AppError> {
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for path in &paths {
+        if let Err(e) = watcher.watch(Path::new(path), RecursiveMode::Recursive) {
+            log::warn!("Failed to watch '{}': {}", path, e);
+        }
+    }
+
+    let library = WatchedLibrary {
+        cache_dir: app_handle.path().app_cache_dir().ok(),
+        app_handle,
+        paths,
+        media_base_url,
+        options,
+        songs,
+    };
+    thread::spawn(move || library.run(receiver));
+
+    Ok(watcher)
+}
@@ -1,7 +1,7 @@
-use std::collections::HashMap;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -15,6 +15,82 @@ use tauri::{
 use tauri_plugin_fs::FsExt;
 use tauri_utils::mime_type::MimeType;
 
+use crate::ultrastar::{
+    archive::{open_archive, read_media_file, split_archive_path},
+    cdg::CdgDecoder,
+    thumbnail::{get_thumbnail, prune_thumbnails},
+};
+
+/// Decoders of the CD+G songs that are playing, so consecutive frames only
+/// process the packets in between.
+type CdgDecoders = Arc<Mutex<HashMap<String, CdgDecoder>>>;
+
+/// More than one decoder is only needed while switching songs.
+const MAX_CDG_DECODERS: usize = 2;
+
+/// Decompressed archive entries are dropped, least recently used first,
+/// once they take more memory than this.
+const MAX_ARCHIVE_ENTRY_BYTES: usize = 256 * 1024 * 1024;
+const MAX_ARCHIVE_PATHS: usize = 1024;
+
+/// Requested paths inside archives split into archive and entry name, and the
+/// compressed entries decompressed for them, so seeking in a song neither
+/// looks up the archive nor decompresses the entry on every range request.
+#[derive(Default)]
+struct ArchiveCache {
+    paths: HashMap<String, (PathBuf, String)>,
+    /// Least recently used first.
+    entries: VecDeque<(String, Arc<Vec<u8>>)>,
+}
+
+impl ArchiveCache {
+    fn insert_path(&mut self, file_path: &str, archive_path: (PathBuf, String)) {
+        if self.paths.len() >= MAX_ARCHIVE_PATHS {
+            self.paths.clear();
+        }
+        self.paths.insert(file_path.to_string(), archive_path);
+    }
+
+    fn entry(&mut self, file_path: &str) -> Option<Arc<Vec<u8>>> {
+        let index = self
+            .entries
+            .iter()
+            .position(|(path, _)| path == file_path)?;
+        let entry = self.entries.remove(index)?;
+        let content = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(content)
+    }
+
+    /// Keeps the newest entry even if it alone is over the limit.
+    fn insert_entry(&mut self, file_path: &str, content: Arc<Vec<u8>>) {
+        let mut size = content.len()
+            + self
+                .entries
+                .iter()
+                .map(|(_, content)| content.len())
+                .sum::<usize>();
+        while size > MAX_ARCHIVE_ENTRY_BYTES {
+            let Some((_, dropped)) = self.entries.pop_front() else {
+                break;
+            };
+            size -= dropped.len();
+        }
+        self.entries.push_back((file_path.to_string(), content));
+    }
+
+    fn remove(&mut self, file_path: &str) {
+        self.paths.remove(file_path);
+        self.entries.retain(|(path, _)| path != file_path);
+    }
+}
+
+enum Route {
+    File,
+    Cdg,
+    Thumbnail(u32),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 pub struct MediaServerConfig {
     pub port: u16,
@@ -57,6 +133,9 @@ fn find_available_port(start_port: u16) -> Option<u16> {
 fn handle_request(
     request: &str,
     scope: &tauri::scope::fs::Scope,
+    cdg_decoders: &CdgDecoders,
+    archive_cache: &Mutex<ArchiveCache>,
+    cache_dir: Option<&Path>,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
     let lines: Vec<&str> = request.lines().collect();
     if lines.is_empty() {
@@ -70,7 +149,7 @@ fn handle_request(
     }
 
     let method = parts[0];
-    let path = parts[1];
+    let (path, query) = parts[1].split_once('?').unwrap_or((parts[1], ""));
 
     // Parse headers
     let mut headers = HashMap::new();
@@ -92,6 +171,22 @@ fn handle_request(
         path
     };
 
+    // CD+G frames are requested as `/cdg/<path>?time=<ms>` and cover
+    // thumbnails as `/thumb/<size>/<path>`
+    let thumbnail = file_path
+        .strip_prefix("thumb/")
+        .and_then(|rest| rest.split_once('/'));
+    let (file_path, route) = if let Some(cdg_path) = file_path.strip_prefix("cdg/") {
+        (cdg_path, Route::Cdg)
+    } else if let Some((size, image_path)) = thumbnail {
+        match size.parse() {
+            Ok(size) => (image_path, Route::Thumbnail(size)),
+            Err(_) => return Ok(create_error_response(404, "Not Found")),
+        }
+    } else {
+        (file_path, Route::File)
+    };
+
     let file_path = percent_encoding::percent_decode(file_path.as_bytes())
         .decode_utf8_lossy()
         .to_string();
@@ -101,9 +196,44 @@ fn handle_request(
         return Ok(create_error_response(403, "Forbidden"));
     }
 
+    match route {
+        Route::Cdg => return handle_cdg_request(&file_path, query, cdg_decoders),
+        Route::Thumbnail(size) => {
+            return handle_thumbnail_request(&file_path, size, cache_dir, method)
+        }
+        Route::File => {}
+    }
+
     let path_buf = PathBuf::from(&file_path);
-    if !path_buf.exists() {
-        return Ok(create_error_response(404, "Not Found"));
+    let cached_archive_path = archive_cache
+        .lock()
+        .map_err(|e| e.to_string())?
+        .paths
+        .get(&file_path)
+        .cloned();
+    let archive_path = match cached_archive_path {
+        Some(archive_path) => Some(archive_path),
+        None if path_buf.exists() => None,
+        None => match split_archive_path(&path_buf) {
+            Some(archive_path) => {
+                archive_cache
+                    .lock()
+                    .map_err(|e| e.to_string())?
+                    .insert_path(&file_path, archive_path.clone());
+                Some(archive_path)
+            }
+            None => return Ok(create_error_response(404, "Not Found")),
+        },
+    };
+    if let Some((archive, entry)) = archive_path {
+        return handle_archive_request(
+            &archive,
+            &entry,
+            &file_path,
+            &headers,
+            method,
+            archive_cache,
+        );
     }
 
     let mut file = std::fs::File::open(&path_buf)?;
@@ -111,6 +241,16 @@ fn handle_request(
     // Get file length
     let len = file.metadata()?.len();
 
+    serve_file(&mut file, len, &file_path, &headers, method)
+}
+
+fn serve_file(
+    file: &mut (impl Read + Seek),
+    len: u64,
+    file_path: &str,
+    headers: &HashMap<String, String>,
+    method: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
     // Get file mime type
     let mime_type = {
         let magic_buf_size = 8192.min(len as usize);
@@ -119,12 +259,12 @@ fn handle_request(
             file.read_exact(&mut magic_buf)?;
             file.seek(SeekFrom::Start(0))?;
         }
-        MimeType::parse(&magic_buf, &file_path)
+        MimeType::parse(&magic_buf, file_path)
     };
 
     // Handle range requests
     if let Some(range_header) = headers.get("range") {
-        handle_range_request(&mut file, len, &mime_type, range_header, method == "HEAD")
+        handle_range_request(file, len, &mime_type, range_header, method == "HEAD")
     } else if method == "HEAD" {
         Ok(create_head_response(len, &mime_type))
     } else {
@@ -134,8 +274,123 @@ fn handle_request(
     }
 }
 
+/// Serves a file inside an archive. Stored entries are read straight from
+/// the archive at the requested range, compressed ones are decompressed into
+/// memory once and kept in the archive cache.
+fn handle_archive_request(
+    archive: &Path,
+    entry: &str,
+    file_path: &str,
+    headers: &HashMap<String, String>,
+    method: &str,
+    archive_cache: &Mutex<ArchiveCache>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let cached_entry = archive_cache
+        .lock()
+        .map_err(|e| e.to_string())?
+        .entry(file_path);
+    if let Some(content) = cached_entry {
+        let len = content.len() as u64;
+        return serve_file(
+            &mut Cursor::new(content.as_slice()),
+            len,
+            file_path,
+            headers,
+            method,
+        );
+    }
+
+    let Ok(mut archive) = open_archive(archive) else {
+        // The archive was moved or deleted since its path was resolved
+        archive_cache
+            .lock()
+            .map_err(|e| e.to_string())?
+            .remove(file_path);
+        return Ok(create_error_response(404, "Not Found"));
+    };
+
+    if let Ok(mut stored_entry) = archive.by_name_seek(entry) {
+        let len = stored_entry.seek(SeekFrom::End(0))?;
+        stored_entry.seek(SeekFrom::Start(0))?;
+        return serve_file(&mut stored_entry, len, file_path, headers, method);
+    }
+
+    let mut content = Vec::new();
+    match archive.by_name(entry) {
+        Ok(mut compressed_entry) => compressed_entry.read_to_end(&mut content)?,
+        Err(_) => return Ok(create_error_response(404, "Not Found")),
+    };
+    let content = Arc::new(content);
+    archive_cache
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert_entry(file_path, content.clone());
+    let len = content.len() as u64;
+    serve_file(
+        &mut Cursor::new(content.as_slice()),
+        len,
+        file_path,
+        headers,
+        method,
+    )
+}
+
+/// Renders the frame of a CD+G song at the `time` query parameter in
+/// milliseconds as a BMP.
+fn handle_cdg_request(
+    file_path: &str,
+    query: &str,
+    cdg_decoders: &CdgDecoders,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let time_ms = query
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("time="))
+        .and_then(|time| time.parse::<f64>().ok())
+        .unwrap_or(0.0);
+
+    let mut decoders = cdg_decoders.lock().map_err(|e| e.to_string())?;
+    if !decoders.contains_key(file_path) {
+        let Ok(data) = read_media_file(Path::new(file_path)) else {
+            return Ok(create_error_response(404, "Not Found"));
+        };
+        if decoders.len() >= MAX_CDG_DECODERS {
+            decoders.clear();
+        }
+        decoders.insert(file_path.to_string(), CdgDecoder::new(data));
+    }
+    let Some(decoder) = decoders.get_mut(file_path) else {
+        return Ok(create_error_response(404, "Not Found"));
+    };
+
+    decoder.seek(time_ms);
+    Ok(create_full_response(decoder.render_bmp(), "image/bmp"))
+}
+
+/// Serves an image scaled down to `size`, made and cached on first request.
+fn handle_thumbnail_request(
+    file_path: &str,
+    size: u32,
+    cache_dir: Option<&Path>,
+    method: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(cache_dir) = cache_dir else {
+        return Ok(create_error_response(404, "Not Found"));
+    };
+
+    match get_thumbnail(cache_dir, Path::new(file_path), size) {
+        Ok((data, mime_type)) if method == "HEAD" => {
+            Ok(create_head_response(data.len() as u64, mime_type))
+        }
+        Ok((data, mime_type)) => Ok(create_full_response(data, mime_type)),
+        Err(e) => {
+            eprintln!("Failed to create thumbnail of '{}': {}", file_path, e);
+            Ok(create_error_response(404, "Not Found"))
+        }
+    }
+}
+
 fn handle_range_request(
-    file: &mut std::fs::File,
+    file: &mut (impl Read + Seek),
     len: u64,
     mime_type: &str,
     range_header: &str,
@@ -225,12 +480,21 @@ fn create_error_response(status: u16, message: &str) -> Vec<u8> {
 fn handle_client(
     mut stream: TcpStream,
     scope: tauri::scope::fs::Scope,
+    cdg_decoders: CdgDecoders,
+    archive_cache: Arc<Mutex<ArchiveCache>>,
+    cache_dir: Option<PathBuf>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut buffer = [0; 4096];
     let bytes_read = stream.read(&mut buffer)?;
     let request = String::from_utf8_lossy(&buffer[..bytes_read]);
 
-    match handle_request(&request, &scope) {
+    match handle_request(
+        &request,
+        &scope,
+        &cdg_decoders,
+        &archive_cache,
+        cache_dir.as_deref(),
+    ) {
         Ok(response) => {
             stream.write_all(&response)?;
         }
@@ -267,6 +531,15 @@ pub fn create_media_server_plugin<R: Runtime>() -> TauriPlugin<R> {
 
             // Get the app handle for accessing the scope later
             let app_handle = app.app_handle().clone();
+            let cdg_decoders = CdgDecoders::default();
+            let archive_cache = Arc::new(Mutex::new(ArchiveCache::default()));
+            let cache_dir = app.path().app_cache_dir().ok();
+
+            // Thumbnails of changed or removed images are left behind, so the
+            // cache is pruned once per start
+            if let Some(cache_dir) = cache_dir.clone() {
+                thread::spawn(move || prune_thumbnails(&cache_dir));
+            }
 
             // Start the server in a background thread
             thread::spawn(move || {
@@ -284,10 +557,19 @@ pub fn create_media_server_plugin<R: Runtime>() -> TauriPlugin<R> {
                     match stream {
                         Ok(stream) => {
                             let app_handle_clone = app_handle.clone();
+                            let cdg_decoders = cdg_decoders.clone();
+                            let archive_cache = archive_cache.clone();
+                            let cache_dir = cache_dir.clone();
                             thread::spawn(move || {
                                 // Get the actual fs scope from the app
                                 let scope = app_handle_clone.fs_scope();
-                                if let Err(e) = handle_client(stream, scope) {
+                                if let Err(e) = handle_client(
+                                    stream,
+                                    scope,
+                                    cdg_decoders,
+                                    archive_cache,
+                                    cache_dir,
+                                ) {
                                     eprintln!("Error handling client: {}", e);
                                 }
                             });
@@ -0,0 +1,79 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use zip::ZipArchive;
+
+use crate::error::AppError;
+
+/// Whether a file is an archive that songs can be loaded from.
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Splits a path into a zip archive, like `Karaoke.zip/Artist - Title.mp3`,
+/// into the archive and the name of the entry. Returns `None` for paths that
+/// exist on disk or do not lead into an archive.
+pub fn split_archive_path(path: &Path) -> Option<(PathBuf, String)> {
+    if path.exists() {
+        return None;
+    }
+
+    let archive = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_file())?;
+    if !is_archive(archive) {
+        return None;
+    }
+    // Entry names always use forward slashes
+    let entry = path
+        .strip_prefix(archive)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    Some((archive.to_path_buf(), entry))
+}
+
+pub fn open_archive(archive: &Path) -> Result<ZipArchive<File>, AppError> {
+    Ok(ZipArchive::new(File::open(archive)?)?)
+}
+
+/// Names of the files in an archive, directories are left out.
+pub fn list_archive_files(archive: &Path) -> Result<Vec<String>, AppError> {
+    Ok(open_archive(archive)?
+        .file_names()
+        .filter(|name| !name.ends_with('/'))
+        .map(String::from)
+        .collect())
+}
+
+pub fn read_archive_entry(archive: &Path, entry: &str) -> Result<Vec<u8>, AppError> {
+    let mut archive = open_archive(archive)?;
+    let mut file = archive.by_name(entry)?;
+    let mut content = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Reads a file from disk or from inside an archive.
+pub fn read_media_file(path: &Path) -> Result<Vec<u8>, AppError> {
+    match split_archive_path(path) {
+        Some((archive, entry)) => read_archive_entry(&archive, &entry),
+        None => Ok(std::fs::read(path)?),
+    }
+}
+
+/// Canonicalizes a path on disk, or the archive part of a path into an archive.
+pub fn canonicalize_media_path(path: &Path) -> Result<PathBuf, AppError> {
+    match split_archive_path(path) {
+        Some((archive, entry)) => Ok(dunce::canonicalize(archive)?.join(entry)),
+        None => Ok(dunce::canonicalize(path)?),
+    }
+}
@@ -0,0 +1,530 @@
+use crate::{
+    error::AppError,
+    ultrastar::{
+        diagnostics::{DiagnosticCode, ParseDiagnostic},
+        filesystem::AUDIO_EXTENSIONS,
+        song::{Note, NoteType, Phrase, Song, Voice},
+    },
+};
+
+/// Size of the CD+G screen including the border.
+pub const CDG_WIDTH: usize = 300;
+pub const CDG_HEIGHT: usize = 216;
+const TILE_WIDTH: usize = 6;
+const TILE_HEIGHT: usize = 12;
+/// Subcode packets are 24 bytes, a CD plays 300 of them per second.
+const PACKET_SIZE: usize = 24;
+const PACKETS_PER_SECOND: f64 = 300.0;
+const CDG_COMMAND: u8 = 0x09;
+
+const MEMORY_PRESET: u8 = 1;
+const BORDER_PRESET: u8 = 2;
+const TILE_BLOCK: u8 = 6;
+const SCROLL_PRESET: u8 = 20;
+const SCROLL_COPY: u8 = 24;
+const LOAD_COLORS_LOW: u8 = 30;
+const LOAD_COLORS_HIGH: u8 = 31;
+const TILE_BLOCK_XOR: u8 = 38;
+
+/// CD+G songs have no notes, so they get a single freestyle note over the
+/// whole track. At this tempo a beat is 10 ms.
+const CDG_BPM: f64 = 1500.0;
+
+fn duration_ms(data_len: usize) -> f64 {
+    (data_len / PACKET_SIZE) as f64 / PACKETS_PER_SECOND * 1000.0
+}
+
+/// Whether a file is the graphics track of a CD+G karaoke song.
+pub fn is_cdg_file(filename: &str) -> bool {
+    filename
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("cdg"))
+}
+
+/// Decodes the subcode packets of a `.cdg` file into 16-color frames.
+pub struct CdgDecoder {
+    data: Vec<u8>,
+    /// Index of the next packet to process.
+    position: usize,
+    pixels: Vec<u8>,
+    palette: [[u8; 3]; 16],
+    border_color: u8,
+    h_offset: usize,
+    v_offset: usize,
+}
+
+impl CdgDecoder {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            position: 0,
+            pixels: vec![0; CDG_WIDTH * CDG_HEIGHT],
+            palette: [[0; 3]; 16],
+            border_color: 0,
+            h_offset: 0,
+            v_offset: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+        self.pixels.fill(0);
+        self.palette = [[0; 3]; 16];
+        self.border_color = 0;
+        self.h_offset = 0;
+        self.v_offset = 0;
+    }
+
+    /// Processes packets up to the given time. Seeking backwards replays the
+    /// file from the start, since every frame depends on all packets before it.
+    pub fn seek(&mut self, time_ms: f64) {
+        let target = ((time_ms.max(0.0) / 1000.0 * PACKETS_PER_SECOND) as usize)
+            .min(self.data.len() / PACKET_SIZE);
+        if target < self.position {
+            self.reset();
+        }
+
+        while self.position < target {
+            let offset = self.position * PACKET_SIZE;
+            let mut packet = [0; PACKET_SIZE];
+            packet.copy_from_slice(&self.data[offset..offset + PACKET_SIZE]);
+            self.process_packet(&packet);
+            self.position += 1;
+        }
+    }
+
+    fn process_packet(&mut self, packet: &[u8; PACKET_SIZE]) {
+        if packet[0] & 0x3F != CDG_COMMAND {
+            return;
+        }
+
+        let data = &packet[4..20];
+        match packet[1] & 0x3F {
+            MEMORY_PRESET => self.pixels.fill(data[0] & 0x0F),
+            BORDER_PRESET => self.border_color = data[0] & 0x0F,
+            TILE_BLOCK => self.draw_tile(data, false),
+            TILE_BLOCK_XOR => self.draw_tile(data, true),
+            SCROLL_PRESET => self.scroll(data, false),
+            SCROLL_COPY => self.scroll(data, true),
+            LOAD_COLORS_LOW => self.load_colors(data, 0),
+            LOAD_COLORS_HIGH => self.load_colors(data, 8),
+            _ => {}
+        }
+    }
+
+    /// A tile is 6x12 pixels, each row is 6 bits choosing between two colors.
+    fn draw_tile(&mut self, data: &[u8], xor: bool) {
+        let colors = [data[0] & 0x0F, data[1] & 0x0F];
+        let row = (data[2] & 0x1F) as usize;
+        let column = (data[3] & 0x3F) as usize;
+        if row >= CDG_HEIGHT / TILE_HEIGHT || column >= CDG_WIDTH / TILE_WIDTH {
+            return;
+        }
+
+        for (y, bits) in data[4..4 + TILE_HEIGHT].iter().enumerate() {
+            for x in 0..TILE_WIDTH {
+                let color = colors[((bits >> (TILE_WIDTH - 1 - x)) & 1) as usize];
+                let index = (row * TILE_HEIGHT + y) * CDG_WIDTH + column * TILE_WIDTH + x;
+                if xor {
+                    self.pixels[index] ^= color;
+                } else {
+                    self.pixels[index] = color;
+                }
+            }
+        }
+    }
+
+    /// Moves the screen by a whole tile and sets the fine offsets for smooth
+    /// scrolling. Copy wraps the pixels around, preset fills the uncovered
+    /// area with a color.
+    fn scroll(&mut self, data: &[u8], copy: bool) {
+        let color = data[0] & 0x0F;
+        let horizontal = data[1] & 0x3F;
+        let vertical = data[2] & 0x3F;
+        self.h_offset = ((horizontal & 0x07) as usize).min(TILE_WIDTH - 1);
+        self.v_offset = ((vertical & 0x0F) as usize).min(TILE_HEIGHT - 1);
+
+        let dx = match horizontal >> 4 {
+            1 => TILE_WIDTH as isize,
+            2 => -(TILE_WIDTH as isize),
+            _ => 0,
+        };
+        let dy = match vertical >> 4 {
+            1 => TILE_HEIGHT as isize,
+            2 => -(TILE_HEIGHT as isize),
+            _ => 0,
+        };
+        if dx == 0 && dy == 0 {
+            return;
+        }
+
+        let (width, height) = (CDG_WIDTH as isize, CDG_HEIGHT as isize);
+        let previous = self.pixels.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = (x - dx, y - dy);
+                let inside = (0..width).contains(&source_x) && (0..height).contains(&source_y);
+                self.pixels[(y * width + x) as usize] = if inside || copy {
+                    let source_x = source_x.rem_euclid(width);
+                    let source_y = source_y.rem_euclid(height);
+                    previous[(source_y * width + source_x) as usize]
+                } else {
+                    color
+                };
+            }
+        }
+    }
+
+    /// Loads 8 colors, each packed into two bytes as 4-bit red, green and blue.
+    fn load_colors(&mut self, data: &[u8], first: usize) {
+        for (index, pair) in data.chunks_exact(2).enumerate() {
+            let (high, low) = (pair[0] & 0x3F, pair[1] & 0x3F);
+            let red = high >> 2;
+            let green = ((high & 0x03) << 2) | (low >> 4);
+            let blue = low & 0x0F;
+            self.palette[first + index] = [red * 17, green * 17, blue * 17];
+        }
+    }
+
+    /// Renders the current frame as RGB, with the border on top of the
+    /// scrolled screen.
+    pub fn render_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(CDG_WIDTH * CDG_HEIGHT * 3);
+        for y in 0..CDG_HEIGHT {
+            for x in 0..CDG_WIDTH {
+                let is_border = !(TILE_WIDTH..CDG_WIDTH - TILE_WIDTH).contains(&x)
+                    || !(TILE_HEIGHT..CDG_HEIGHT - TILE_HEIGHT).contains(&y);
+                let color = if is_border {
+                    self.border_color
+                } else {
+                    self.pixels[(y + self.v_offset) * CDG_WIDTH + x + self.h_offset]
+                };
+                rgb.extend_from_slice(&self.palette[color as usize]);
+            }
+        }
+        rgb
+    }
+
+    /// Renders the current frame as an uncompressed 24-bit BMP, which the
+    /// webview can display without any further decoding.
+    pub fn render_bmp(&self) -> Vec<u8> {
+        let row_size = (CDG_WIDTH * 3).div_ceil(4) * 4;
+        let image_size = row_size * CDG_HEIGHT;
+        let mut bmp = Vec::with_capacity(54 + image_size);
+
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&((54 + image_size) as u32).to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&54u32.to_le_bytes());
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&(CDG_WIDTH as i32).to_le_bytes());
+        bmp.extend_from_slice(&(CDG_HEIGHT as i32).to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&(image_size as u32).to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+
+        // Rows are stored bottom to top as BGR
+        let rgb = self.render_rgb();
+        for row in rgb.chunks_exact(CDG_WIDTH * 3).rev() {
+            for pixel in row.chunks_exact(3) {
+                bmp.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            bmp.resize(bmp.len() + row_size - CDG_WIDTH * 3, 0);
+        }
+
+        bmp
+    }
+}
+
+/// Karaoke discs name their tracks `Artist - Title`, often with the disc and
+/// track number in front, like `SC8001-01 - Artist - Title`.
+fn split_track_name(stem: &str) -> (Option<String>, String) {
+    let parts: Vec<&str> = stem.split(" - ").map(str::trim).collect();
+    let parts = match parts.as_slice() {
+        [disc, rest @ ..]
+            if rest.len() >= 2
+                && disc.chars().any(|c| c.is_ascii_digit())
+                && !disc.contains(' ') =>
+        {
+            rest
+        }
+        parts => parts,
+    };
+
+    match parts {
+        [artist, title @ ..] if !title.is_empty() => (Some(artist.to_string()), title.join(" - ")),
+        _ => (None, stem.trim().to_string()),
+    }
+}
+
+/// Builds a freestyle-only `Song` for a CD+G track. The lyrics are part of
+/// the graphics, so there is nothing to score and the song is one freestyle
+/// note as long as the graphics track. The audio is the file with the same
+/// name as the `.cdg` and title and artist come from that name. The hash
+/// covers the CD+G data.
+pub fn parse_cdg_song(
+    cdg_filename: &str,
+    cdg: &[u8],
+    files: &[String],
+) -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
+    let mut diagnostics = Vec::new();
+    let stem = cdg_filename
+        .rsplit_once('.')
+        .map_or(cdg_filename, |(stem, _)| stem);
+
+    let audio = AUDIO_EXTENSIONS.iter().find_map(|extension| {
+        let candidate = format!("{}.{}", stem, extension);
+        files
+            .iter()
+            .find(|file| file.eq_ignore_ascii_case(&candidate))
+            .cloned()
+    });
+    let Some(audio) = audio else {
+        return Err(ParseDiagnostic::error(
+            None,
+            DiagnosticCode::MissingMedia,
+            format!("No audio file named '{}' next to the CD+G file", stem),
+        )
+        .into());
+    };
+
+    let (artist, title) = split_track_name(stem);
+    let artist = artist.unwrap_or_else(|| {
+        diagnostics.push(ParseDiagnostic::warning(
+            None,
+            DiagnosticCode::MissingArtist,
+            "File name has no artist, expected 'Artist - Title'",
+        ));
+        "Unknown".to_string()
+    });
+
+    let duration_beats = (duration_ms(cdg.len()) / 60_000.0 * CDG_BPM * 4.0) as i32;
+    if duration_beats <= 0 {
+        return Err(ParseDiagnostic::error(
+            None,
+            DiagnosticCode::IncompleteLine,
+            "CD+G file has no packets",
+        )
+        .into());
+    }
+
+    let mut md5_context = md5::Context::new();
+    md5_context.consume(cdg);
+
+    let song = Song {
+        title,
+        artist,
+        bpm: CDG_BPM,
+        tempo_changes: Vec::new(),
+        gap: 0.0,
+        video_gap: 0.0,
+        start: None,
+        end: None,
+        hash: format!("{:x}", md5_context.finalize()),
+        album: None,
+        language: None,
+        edition: None,
+        genre: None,
+        year: None,
+        creator: None,
+        relative: None,
+        audio: Some(audio),
+        instrumental: None,
+        cover: None,
+        video: None,
+        background: None,
+        p1: None,
+        p2: None,
+        preview_start: None,
+        version: Some("1.0.0".to_string()),
+        tags: None,
+        medley_start_beat: None,
+        medley_end_beat: None,
+        medley_start: None,
+        medley_end: None,
+        resolution: None,
+        notes_gap: None,
+        calc_medley: None,
+        vocals: None,
+        comment: None,
+        provided_by: None,
+        encoding: None,
+        audio_web_url: None,
+        video_web_url: None,
+        cover_web_url: None,
+        background_web_url: None,
+        custom_tags: Vec::new(),
+        voices: vec![Voice {
+            name: None,
+            phrases: vec![Phrase {
+                disappear_beat: duration_beats,
+                next_start_beat: None,
+                shared: false,
+                notes: vec![Note {
+                    note_type: NoteType::Freestyle,
+                    start_beat: 0,
+                    length: duration_beats,
+                    text: String::new(),
+                    txt_pitch: 0,
+                    midi_note: 60,
+                }],
+            }],
+        }],
+    };
+
+    Ok((song, diagnostics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(instruction: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; PACKET_SIZE];
+        packet[0] = CDG_COMMAND;
+        packet[1] = instruction;
+        packet[4..4 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn decode(packets: &[Vec<u8>]) -> CdgDecoder {
+        let mut decoder = CdgDecoder::new(packets.concat());
+        decoder.seek(f64::INFINITY);
+        decoder
+    }
+
+    fn pixel(decoder: &CdgDecoder, x: usize, y: usize) -> u8 {
+        decoder.pixels[y * CDG_WIDTH + x]
+    }
+
+    /// A tile with `on` as the color of set bits, the first row fully set
+    /// and the others only in their leftmost pixel.
+    fn tile(instruction: u8, row: u8, column: u8, off: u8, on: u8) -> Vec<u8> {
+        let mut data = vec![off, on, row, column, 0x3F];
+        data.extend([0x20; TILE_HEIGHT - 1]);
+        packet(instruction, &data)
+    }
+
+    #[test]
+    fn presets_fill_the_screen_and_border() {
+        let decoder = decode(&[packet(MEMORY_PRESET, &[5]), packet(BORDER_PRESET, &[9])]);
+        assert!(decoder.pixels.iter().all(|color| *color == 5));
+        assert_eq!(decoder.border_color, 9);
+    }
+
+    #[test]
+    fn ignores_packets_of_other_subcode_modes() {
+        let mut other = packet(MEMORY_PRESET, &[5]);
+        other[0] = 0x08;
+        let decoder = decode(&[other]);
+        assert!(decoder.pixels.iter().all(|color| *color == 0));
+    }
+
+    #[test]
+    fn draws_tiles() {
+        let decoder = decode(&[tile(TILE_BLOCK, 1, 2, 3, 7)]);
+        let (left, top) = (2 * TILE_WIDTH, TILE_HEIGHT);
+        assert_eq!(pixel(&decoder, left, top), 7);
+        assert_eq!(pixel(&decoder, left + TILE_WIDTH - 1, top), 7);
+        assert_eq!(pixel(&decoder, left, top + 1), 7);
+        assert_eq!(pixel(&decoder, left + 1, top + 1), 3);
+        // Outside of the tile
+        assert_eq!(pixel(&decoder, left - 1, top), 0);
+        assert_eq!(pixel(&decoder, left, top + TILE_HEIGHT), 0);
+    }
+
+    #[test]
+    fn draws_xor_tiles() {
+        let decoder = decode(&[
+            packet(MEMORY_PRESET, &[0x0C]),
+            tile(TILE_BLOCK_XOR, 1, 2, 0x01, 0x0A),
+        ]);
+        let (left, top) = (2 * TILE_WIDTH, TILE_HEIGHT);
+        assert_eq!(pixel(&decoder, left, top), 0x0C ^ 0x0A);
+        assert_eq!(pixel(&decoder, left + 1, top + 1), 0x0C ^ 0x01);
+        assert_eq!(pixel(&decoder, left - 1, top), 0x0C);
+    }
+
+    #[test]
+    fn skips_tiles_outside_of_the_screen() {
+        let decoder = decode(&[tile(TILE_BLOCK, 18, 0, 1, 1), tile(TILE_BLOCK, 0, 50, 1, 1)]);
+        assert!(decoder.pixels.iter().all(|color| *color == 0));
+    }
+
+    #[test]
+    fn scroll_copy_wraps_pixels_around() {
+        let last_column = (CDG_WIDTH / TILE_WIDTH - 1) as u8;
+        let decoder = decode(&[
+            tile(TILE_BLOCK, 1, last_column, 0, 4),
+            // Right by one tile, with a fine offset of 2 pixels
+            packet(SCROLL_COPY, &[6, 0x12, 0]),
+        ]);
+        assert_eq!(pixel(&decoder, 0, TILE_HEIGHT), 4);
+        assert_eq!(pixel(&decoder, TILE_WIDTH - 1, TILE_HEIGHT), 4);
+        assert_eq!(pixel(&decoder, CDG_WIDTH - 1, TILE_HEIGHT), 0);
+        assert_eq!(decoder.h_offset, 2);
+        assert_eq!(decoder.v_offset, 0);
+    }
+
+    #[test]
+    fn scroll_preset_fills_the_uncovered_area() {
+        let decoder = decode(&[
+            tile(TILE_BLOCK, 0, 1, 0, 4),
+            // Down by one tile
+            packet(SCROLL_PRESET, &[6, 0, 0x13]),
+        ]);
+        assert_eq!(pixel(&decoder, TILE_WIDTH, 0), 6);
+        assert_eq!(pixel(&decoder, TILE_WIDTH, TILE_HEIGHT - 1), 6);
+        assert_eq!(pixel(&decoder, TILE_WIDTH, TILE_HEIGHT), 4);
+        assert_eq!(pixel(&decoder, 0, TILE_HEIGHT), 0);
+        assert_eq!(decoder.v_offset, 3);
+    }
+
+    #[test]
+    fn loads_color_tables() {
+        let mut low = [0; 16];
+        // Color 1 is red 0xF, green 0x5, blue 0xA
+        low[2..4].copy_from_slice(&[0x3D, 0x1A]);
+        let mut high = [0; 16];
+        // Color 15 is white
+        high[14..16].copy_from_slice(&[0x3F, 0x3F]);
+        let decoder = decode(&[
+            packet(LOAD_COLORS_LOW, &low),
+            packet(LOAD_COLORS_HIGH, &high),
+        ]);
+        assert_eq!(decoder.palette[0], [0, 0, 0]);
+        assert_eq!(decoder.palette[1], [0xFF, 0x55, 0xAA]);
+        assert_eq!(decoder.palette[15], [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn renders_the_border_on_top_of_the_screen() {
+        let mut colors = [0; 16];
+        colors[2..6].copy_from_slice(&[0x3C, 0x00, 0x03, 0x00]);
+        let decoder = decode(&[
+            packet(LOAD_COLORS_LOW, &colors),
+            packet(MEMORY_PRESET, &[1]),
+            packet(BORDER_PRESET, &[2]),
+        ]);
+        let rgb = decoder.render_rgb();
+        let at = |x: usize, y: usize| &rgb[(y * CDG_WIDTH + x) * 3..][..3];
+        assert_eq!(at(0, 0), [0, 0xCC, 0]);
+        assert_eq!(at(CDG_WIDTH / 2, CDG_HEIGHT / 2), [0xFF, 0, 0]);
+    }
+
+    #[test]
+    fn seeking_backwards_replays_from_the_start() {
+        let mut decoder =
+            CdgDecoder::new([packet(MEMORY_PRESET, &[5]), packet(MEMORY_PRESET, &[6])].concat());
+        decoder.seek(f64::INFINITY);
+        assert_eq!(pixel(&decoder, 0, 0), 6);
+        // The first packet ends after 1/300 s
+        decoder.seek(1000.0 / PACKETS_PER_SECOND + 0.1);
+        assert_eq!(pixel(&decoder, 0, 0), 5);
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::fmt;
+
+/// `Error` means the song could not be parsed, `Warning` that part of the
+/// file was ignored or guessed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum DiagnosticCode {
+    InvalidVersion,
+    InvalidNumber,
+    MissingTitle,
+    MissingArtist,
+    MissingBpm,
+    MissingEnd,
+    MissingMedia,
+    MalformedHeader,
+    UnknownNoteType,
+    IncompleteLine,
+    UnknownLine,
+    ContentAfterEnd,
+    EmptyMediaFile,
+    NotesAfterAudioEnd,
+    StartAfterAudioEnd,
+}
+
+/// A problem found while parsing an UltraStar txt file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct ParseDiagnostic {
+    pub severity: DiagnosticSeverity,
+    /// 1-based line number, `None` for problems with the song as a whole.
+    pub line: Option<u32>,
+    pub code: DiagnosticCode,
+    pub message: String,
+}
+
+impl ParseDiagnostic {
+    pub fn error(line: Option<u32>, code: DiagnosticCode, message: impl Into<String>) -> Self {
+        Self {
+            severity: DiagnosticSeverity::Error,
+            line,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(line: Option<u32>, code: DiagnosticCode, message: impl Into<String>) -> Self {
+        Self {
+            severity: DiagnosticSeverity::Warning,
+            line,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
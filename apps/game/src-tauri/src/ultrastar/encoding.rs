@@ -0,0 +1,151 @@
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::path::Path;
+
+use crate::{error::AppError, ultrastar::archive::read_media_file};
+
+/// How the text encoding of a txt file was determined. `Certain` means a byte
+/// order mark, plain ASCII or valid UTF-8, `Declared` that it was taken from the
+/// `#ENCODING` header and `Guessed` that it was guessed from the bytes, which
+/// can go wrong for short files with few special characters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum EncodingConfidence {
+    Certain,
+    Declared,
+    Guessed,
+}
+
+pub struct DecodedTxt {
+    pub content: String,
+    pub encoding: &'static Encoding,
+    pub confidence: EncodingConfidence,
+}
+
+/// Looks for an `#ENCODING` header before the first note. The header is plain
+/// ASCII, so it can be read before the rest of the file is decoded.
+fn find_declared_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    for line in bytes.split(|byte| *byte == b'\n') {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            continue;
+        }
+        let header = line.strip_prefix(b"#")?;
+
+        let Some(colon) = header.iter().position(|byte| *byte == b':') else {
+            continue;
+        };
+        let (property, value) = (&header[..colon], &header[colon + 1..]);
+        if property.trim_ascii().eq_ignore_ascii_case(b"ENCODING") {
+            // "UTF8", "CP1250" and "CP1252" are all valid WHATWG labels, "LOCALE" and "AUTO" are not
+            return Encoding::for_label(value.trim_ascii());
+        }
+    }
+
+    None
+}
+
+fn guess_encoding(bytes: &[u8]) -> (&'static Encoding, EncodingConfidence) {
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+    let has_non_ascii = detector.feed(bytes, true);
+    let encoding = detector.guess(None, Utf8Detection::Allow);
+
+    // chardetng only picks UTF-8 if the whole file is valid UTF-8
+    if !has_non_ascii || encoding == UTF_8 {
+        (encoding, EncodingConfidence::Certain)
+    } else {
+        (encoding, EncodingConfidence::Guessed)
+    }
+}
+
+/// Decodes the bytes of a txt file. A byte order mark wins over the
+/// `#ENCODING` header, which wins over guessing. A file declared as UTF-8 that
+/// is not valid UTF-8 is guessed instead, since the header is obviously wrong.
+pub fn decode_txt(bytes: &[u8]) -> DecodedTxt {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        let (content, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        return DecodedTxt {
+            content: content.into_owned(),
+            encoding,
+            confidence: EncodingConfidence::Certain,
+        };
+    }
+
+    if let Some(encoding) = find_declared_encoding(bytes) {
+        let (content, had_errors) = encoding.decode_without_bom_handling(bytes);
+        if !had_errors || encoding != UTF_8 {
+            return DecodedTxt {
+                content: content.into_owned(),
+                encoding,
+                confidence: EncodingConfidence::Declared,
+            };
+        }
+    }
+
+    let (encoding, confidence) = guess_encoding(bytes);
+    let (content, _) = encoding.decode_without_bom_handling(bytes);
+    DecodedTxt {
+        content: content.into_owned(),
+        encoding,
+        confidence,
+    }
+}
+
+/// Reads and decodes a txt file from disk or an archive, see [`decode_txt`].
+pub fn read_txt_file(txt: &str) -> Result<DecodedTxt, AppError> {
+    let bytes = read_media_file(Path::new(txt))?;
+    Ok(decode_txt(&bytes))
+}
+
+/// Encodes txt content for writing to disk in the encoding its `#ENCODING`
+/// header declares, so the header stays truthful. Without a usable header the
+/// content is written as UTF-8.
+pub fn encode_txt(content: &str, declared_encoding: Option<&str>) -> Result<Vec<u8>, AppError> {
+    let encoding = declared_encoding
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+    let (bytes, _, had_errors) = encoding.encode(content);
+    if had_errors {
+        return Err(AppError::UltrastarError(format!(
+            "Song contains characters that cannot be encoded as {}, change #ENCODING to UTF8",
+            encoding.name()
+        )));
+    }
+
+    Ok(bytes.into_owned())
+}
+
+/// Re-encodes a txt file as UTF-8 and points its `#ENCODING` header, if it has
+/// one, at UTF-8 as well. Everything else in the file is kept as is. Returns
+/// `None` if the file is already UTF-8 and needs no changes.
+pub fn convert_txt_to_utf8(bytes: &[u8]) -> Option<String> {
+    let decoded = decode_txt(bytes);
+    let mut changed = decoded.encoding != UTF_8 || bytes.starts_with(b"\xEF\xBB\xBF");
+
+    let mut output = String::with_capacity(decoded.content.len());
+    let mut in_header = true;
+    for line in decoded.content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if in_header && !trimmed.is_empty() && !trimmed.starts_with('#') {
+            in_header = false;
+        }
+
+        let encoding_value = trimmed
+            .strip_prefix('#')
+            .and_then(|header| header.split_once(':'))
+            .filter(|(property, _)| property.trim().eq_ignore_ascii_case("encoding"))
+            .map(|(_, value)| value.trim());
+        match encoding_value {
+            Some(value) if in_header && !value.eq_ignore_ascii_case("UTF8") => {
+                changed = true;
+                let line_ending = &line[line.trim_end().len()..];
+                output.push_str("#ENCODING:UTF8");
+                output.push_str(line_ending);
+            }
+            _ => output.push_str(line),
+        }
+    }
+
+    changed.then_some(output)
+}
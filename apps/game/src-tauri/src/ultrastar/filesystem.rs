@@ -1,6 +1,24 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
 
-use crate::error::AppError;
+use glob::{MatchOptions, Pattern};
+
+use crate::ultrastar::{
+    archive::{is_archive, list_archive_files},
+    cdg::is_cdg_file,
+    melody::is_melody_chart,
+    rockband::is_rockband_chart,
+};
+
+pub const AUDIO_EXTENSIONS: [&str; 7] = ["ogg", "opus", "mp3", "wav", "m4a", "aac", "flac"];
+pub const VIDEO_EXTENSIONS: [&str; 6] = ["mp4", "webm", "mkv", "avi", "m4v", "mpg"];
+pub const IMAGE_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+
+pub const IGNORE_FILE_NAME: &str = ".tuneperfectignore";
+const DEFAULT_MAX_DEPTH: usize = 16;
 
 #[derive(Debug, Clone)]
 pub struct FileEntry {
@@ -8,73 +26,455 @@ pub struct FileEntry {
     pub filename: String,
 }
 
-pub fn traverse_and_find_txt_files(
-    paths: Vec<String>,
-) -> Result<HashMap<String, Vec<FileEntry>>, AppError> {
-    let mut result = HashMap::new();
+/// Whether a file is a song the library can load: an UltraStar txt file, a
+/// SingStar/Performous `notes.xml`, the `notes.mid` of a Rock Band/Frets on
+/// Fire chart with its `song.ini` or a CD+G track with its audio. `has_file`
+/// tells whether the folder of the song has a file with the given name.
+fn is_song_filename(filename: &str, has_file: impl Fn(&str) -> bool) -> bool {
+    if filename
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension == "txt")
+    {
+        return true;
+    }
 
-    for path_str in paths {
-        let path = Path::new(&path_str);
-        if path.is_dir() {
-            traverse_directory_recursive(path, &mut result)?;
-        } else if path.is_file() && path.extension().map_or(false, |ext| ext == "txt") {
-            // If it's directly a .txt file
-            if let Some(parent) = path.parent() {
-                let files_in_dir = get_files_in_directory(parent)?;
-                result.insert(path.to_string_lossy().to_string(), files_in_dir);
+    let has_audio = || {
+        let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+        AUDIO_EXTENSIONS.iter().any(|extension| {
+            has_file(&format!("{}.{}", stem, extension))
+                || has_file(&format!("{}.{}", stem, extension.to_uppercase()))
+        })
+    };
+
+    is_melody_chart(filename)
+        || (is_rockband_chart(filename) && has_file("song.ini"))
+        || (is_cdg_file(filename) && has_audio())
+}
+
+fn is_song_file(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|filename| {
+            is_song_filename(filename, |other| path.with_file_name(other).is_file())
+        })
+}
+
+/// Adds the songs inside a zip archive. Their paths lead into the archive,
+/// like `Pack.zip/Artist - Title/Artist - Title.txt`, and their files are the
+/// entries in the same folder of the archive. Unreadable archives are skipped.
+fn find_songs_in_archive(archive: &Path, result: &mut HashMap<String, Vec<FileEntry>>) {
+    let entries = match list_archive_files(archive) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Skipping unreadable archive '{}': {}", archive.display(), e);
+            return;
+        }
+    };
+
+    fn split_entry(entry: &str) -> (&str, &str) {
+        entry.rsplit_once('/').unwrap_or(("", entry))
+    }
+
+    let mut folders: HashMap<&str, Vec<&str>> = HashMap::new();
+    for entry in &entries {
+        folders
+            .entry(split_entry(entry).0)
+            .or_default()
+            .push(entry.as_str());
+    }
+
+    for folder_entries in folders.values() {
+        let filenames: Vec<&str> = folder_entries
+            .iter()
+            .map(|entry| split_entry(entry).1)
+            .collect();
+        let has_file = |name: &str| filenames.iter().any(|file| file.eq_ignore_ascii_case(name));
+
+        let files_in_folder: Vec<FileEntry> = folder_entries
+            .iter()
+            .map(|entry| FileEntry {
+                path: archive.join(entry).to_string_lossy().to_string(),
+                filename: split_entry(entry).1.to_string(),
+            })
+            .collect();
+
+        for file in &files_in_folder {
+            if is_song_filename(&file.filename, has_file) {
+                result.insert(file.path.clone(), files_in_folder.clone());
             }
         }
     }
+}
 
-    Ok(result)
+/// Finds the first file named `<stem>.<extension>` in priority order, for
+/// formats that pick their media by conventional names.
+pub fn find_media(files: &[String], stems: &[&str], extensions: &[&str]) -> Option<String> {
+    stems.iter().find_map(|stem| {
+        extensions.iter().find_map(|extension| {
+            let candidate = format!("{}.{}", stem, extension);
+            files
+                .iter()
+                .find(|file| file.eq_ignore_ascii_case(&candidate))
+                .cloned()
+        })
+    })
 }
 
-fn traverse_directory_recursive(
-    dir: &Path,
-    result: &mut HashMap<String, Vec<FileEntry>>,
-) -> Result<(), AppError> {
-    if dir.is_dir() {
-        let entries = fs::read_dir(dir).map_err(|e| AppError::IoError(e.to_string()))?;
-
-        for entry in entries {
-            let entry = entry.map_err(|e| AppError::IoError(e.to_string()))?;
-            let path = entry.path();
-
-            if path.is_dir() {
-                traverse_directory_recursive(&path, result)?;
-            } else if path.is_file() && path.extension().map_or(false, |ext| ext == "txt") {
-                if let Some(parent) = path.parent() {
-                    let files_in_dir = get_files_in_directory(parent)?;
-                    result.insert(path.to_string_lossy().to_string(), files_in_dir);
-                }
-            }
+#[derive(Debug, Clone, Copy)]
+pub struct TraversalOptions {
+    /// How many folders deep songs are searched for below a song path.
+    pub max_depth: usize,
+}
+
+impl TraversalOptions {
+    pub fn new(max_depth: Option<u32>) -> Self {
+        Self {
+            max_depth: max_depth.map_or(DEFAULT_MAX_DEPTH, |max_depth| max_depth as usize),
         }
     }
+}
 
-    Ok(())
+impl Default for TraversalOptions {
+    fn default() -> Self {
+        Self::new(None)
+    }
 }
 
-fn get_files_in_directory(dir: &Path) -> Result<Vec<FileEntry>, AppError> {
-    let mut files = Vec::new();
-    let entries = fs::read_dir(dir).map_err(|e| AppError::IoError(e.to_string()))?;
+/// A folder that could not be read, e.g. for missing permissions. The scan
+/// continues without it.
+#[derive(Debug, Clone)]
+pub struct FolderWarning {
+    pub path: String,
+    pub message: String,
+}
 
-    for entry in entries {
-        let entry = entry.map_err(|e| AppError::IoError(e.to_string()))?;
-        let path = entry.path();
+#[derive(Debug, Default)]
+pub struct SongFiles {
+    /// Song files with the files in their folder.
+    pub songs: HashMap<String, Vec<FileEntry>>,
+    pub warnings: Vec<FolderWarning>,
+}
+
+struct IgnorePattern {
+    pattern: Pattern,
+    /// Patterns without a slash match the name at any depth, like in `.gitignore`.
+    match_name: bool,
+    /// Patterns ending with a slash only match folders.
+    folders_only: bool,
+}
+
+/// The globs of a `.tuneperfectignore` file, one per line. They apply to
+/// the files and folders below the folder of the ignore file.
+struct IgnoreFile {
+    dir: PathBuf,
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreFile {
+    fn read(dir: &Path) -> Option<Self> {
+        let path = dir.join(IGNORE_FILE_NAME);
+        let content = fs::read_to_string(&path).ok()?;
+
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let folders_only = line.ends_with('/');
+                let glob = line.trim_end_matches('/');
+                let match_name = !glob.contains('/');
+                match Pattern::new(glob.trim_start_matches('/')) {
+                    Ok(pattern) => Some(IgnorePattern {
+                        pattern,
+                        match_name,
+                        folders_only,
+                    }),
+                    Err(e) => {
+                        log::warn!("Invalid pattern '{}' in '{}': {}", line, path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect();
 
-        if path.is_file() {
-            let filename = path
-                .file_name()
-                .and_then(|name| name.to_str())
-                .unwrap_or("")
-                .to_string();
+        Some(Self {
+            dir: dir.to_path_buf(),
+            patterns,
+        })
+    }
+
+    fn is_ignored(&self, path: &Path, is_folder: bool) -> bool {
+        let Ok(relative_path) = path.strip_prefix(&self.dir) else {
+            return false;
+        };
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        self.patterns.iter().any(|ignore| {
+            (is_folder || !ignore.folders_only)
+                && (ignore.pattern.matches_path_with(relative_path, options)
+                    || (ignore.match_name
+                        && path.file_name().is_some_and(|name| {
+                            ignore
+                                .pattern
+                                .matches_with(&name.to_string_lossy(), options)
+                        })))
+        })
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
 
-            files.push(FileEntry {
+struct DirectoryListing {
+    files: Vec<FileEntry>,
+    folders: Vec<PathBuf>,
+}
+
+/// Lists a folder, following symlinks. Entries that cannot be read, like
+/// broken symlinks, are left out.
+fn list_directory(dir: &Path) -> std::io::Result<DirectoryListing> {
+    let mut listing = DirectoryListing {
+        files: Vec::new(),
+        folders: Vec::new(),
+    };
+
+    for entry in fs::read_dir(dir)? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            listing.folders.push(path);
+        } else if metadata.is_file() {
+            listing.files.push(FileEntry {
+                filename: entry.file_name().to_string_lossy().to_string(),
                 path: path.to_string_lossy().to_string(),
-                filename,
             });
         }
     }
 
-    Ok(files)
+    Ok(listing)
+}
+
+struct Traversal {
+    options: TraversalOptions,
+    recursive: bool,
+    /// Folders that were listed, by their canonical path. Symlinks can lead
+    /// back to a folder that was already listed.
+    visited: HashSet<PathBuf>,
+    /// The ignore files of the folder being listed and the folders above it.
+    ignore_files: Vec<IgnoreFile>,
+    found: SongFiles,
+}
+
+impl Traversal {
+    fn new(options: TraversalOptions, recursive: bool) -> Self {
+        Self {
+            options,
+            recursive,
+            visited: HashSet::new(),
+            ignore_files: Vec::new(),
+            found: SongFiles::default(),
+        }
+    }
+
+    fn is_ignored(&self, path: &Path, is_folder: bool) -> bool {
+        self.ignore_files
+            .iter()
+            .any(|ignore_file| ignore_file.is_ignored(path, is_folder))
+    }
+
+    fn warn(&mut self, path: &Path, error: impl std::fmt::Display) {
+        log::warn!("Skipping unreadable folder '{}': {}", path.display(), error);
+        self.found.warnings.push(FolderWarning {
+            path: path.to_string_lossy().to_string(),
+            message: error.to_string(),
+        });
+    }
+
+    /// Walks `dir`, which is `root` or a folder below it. The ignore files of
+    /// `root` and the folders between it and `dir` apply, the ones above
+    /// `root` don't.
+    fn walk_below(&mut self, root: &Path, dir: &Path) {
+        let Ok(relative_dir) = dir.strip_prefix(root) else {
+            return;
+        };
+
+        let mut current = root.to_path_buf();
+        let mut depth = 0;
+        for component in relative_dir.components() {
+            self.ignore_files.extend(IgnoreFile::read(&current));
+            current.push(component);
+            depth += 1;
+            if is_hidden(&current) || self.is_ignored(&current, true) {
+                self.ignore_files.clear();
+                return;
+            }
+        }
+
+        self.walk(dir, depth);
+        self.ignore_files.clear();
+    }
+
+    fn walk(&mut self, dir: &Path, depth: usize) {
+        let canonical_dir = dunce::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        if !self.visited.insert(canonical_dir) {
+            return;
+        }
+
+        let listing = match list_directory(dir) {
+            Ok(listing) => listing,
+            Err(e) => {
+                self.warn(dir, e);
+                return;
+            }
+        };
+
+        let ignore_file = IgnoreFile::read(dir);
+        let has_ignore_file = ignore_file.is_some();
+        self.ignore_files.extend(ignore_file);
+
+        let files: Vec<FileEntry> = listing
+            .files
+            .into_iter()
+            .filter(|file| !self.is_ignored(Path::new(&file.path), false))
+            .collect();
+        self.add_songs(&files);
+
+        if self.recursive {
+            for folder in listing.folders {
+                if is_hidden(&folder) || self.is_ignored(&folder, true) {
+                    continue;
+                }
+                if depth >= self.options.max_depth {
+                    log::debug!("Not descending into '{}', too deep", folder.display());
+                    continue;
+                }
+                self.walk(&folder, depth + 1);
+            }
+        }
+
+        if has_ignore_file {
+            self.ignore_files.pop();
+        }
+    }
+
+    /// Adds the songs among the files of one folder, which all share the
+    /// same listing of the folder.
+    fn add_songs(&mut self, files: &[FileEntry]) {
+        let has_file = |name: &str| files.iter().any(|file| file.filename == name);
+
+        for file in files {
+            let path = Path::new(&file.path);
+            if is_archive(path) {
+                find_songs_in_archive(path, &mut self.found.songs);
+            } else if is_song_filename(&file.filename, has_file) {
+                self.found.songs.insert(file.path.clone(), files.to_vec());
+            }
+        }
+    }
+}
+
+pub fn traverse_and_find_txt_files(paths: Vec<String>, options: TraversalOptions) -> SongFiles {
+    let mut traversal = Traversal::new(options, true);
+
+    for path_str in paths {
+        let path = Path::new(&path_str);
+        if path.is_dir() {
+            traversal.walk_below(path, path);
+        } else if is_archive(path) {
+            find_songs_in_archive(path, &mut traversal.found.songs);
+        } else if is_song_file(path) {
+            // If it's directly a song file
+            if let Some(parent) = path.parent() {
+                match list_directory(parent) {
+                    Ok(listing) => {
+                        traversal
+                            .found
+                            .songs
+                            .insert(path.to_string_lossy().to_string(), listing.files);
+                    }
+                    Err(e) => traversal.warn(parent, e),
+                }
+            }
+        }
+    }
+
+    traversal.found
+}
+
+/// Finds the songs in a folder below a song path, with its subfolders if
+/// `recursive` is set. Nothing is found if the folder is hidden or ignored.
+pub fn find_songs_below(
+    root: &Path,
+    dir: &Path,
+    recursive: bool,
+    options: TraversalOptions,
+) -> SongFiles {
+    let mut traversal = Traversal::new(options, recursive);
+    traversal.walk_below(root, dir);
+    traversal.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "#TITLE:Title\n#ARTIST:Artist\n#MP3:song.mp3\n#BPM:300\n: 0 4 0 la\nE\n";
+
+    #[test]
+    fn only_honours_ignore_files_below_the_song_path() {
+        let dir = std::env::temp_dir().join(format!("tuneperfect-ignore-{}", std::process::id()));
+        let root = dir.join("songs");
+        for folder in ["", "kept", "ignored"] {
+            fs::create_dir_all(root.join(folder)).unwrap();
+            fs::write(root.join(folder).join("song.txt"), SONG).unwrap();
+        }
+        fs::write(dir.join(IGNORE_FILE_NAME), "songs/\nkept/\n").unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "ignored/\n").unwrap();
+
+        let found = traverse_and_find_txt_files(
+            vec![root.to_string_lossy().to_string()],
+            TraversalOptions::default(),
+        );
+        let below = find_songs_below(
+            &root,
+            &root.join("kept"),
+            false,
+            TraversalOptions::default(),
+        );
+        let ignored = find_songs_below(
+            &root,
+            &root.join("ignored"),
+            false,
+            TraversalOptions::default(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut songs: Vec<_> = found
+            .songs
+            .keys()
+            .map(|song| Path::new(song).strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        songs.sort();
+        assert_eq!(
+            songs,
+            [PathBuf::from("kept/song.txt"), PathBuf::from("song.txt")]
+        );
+        assert_eq!(below.songs.len(), 1);
+        assert!(ignored.songs.is_empty());
+    }
 }
@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::AppError,
+    ultrastar::{archive::split_archive_path, filesystem::FileEntry, song::LocalSong},
+};
+
+/// Bump when the parser or `LocalSong` changes, so every song is parsed again.
+const INDEX_VERSION: u32 = 11;
+const INDEX_FILE_NAME: &str = "library-index.json";
+
+/// Size and modification time of a file, for files inside an archive those of the archive.
+pub fn file_stamp(path: &Path) -> Option<(u64, Option<u64>)> {
+    let path = split_archive_path(path).map_or_else(|| path.to_path_buf(), |(archive, _)| archive);
+    let metadata = fs::metadata(path).ok()?;
+    let modified_ms = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as u64);
+    Some((metadata.len(), modified_ms))
+}
+
+/// What a cached song was parsed from. A song is parsed again when its file
+/// changed, or when a file in its folder was added, removed or modified,
+/// since that can be the audio, video or an image it refers to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SongFileKey {
+    size: u64,
+    modified_ms: Option<u64>,
+    files: Vec<(String, Option<u64>)>,
+}
+
+impl SongFileKey {
+    pub fn new(song_file: &str, files: &[FileEntry]) -> Option<Self> {
+        let (size, modified_ms) = file_stamp(Path::new(song_file))?;
+        let mut files: Vec<(String, Option<u64>)> = files
+            .iter()
+            .filter(|file| file.path != song_file)
+            .map(|file| {
+                let modified_ms = file_stamp(Path::new(&file.path)).and_then(|stamp| stamp.1);
+                (file.filename.clone(), modified_ms)
+            })
+            .collect();
+        files.sort();
+
+        Some(Self {
+            size,
+            modified_ms,
+            files,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    key: SongFileKey,
+    result: Result<LocalSong, AppError>,
+}
+
+/// Parsed songs and parse failures from the last scan, stored in the app
+/// data directory so unchanged songs do not have to be parsed again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LibraryIndex {
+    version: u32,
+    app_version: String,
+    /// The media URLs of the songs start with it. The media server port can
+    /// change between launches, so the URLs are rebased when loading.
+    media_base_url: String,
+    songs: HashMap<String, IndexEntry>,
+}
+
+impl LibraryIndex {
+    pub fn new(media_base_url: &str) -> Self {
+        Self {
+            version: INDEX_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            media_base_url: media_base_url.to_string(),
+            songs: HashMap::new(),
+        }
+    }
+
+    pub fn path(app_data_dir: &Path) -> PathBuf {
+        app_data_dir.join(INDEX_FILE_NAME)
+    }
+
+    /// Loads the index, or returns an empty one if there is none yet, it
+    /// cannot be read or it was written by another parser version.
+    pub fn load(path: &Path, media_base_url: &str) -> Self {
+        let index = fs::read(path).ok().and_then(|content| {
+            match serde_json::from_slice::<Self>(&content) {
+                Ok(index) => Some(index),
+                Err(e) => {
+                    log::warn!("Ignoring unreadable library index: {}", e);
+                    None
+                }
+            }
+        });
+
+        match index {
+            Some(mut index)
+                if index.version == INDEX_VERSION
+                    && index.app_version == env!("CARGO_PKG_VERSION") =>
+            {
+                index.rebase_urls(media_base_url);
+                index
+            }
+            _ => Self::new(media_base_url),
+        }
+    }
+
+    fn rebase_urls(&mut self, media_base_url: &str) {
+        if self.media_base_url == media_base_url {
+            return;
+        }
+
+        let old_base_url = std::mem::replace(&mut self.media_base_url, media_base_url.to_string());
+        for entry in self.songs.values_mut() {
+            let Ok(song) = &mut entry.result else {
+                continue;
+            };
+            let thumbnail_urls = song
+                .cover_thumbnail_urls
+                .iter_mut()
+                .flat_map(|urls| [&mut urls.small, &mut urls.medium, &mut urls.large]);
+            for url in [
+                &mut song.audio_url,
+                &mut song.instrumental_url,
+                &mut song.video_url,
+                &mut song.cover_url,
+                &mut song.background_url,
+                &mut song.cdg_url,
+            ]
+            .into_iter()
+            .flatten()
+            .chain(thumbnail_urls)
+            {
+                if let Some(rest) = url.strip_prefix(&old_base_url) {
+                    *url = format!("{}{}", media_base_url, rest);
+                }
+            }
+        }
+    }
+
+    /// The cached result for a song file, if it was parsed from the same files.
+    pub fn get(&self, song_file: &str, key: &SongFileKey) -> Option<&Result<LocalSong, AppError>> {
+        self.songs
+            .get(song_file)
+            .filter(|entry| entry.key == *key)
+            .map(|entry| &entry.result)
+    }
+
+    /// The songs that were parsed, by the path of their txt file.
+    pub fn songs(&self) -> impl Iterator<Item = (&String, &LocalSong)> {
+        self.songs
+            .iter()
+            .filter_map(|(song_file, entry)| Some((song_file, entry.result.as_ref().ok()?)))
+    }
+
+    /// The path of a media file from the URL a song has for it.
+    pub fn media_path(&self, url: &str) -> Option<PathBuf> {
+        let encoded = url.strip_prefix(&self.media_base_url)?.strip_prefix('/')?;
+        let path = urlencoding::decode(encoded).ok()?;
+        Some(PathBuf::from(path.into_owned()))
+    }
+
+    /// Sets the ReplayGain values of a song, unless it changed since `hash`
+    /// was read.
+    pub fn set_replay_gain(&mut self, song_file: &str, hash: &str, gain: f32, peak: f32) {
+        let song = self
+            .songs
+            .get_mut(song_file)
+            .and_then(|entry| entry.result.as_mut().ok())
+            .filter(|song| song.song.hash == hash);
+        if let Some(song) = song {
+            song.replay_gain_track_gain = Some(gain);
+            song.replay_gain_track_peak = Some(peak);
+        }
+    }
+
+    /// I/O errors are not stored, they are usually temporary.
+    pub fn insert(
+        &mut self,
+        song_file: String,
+        key: SongFileKey,
+        result: Result<LocalSong, AppError>,
+    ) {
+        if !matches!(result, Err(AppError::IoError(_))) {
+            self.songs.insert(song_file, IndexEntry { key, result });
+        }
+    }
+
+    /// Copies the songs of `other` that are not below any of `paths`, so
+    /// scanning some folders keeps the songs of the others.
+    pub fn keep_songs_outside(&mut self, other: &Self, paths: &[String]) {
+        for (song_file, entry) in &other.songs {
+            if !paths
+                .iter()
+                .any(|path| song_file.starts_with(path.as_str()))
+            {
+                self.songs
+                    .entry(song_file.clone())
+                    .or_insert_with(|| entry.clone());
+            }
+        }
+    }
+
+    /// Writes the index to a temporary file first, so an interrupted write
+    /// cannot leave a broken index behind.
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temporary_path = path.with_extension("json.tmp");
+        let content = serde_json::to_vec(self).map_err(|e| AppError::IoError(e.to_string()))?;
+        fs::write(&temporary_path, content)?;
+        fs::rename(&temporary_path, path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::ultrastar::{
+    diagnostics::{DiagnosticCode, ParseDiagnostic},
+    song::{Note, NoteType, Song, Voice},
+};
+
+/// Notes further than this from a voice's median pitch are reported as outliers.
+const PITCH_OUTLIER_SEMITONES: i32 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum LintCategory {
+    Timing,
+    Pitch,
+    Structure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum LintCode {
+    OverlappingNotes,
+    NotesOutOfOrder,
+    ZeroLengthNote,
+    EarlyLineBreak,
+    PitchOutlier,
+    MissingEnd,
+}
+
+impl LintCode {
+    pub fn category(&self) -> LintCategory {
+        match self {
+            LintCode::OverlappingNotes
+            | LintCode::NotesOutOfOrder
+            | LintCode::ZeroLengthNote
+            | LintCode::EarlyLineBreak => LintCategory::Timing,
+            LintCode::PitchOutlier => LintCategory::Pitch,
+            LintCode::MissingEnd => LintCategory::Structure,
+        }
+    }
+}
+
+/// A musical or structural problem in a parsed song. Indices point into
+/// `Song::voices`, `Voice::phrases` and `Phrase::notes`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct LintFinding {
+    pub category: LintCategory,
+    pub code: LintCode,
+    pub voice: Option<u32>,
+    pub phrase: Option<u32>,
+    pub note: Option<u32>,
+    pub beat: Option<i32>,
+    pub message: String,
+}
+
+struct NoteRef<'a> {
+    phrase: usize,
+    note: usize,
+    inner: &'a Note,
+}
+
+impl NoteRef<'_> {
+    fn end_beat(&self) -> i32 {
+        self.inner.start_beat + self.inner.length
+    }
+}
+
+fn finding(code: LintCode, voice: usize, note: &NoteRef, message: String) -> LintFinding {
+    LintFinding {
+        category: code.category(),
+        code,
+        voice: Some(voice as u32),
+        phrase: Some(note.phrase as u32),
+        note: Some(note.note as u32),
+        beat: Some(note.inner.start_beat),
+        message,
+    }
+}
+
+fn has_pitch(note: &Note) -> bool {
+    matches!(note.note_type, NoteType::Normal | NoteType::Golden)
+}
+
+fn lint_voice(voice_index: usize, voice: &Voice, findings: &mut Vec<LintFinding>) {
+    let notes: Vec<NoteRef> = voice
+        .phrases
+        .iter()
+        .enumerate()
+        .flat_map(|(phrase, p)| {
+            p.notes
+                .iter()
+                .enumerate()
+                .map(move |(note, inner)| NoteRef {
+                    phrase,
+                    note,
+                    inner,
+                })
+        })
+        .collect();
+
+    for note in &notes {
+        if note.inner.length <= 0 {
+            findings.push(finding(
+                LintCode::ZeroLengthNote,
+                voice_index,
+                note,
+                format!(
+                    "Note '{}' has a length of {} beats",
+                    note.inner.text, note.inner.length
+                ),
+            ));
+        }
+    }
+
+    for pair in notes.windows(2) {
+        let (previous, note) = (&pair[0], &pair[1]);
+        if note.inner.start_beat < previous.inner.start_beat {
+            findings.push(finding(
+                LintCode::NotesOutOfOrder,
+                voice_index,
+                note,
+                format!(
+                    "Note '{}' at beat {} comes after a note at beat {}",
+                    note.inner.text, note.inner.start_beat, previous.inner.start_beat
+                ),
+            ));
+        } else if note.inner.start_beat < previous.end_beat() {
+            findings.push(finding(
+                LintCode::OverlappingNotes,
+                voice_index,
+                note,
+                format!(
+                    "Note '{}' at beat {} starts before the previous note ends at beat {}",
+                    note.inner.text,
+                    note.inner.start_beat,
+                    previous.end_beat()
+                ),
+            ));
+        }
+    }
+
+    for (phrase_index, phrase) in voice.phrases.iter().enumerate() {
+        let Some(end_beat) = phrase
+            .notes
+            .iter()
+            .map(|note| note.start_beat + note.length)
+            .max()
+        else {
+            continue;
+        };
+        if phrase.disappear_beat < end_beat {
+            findings.push(LintFinding {
+                category: LintCode::EarlyLineBreak.category(),
+                code: LintCode::EarlyLineBreak,
+                voice: Some(voice_index as u32),
+                phrase: Some(phrase_index as u32),
+                note: None,
+                beat: Some(phrase.disappear_beat),
+                message: format!(
+                    "Line break at beat {} comes before the line's notes end at beat {}",
+                    phrase.disappear_beat, end_beat
+                ),
+            });
+        }
+    }
+
+    let mut pitches: Vec<i32> = notes
+        .iter()
+        .filter(|note| has_pitch(note.inner))
+        .map(|note| note.inner.txt_pitch)
+        .collect();
+    if pitches.is_empty() {
+        return;
+    }
+    pitches.sort_unstable();
+    let median = pitches[pitches.len() / 2];
+
+    for note in notes.iter().filter(|note| has_pitch(note.inner)) {
+        let distance = (note.inner.txt_pitch - median).abs();
+        if distance > PITCH_OUTLIER_SEMITONES {
+            findings.push(finding(
+                LintCode::PitchOutlier,
+                voice_index,
+                note,
+                format!(
+                    "Note '{}' is {} semitones away from the median pitch of the voice",
+                    note.inner.text, distance
+                ),
+            ));
+        }
+    }
+}
+
+/// Checks a parsed song for problems that are valid syntax but break gameplay.
+/// Structural problems the parser already noticed, like a missing end marker,
+/// are taken from its diagnostics.
+pub fn lint_song(song: &Song, diagnostics: &[ParseDiagnostic]) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.code == DiagnosticCode::MissingEnd)
+    {
+        findings.push(LintFinding {
+            category: LintCode::MissingEnd.category(),
+            code: LintCode::MissingEnd,
+            voice: None,
+            phrase: None,
+            note: None,
+            beat: None,
+            message: "Missing end marker 'E'".to_string(),
+        });
+    }
+
+    for (voice_index, voice) in song.voices.iter().enumerate() {
+        lint_voice(voice_index, voice, &mut findings);
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ultrastar::parser::parse_ultrastar_txt;
+
+    fn lint(body: &str) -> Vec<LintFinding> {
+        let content = format!(
+            "#TITLE:Title\n#ARTIST:Artist\n#MP3:song.mp3\n#BPM:300\n#GAP:0\n{}",
+            body
+        );
+        let (song, diagnostics) = parse_ultrastar_txt(&content).unwrap();
+        lint_song(&song, &diagnostics)
+    }
+
+    fn codes(findings: &[LintFinding]) -> Vec<LintCode> {
+        findings.iter().map(|finding| finding.code).collect()
+    }
+
+    #[test]
+    fn finds_nothing_in_clean_songs() {
+        assert!(lint(": 0 2 0 One\n: 2 2 2 two\n- 6\n: 8 2 4 Three\nE\n").is_empty());
+    }
+
+    #[test]
+    fn finds_overlapping_notes() {
+        let findings = lint(": 0 4 0 One\n: 2 2 0 two\nE\n");
+        assert_eq!(codes(&findings), vec![LintCode::OverlappingNotes]);
+        assert_eq!(findings[0].category, LintCategory::Timing);
+        assert_eq!(findings[0].note, Some(1));
+        assert_eq!(findings[0].beat, Some(2));
+    }
+
+    #[test]
+    fn finds_notes_out_of_order() {
+        let findings = lint(": 4 2 0 One\n: 0 2 0 two\n- 6\n: 8 2 0 Three\nE\n");
+        assert_eq!(codes(&findings), vec![LintCode::NotesOutOfOrder]);
+        assert_eq!(findings[0].beat, Some(0));
+    }
+
+    #[test]
+    fn finds_zero_length_notes() {
+        let findings = lint(": 0 0 0 One\n: 2 2 0 two\nE\n");
+        assert_eq!(codes(&findings), vec![LintCode::ZeroLengthNote]);
+        assert_eq!(findings[0].note, Some(0));
+    }
+
+    #[test]
+    fn finds_early_line_breaks() {
+        let findings = lint(": 0 4 0 One\n- 2\n: 6 2 0 Two\nE\n");
+        assert_eq!(codes(&findings), vec![LintCode::EarlyLineBreak]);
+        assert_eq!(findings[0].phrase, Some(0));
+        assert_eq!(findings[0].note, None);
+        assert_eq!(findings[0].beat, Some(2));
+    }
+
+    #[test]
+    fn finds_pitch_outliers_further_than_24_semitones() {
+        let findings = lint(": 0 2 0 One\n: 2 2 0 two\n: 4 2 24 three\n: 6 2 -25 four\nE\n");
+        assert_eq!(codes(&findings), vec![LintCode::PitchOutlier]);
+        assert_eq!(findings[0].category, LintCategory::Pitch);
+        assert_eq!(findings[0].note, Some(3));
+    }
+
+    #[test]
+    fn ignores_the_pitch_of_freestyle_and_rap_notes() {
+        assert!(lint(": 0 2 0 One\n: 2 2 0 two\nF 4 2 40 three\nR 6 2 -40 four\nE\n").is_empty());
+    }
+
+    #[test]
+    fn finds_missing_end_markers() {
+        let findings = lint(": 0 2 0 One\n");
+        assert_eq!(codes(&findings), vec![LintCode::MissingEnd]);
+        assert_eq!(findings[0].category, LintCategory::Structure);
+        assert_eq!(findings[0].voice, None);
+    }
+
+    #[test]
+    fn reports_the_voice_of_findings() {
+        let findings = lint("P1\n: 0 2 0 One\nP2\n: 0 0 0 One\nE\n");
+        assert_eq!(codes(&findings), vec![LintCode::ZeroLengthNote]);
+        assert_eq!(findings[0].voice, Some(1));
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::{
+    error::AppError,
+    ultrastar::{archive::read_media_file, library_index::file_stamp},
+};
+
+/// ReplayGain 2.0 plays everything at this loudness.
+const REFERENCE_LOUDNESS_LUFS: f64 = -18.0;
+const CACHE_FILE_NAME: &str = "loudness-cache.json";
+
+const BLOCK_STEP_MS: u32 = 100;
+/// A gating block is four steps long, so consecutive blocks overlap by 75%.
+const STEPS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// Taps of the true peak interpolation filter per oversampled phase.
+const TAPS_PER_PHASE: usize = 12;
+
+/// Integrated loudness and true peak of an audio file per EBU R128.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    pub integrated_lufs: f64,
+    /// Linear, 1.0 is full scale.
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// The ReplayGain track gain in dB and track peak for this loudness.
+    pub fn replay_gain(&self) -> (f32, f32) {
+        (
+            (REFERENCE_LOUDNESS_LUFS - self.integrated_lufs) as f32,
+            self.true_peak as f32,
+        )
+    }
+}
+
+/// A second order IIR filter in direct form I.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// The two stages of the ITU-R BS.1770 K-weighting filter, a high shelf
+/// modelling the head and a high pass, for any sample rate.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+/// Weight of a channel in the loudness sum, surround channels count more
+/// and the LFE channel is left out.
+fn channel_weight(channel: Channels) -> f64 {
+    if channel == Channels::LFE1 {
+        0.0
+    } else if [
+        Channels::SIDE_LEFT,
+        Channels::SIDE_RIGHT,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+    ]
+    .contains(&channel)
+    {
+        1.41
+    } else {
+        1.0
+    }
+}
+
+/// Interpolates the signal at `factor` times the sample rate to find peaks
+/// between samples. A Hann windowed sinc, one set of taps per phase.
+struct TruePeak {
+    phases: Vec<[f64; TAPS_PER_PHASE]>,
+    history: Vec<[f64; TAPS_PER_PHASE]>,
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        // Rates of 96 kHz and more need less oversampling to reach 192 kHz
+        let factor = match sample_rate {
+            0..96_000 => 4,
+            96_000..192_000 => 2,
+            _ => 1,
+        };
+        let length = factor * TAPS_PER_PHASE;
+        let center = (length - 1) as f64 / 2.0;
+        let phases = (0..factor)
+            .map(|phase| {
+                let mut taps = [0.0; TAPS_PER_PHASE];
+                for (tap, value) in taps.iter_mut().enumerate() {
+                    let n = (tap * factor + phase) as f64;
+                    let x = (n - center) / factor as f64;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (PI * x).sin() / (PI * x)
+                    };
+                    let window = 0.5 - 0.5 * (2.0 * PI * (n + 1.0) / (length + 1) as f64).cos();
+                    *value = sinc * window;
+                }
+                taps
+            })
+            .collect();
+
+        Self {
+            phases,
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, channel: usize, sample: f64) {
+        let history = &mut self.history[channel];
+        history.copy_within(0..TAPS_PER_PHASE - 1, 1);
+        history[0] = sample;
+
+        self.peak = self.peak.max(sample.abs());
+        for taps in &self.phases {
+            let value: f64 = taps.iter().zip(history.iter()).map(|(t, x)| t * x).sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+/// Measures loudness per ITU-R BS.1770-4 and EBU R128: K-weighted mean
+/// square over 400 ms blocks, gated at -70 LUFS and 10 LU below the mean.
+struct LoudnessMeter {
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    true_peak: TruePeak,
+    step_length: usize,
+    /// Sum of squares per channel of the current step.
+    step_sums: Vec<f64>,
+    step_position: usize,
+    /// The weighted mean squares of the last steps.
+    steps: Vec<f64>,
+    blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    fn new(sample_rate: u32, channels: Channels) -> Self {
+        let weights: Vec<f64> = channels.iter().map(channel_weight).collect();
+        Self {
+            filters: vec![k_weighting(sample_rate); weights.len()],
+            true_peak: TruePeak::new(sample_rate, weights.len()),
+            step_length: (sample_rate * BLOCK_STEP_MS / 1000).max(1) as usize,
+            step_sums: vec![0.0; weights.len()],
+            weights,
+            step_position: 0,
+            steps: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn add_interleaved(&mut self, samples: &[f32]) {
+        let channels = self.weights.len();
+        for frame in samples.chunks_exact(channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                self.true_peak.process(channel, sample);
+
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let filtered = high_pass.process(shelf.process(sample));
+                self.step_sums[channel] += filtered * filtered;
+            }
+
+            self.step_position += 1;
+            if self.step_position == self.step_length {
+                self.finish_step();
+            }
+        }
+    }
+
+    fn finish_step(&mut self) {
+        let mean_square: f64 = self
+            .step_sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(sum, weight)| sum * weight)
+            .sum::<f64>()
+            / self.step_length as f64;
+        self.step_sums.fill(0.0);
+        self.step_position = 0;
+
+        self.steps.push(mean_square);
+        if self.steps.len() >= STEPS_PER_BLOCK {
+            let block = &self.steps[self.steps.len() - STEPS_PER_BLOCK..];
+            self.blocks
+                .push(block.iter().sum::<f64>() / STEPS_PER_BLOCK as f64);
+        }
+    }
+
+    /// `None` if the audio is shorter than one block or silent.
+    fn finish(&self) -> Option<Loudness> {
+        let loudness = |mean_square: f64| -0.691 + 10.0 * mean_square.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        let audible: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|block| loudness(*block) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if audible.is_empty() {
+            return None;
+        }
+
+        let relative_gate = loudness(mean(&audible)) + RELATIVE_GATE_LU;
+        let gated: Vec<f64> = audible
+            .into_iter()
+            .filter(|block| loudness(*block) > relative_gate)
+            .collect();
+
+        Some(Loudness {
+            integrated_lufs: loudness(mean(&gated)),
+            true_peak: self.true_peak.peak,
+        })
+    }
+}
+
+/// Decodes an audio file and measures its loudness. Returns `None` if
+/// `cancelled` was set while decoding.
+pub fn measure_loudness(
+    content: Vec<u8>,
+    extension: Option<&str>,
+    cancelled: &AtomicBool,
+) -> Result<Option<Loudness>, AppError> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(content)), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| AppError::IoError("No audio track found".to_string()))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut meter: Option<LoudnessMeter> = None;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame is skipped, like players do
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let meter = meter.get_or_insert_with(|| LoudnessMeter::new(spec.rate, spec.channels));
+
+        if buffer
+            .as_ref()
+            .is_none_or(|buffer| buffer.capacity() < decoded.capacity())
+        {
+            buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        if let Some(buffer) = buffer.as_mut() {
+            buffer.copy_interleaved_ref(decoded);
+            meter.add_interleaved(buffer.samples());
+        }
+    }
+
+    meter
+        .and_then(|meter| meter.finish())
+        .map(Some)
+        .ok_or_else(|| AppError::IoError("The audio is silent or too short".to_string()))
+}
+
+/// The MD5 hash of a file's content, valid while its size and modification
+/// time stay the same.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileHash {
+    size: u64,
+    modified_ms: Option<u64>,
+    hash: String,
+}
+
+/// Loudness of analysed audio files by the MD5 hash of their content, so a
+/// file is only decoded again when it changed, even if it was moved. Files
+/// that could not be analysed are kept too, so they are not decoded on every
+/// launch.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LoudnessCache {
+    files: HashMap<String, Result<Loudness, AppError>>,
+    /// Hashes by file path, so unchanged files are not read to hash them.
+    hashes: HashMap<String, FileHash>,
+}
+
+impl LoudnessCache {
+    pub fn path(app_cache_dir: &Path) -> PathBuf {
+        app_cache_dir.join(CACHE_FILE_NAME)
+    }
+
+    /// Loads the cache, or returns an empty one if there is none yet or it
+    /// cannot be read.
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the cache to a temporary file first, like the library index.
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temporary_path = path.with_extension("json.tmp");
+        let content = serde_json::to_vec(self).map_err(|e| AppError::IoError(e.to_string()))?;
+        fs::write(&temporary_path, content)?;
+        fs::rename(&temporary_path, path)?;
+
+        Ok(())
+    }
+
+    /// The content hash of a file from when it had the same size and
+    /// modification time.
+    fn known_hash(&self, path: &Path) -> Option<&String> {
+        let (size, modified_ms) = file_stamp(path)?;
+        self.hashes
+            .get(path.to_string_lossy().as_ref())
+            .filter(|file_hash| file_hash.size == size && file_hash.modified_ms == modified_ms)
+            .map(|file_hash| &file_hash.hash)
+    }
+
+    /// The ReplayGain track gain and peak of an audio file that was analysed
+    /// before and did not change since. Does not read the file.
+    pub fn replay_gain(&self, path: &Path) -> Option<(f32, f32)> {
+        let hash = self.known_hash(path)?;
+        let loudness = self.files.get(hash)?.as_ref().ok()?;
+        Some(loudness.replay_gain())
+    }
+
+    /// The loudness of an audio file, from the cache if its content was
+    /// analysed before. Returns `None` if `cancelled` was set.
+    pub fn analyze(
+        &mut self,
+        path: &Path,
+        cancelled: &AtomicBool,
+    ) -> Result<Option<Loudness>, AppError> {
+        if let Some(result) = self.known_hash(path).and_then(|hash| self.files.get(hash)) {
+            return result.clone().map(Some);
+        }
+
+        let stamp = file_stamp(path);
+        let content = read_media_file(path)?;
+        let hash = format!("{:x}", md5::compute(&content));
+        if let Some((size, modified_ms)) = stamp {
+            self.hashes.insert(
+                path.to_string_lossy().into_owned(),
+                FileHash {
+                    size,
+                    modified_ms,
+                    hash: hash.clone(),
+                },
+            );
+        }
+        if let Some(result) = self.files.get(&hash) {
+            return result.clone().map(Some);
+        }
+
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let loudness = match measure_loudness(content, extension, cancelled) {
+            Ok(Some(loudness)) => loudness,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.files.insert(hash, Err(e.clone()));
+                return Err(e);
+            }
+        };
+        self.files.insert(hash, Ok(loudness));
+
+        Ok(Some(loudness))
+    }
+}
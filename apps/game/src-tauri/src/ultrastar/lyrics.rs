@@ -0,0 +1,452 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    error::AppError,
+    ultrastar::{
+        song::Song,
+        timeline::{build_song_timeline, TimelineEventKind},
+    },
+};
+
+/// `Lrc` has one timestamp per line, `EnhancedLrc` adds one per syllable,
+/// `Srt` and `WebVtt` have one cue per line and `Ass` has karaoke timing
+/// with `\k` tags.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum LyricsFormat {
+    Lrc,
+    EnhancedLrc,
+    Srt,
+    WebVtt,
+    Ass,
+}
+
+const ASS_STYLE_FORMAT: &str = "Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, \
+    OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, \
+    BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding";
+
+struct Syllable {
+    start_ms: f64,
+    end_ms: f64,
+    text: String,
+}
+
+struct LyricLine {
+    /// More than one for lines of shared sections, which every singer sings.
+    voices: Vec<usize>,
+    shared: bool,
+    start_ms: f64,
+    end_ms: f64,
+    syllables: Vec<Syllable>,
+}
+
+impl LyricLine {
+    fn text(&self) -> String {
+        self.syllables
+            .iter()
+            .map(|syllable| syllable.text.as_str())
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    fn is_same_shared_line(&self, other: &LyricLine) -> bool {
+        self.shared
+            && other.shared
+            && self.start_ms == other.start_ms
+            && self.end_ms == other.end_ms
+            && self.text() == other.text()
+    }
+}
+
+/// Collects the lines of all voices, sorted by start time. `~` only extends
+/// the previous syllable, so it is dropped from the text. The parser copies
+/// shared phrases into every voice singing them, they are merged back into
+/// one line here.
+fn collect_lines(song: &Song) -> Result<Vec<LyricLine>, AppError> {
+    let timeline = build_song_timeline(song)?;
+    let mut lines = Vec::new();
+
+    for (voice, voice_timeline) in timeline.voices.iter().enumerate() {
+        let mut phrase_lines: HashMap<u32, usize> = HashMap::new();
+        for event in &voice_timeline.events {
+            match event.kind {
+                TimelineEventKind::Line => {
+                    phrase_lines.insert(event.phrase, lines.len());
+                    lines.push(LyricLine {
+                        voices: vec![voice],
+                        shared: song.voices[voice].phrases[event.phrase as usize].shared,
+                        start_ms: event.start_ms,
+                        end_ms: event.end_ms,
+                        syllables: Vec::new(),
+                    });
+                }
+                TimelineEventKind::Note => {
+                    if let Some(&line) = phrase_lines.get(&event.phrase) {
+                        lines[line].syllables.push(Syllable {
+                            start_ms: event.start_ms,
+                            end_ms: event.end_ms,
+                            text: event.text.as_deref().unwrap_or("").replace('~', ""),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    lines.retain(|line| !line.text().is_empty());
+    lines.sort_by(|a, b| a.start_ms.total_cmp(&b.start_ms));
+
+    let mut merged: Vec<LyricLine> = Vec::with_capacity(lines.len());
+    for line in lines {
+        // Lines are sorted, so copies of a shared line are next to each other
+        // unless other lines start at the same time
+        let same_line = merged
+            .iter_mut()
+            .rev()
+            .take_while(|other| other.start_ms == line.start_ms)
+            .find(|other| other.is_same_shared_line(&line));
+        match same_line {
+            Some(other) => other.voices.extend(line.voices),
+            None => merged.push(line),
+        }
+    }
+
+    Ok(merged)
+}
+
+fn singer_name(song: &Song, voice: usize) -> String {
+    song.voices
+        .get(voice)
+        .and_then(|voice| voice.name.clone())
+        .unwrap_or_else(|| format!("P{}", voice + 1))
+}
+
+/// The singers of a line, joined with `&` for shared lines.
+fn singer_names(song: &Song, line: &LyricLine) -> String {
+    line.voices
+        .iter()
+        .map(|&voice| singer_name(song, voice))
+        .collect::<Vec<_>>()
+        .join(" & ")
+}
+
+/// Splits milliseconds into hours, minutes, seconds and the remaining milliseconds.
+fn split_ms(ms: f64) -> (u64, u64, u64, u64) {
+    let ms = ms.max(0.0).round() as u64;
+    (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+/// `mm:ss.xx`, minutes are not wrapped into hours.
+fn lrc_timestamp(ms: f64) -> String {
+    let centiseconds = (ms.max(0.0) / 10.0).round() as u64;
+    format!(
+        "{:02}:{:02}.{:02}",
+        centiseconds / 6000,
+        centiseconds / 100 % 60,
+        centiseconds % 100
+    )
+}
+
+fn srt_timestamp(ms: f64) -> String {
+    let (hours, minutes, seconds, millis) = split_ms(ms);
+    format!("{:02}:{:02}:{:02},{:03}", hours, minutes, seconds, millis)
+}
+
+fn vtt_timestamp(ms: f64) -> String {
+    let (hours, minutes, seconds, millis) = split_ms(ms);
+    format!("{:02}:{:02}:{:02}.{:03}", hours, minutes, seconds, millis)
+}
+
+fn ass_timestamp(ms: f64) -> String {
+    let centiseconds = (ms.max(0.0) / 10.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centiseconds / 360_000,
+        centiseconds / 6000 % 60,
+        centiseconds / 100 % 60,
+        centiseconds % 100
+    )
+}
+
+fn write_lrc(song: &Song, lines: &[LyricLine], enhanced: bool) -> String {
+    let is_duet = song.voices.len() > 1;
+    let mut output = String::new();
+
+    let _ = writeln!(output, "[ti:{}]", song.title);
+    let _ = writeln!(output, "[ar:{}]", song.artist);
+    if let Some(album) = &song.album {
+        let _ = writeln!(output, "[al:{}]", album);
+    }
+
+    for line in lines {
+        let _ = write!(output, "[{}]", lrc_timestamp(line.start_ms));
+        // LRC has no tracks, so duet lines are prefixed with the singer
+        if is_duet {
+            let _ = write!(output, "{}: ", singer_names(song, line));
+        }
+
+        if enhanced {
+            let syllables = line
+                .syllables
+                .iter()
+                .filter(|syllable| !syllable.text.is_empty());
+            for (index, syllable) in syllables.enumerate() {
+                // Word boundaries go in front of the timestamp
+                let text = syllable.text.trim_start();
+                if index > 0 && text.len() < syllable.text.len() {
+                    output.push(' ');
+                }
+                let _ = write!(output, "<{}>{}", lrc_timestamp(syllable.start_ms), text);
+            }
+            if let Some(last_syllable) = line.syllables.last() {
+                let _ = write!(output, "<{}>", lrc_timestamp(last_syllable.end_ms));
+            }
+            output.push('\n');
+        } else {
+            let _ = writeln!(output, "{}", line.text());
+        }
+    }
+
+    output
+}
+
+fn write_srt(song: &Song, lines: &[LyricLine]) -> String {
+    let is_duet = song.voices.len() > 1;
+    let mut output = String::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        let _ = writeln!(output, "{}", index + 1);
+        let _ = writeln!(
+            output,
+            "{} --> {}",
+            srt_timestamp(line.start_ms),
+            srt_timestamp(line.end_ms)
+        );
+        if is_duet {
+            let _ = write!(output, "{}: ", singer_names(song, line));
+        }
+        let _ = writeln!(output, "{}\n", line.text());
+    }
+
+    output
+}
+
+fn write_vtt(song: &Song, lines: &[LyricLine]) -> String {
+    let is_duet = song.voices.len() > 1;
+    let mut output = String::from("WEBVTT\n\n");
+
+    for line in lines {
+        let _ = writeln!(
+            output,
+            "{} --> {}",
+            vtt_timestamp(line.start_ms),
+            vtt_timestamp(line.end_ms)
+        );
+        let escape = |text: &str| {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        };
+        let text = escape(&line.text());
+        if is_duet {
+            let _ = writeln!(
+                output,
+                "<v {}>{}\n",
+                escape(&singer_names(song, line)),
+                text
+            );
+        } else {
+            let _ = writeln!(output, "{}\n", text);
+        }
+    }
+
+    output
+}
+
+/// Writes one style per voice, the first at the bottom of the screen and the
+/// second at the top, like in the game. Shared lines use the style of their
+/// first singer. Syllables are highlighted with `\k`,
+/// pauses between them get an empty `\k`.
+fn write_ass(song: &Song, lines: &[LyricLine]) -> String {
+    let mut output = String::new();
+
+    let _ = writeln!(output, "[Script Info]");
+    let _ = writeln!(output, "Title: {} - {}", song.artist, song.title);
+    let _ = writeln!(output, "ScriptType: v4.00+");
+    let _ = writeln!(output, "PlayResX: 1920");
+    let _ = writeln!(output, "PlayResY: 1080");
+    let _ = writeln!(output, "WrapStyle: 2");
+    output.push('\n');
+
+    let _ = writeln!(output, "[V4+ Styles]");
+    let _ = writeln!(output, "Format: {}", ASS_STYLE_FORMAT);
+    // Colors are &HAABBGGRR, the primary color is the sung part
+    let highlight_colors = ["&H00FFB400", "&H00B400FF", "&H0000C8FF", "&H0064FF64"];
+    for voice in 0..song.voices.len().max(1) {
+        let alignment = match voice {
+            0 => 2,
+            1 => 8,
+            _ => 5,
+        };
+        let _ = writeln!(
+            output,
+            "Style: P{},Arial,64,{},&H00FFFFFF,&H00000000,&H80000000,-1,0,0,0,100,100,0,0,1,3,0,{},60,60,60,1",
+            voice + 1,
+            highlight_colors[voice % highlight_colors.len()],
+            alignment
+        );
+    }
+    output.push('\n');
+
+    let _ = writeln!(output, "[Events]");
+    let _ = writeln!(
+        output,
+        "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text"
+    );
+    let centiseconds = |ms: f64| (ms / 10.0).round() as i64;
+    for line in lines {
+        let mut position = centiseconds(line.start_ms);
+        let mut text = String::new();
+        for (index, syllable) in line.syllables.iter().enumerate() {
+            let start = centiseconds(syllable.start_ms);
+            if start > position {
+                let _ = write!(text, "{{\\k{}}}", start - position);
+                position = start;
+            }
+            let end = centiseconds(syllable.end_ms).max(position);
+            let syllable_text = if index == 0 {
+                syllable.text.trim_start()
+            } else {
+                &syllable.text
+            };
+            let _ = write!(
+                text,
+                "{{\\k{}}}{}",
+                end - position,
+                syllable_text.replace(['{', '}'], "")
+            );
+            position = end;
+        }
+
+        let _ = writeln!(
+            output,
+            "Dialogue: 0,{},{},P{},{},0,0,0,,{}",
+            ass_timestamp(line.start_ms),
+            ass_timestamp(line.end_ms),
+            line.voices[0] + 1,
+            singer_names(song, line),
+            text.trim_end()
+        );
+    }
+
+    output
+}
+
+/// Renders the lyrics of a song in the given subtitle or lyrics format.
+/// Timing comes from the song timeline, so `#START` and `#END` are honored.
+pub fn export_lyrics(song: &Song, format: LyricsFormat) -> Result<String, AppError> {
+    let lines = collect_lines(song)?;
+
+    Ok(match format {
+        LyricsFormat::Lrc => write_lrc(song, &lines, false),
+        LyricsFormat::EnhancedLrc => write_lrc(song, &lines, true),
+        LyricsFormat::Srt => write_srt(song, &lines),
+        LyricsFormat::WebVtt => write_vtt(song, &lines),
+        LyricsFormat::Ass => write_ass(song, &lines),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ultrastar::parser::parse_ultrastar_txt;
+
+    /// 50 ms per beat, starting one second into the audio.
+    fn song(body: &str) -> Song {
+        let content = format!(
+            "#TITLE:Title\n#ARTIST:Artist\n#MP3:song.mp3\n#BPM:300\n#GAP:1000\n{}E\n",
+            body
+        );
+        parse_ultrastar_txt(&content).unwrap().0
+    }
+
+    fn solo() -> Song {
+        song(": 0 2 0 Hel\n: 2 2 0 lo\n: 6 2 0  world\n- 10\n: 20 4 0 Bye\n: 24 2 0 ~\n")
+    }
+
+    fn duet() -> Song {
+        song(
+            "#P1:Ann\n#P2:Bob\n\
+             P1\n: 0 2 0 One\n- 4\n\
+             P2\n: 6 2 0 Two\n- 10\n\
+             P3\n: 12 2 0 Both\n",
+        )
+    }
+
+    #[test]
+    fn writes_lrc() {
+        assert_eq!(
+            export_lyrics(&solo(), LyricsFormat::Lrc).unwrap(),
+            "[ti:Title]\n[ar:Artist]\n[00:01.00]Hello world\n[00:02.00]Bye\n"
+        );
+    }
+
+    #[test]
+    fn writes_enhanced_lrc() {
+        assert_eq!(
+            export_lyrics(&solo(), LyricsFormat::EnhancedLrc).unwrap(),
+            "[ti:Title]\n[ar:Artist]\n\
+             [00:01.00]<00:01.00>Hel<00:01.10>lo <00:01.30>world<00:01.40>\n\
+             [00:02.00]<00:02.00>Bye<00:02.30>\n"
+        );
+    }
+
+    #[test]
+    fn writes_srt() {
+        assert_eq!(
+            export_lyrics(&solo(), LyricsFormat::Srt).unwrap(),
+            "1\n00:00:01,000 --> 00:00:01,500\nHello world\n\n\
+             2\n00:00:02,000 --> 00:00:02,350\nBye\n\n"
+        );
+    }
+
+    #[test]
+    fn writes_web_vtt() {
+        assert_eq!(
+            export_lyrics(&solo(), LyricsFormat::WebVtt).unwrap(),
+            "WEBVTT\n\n\
+             00:00:01.000 --> 00:00:01.500\nHello world\n\n\
+             00:00:02.000 --> 00:00:02.350\nBye\n\n"
+        );
+    }
+
+    #[test]
+    fn writes_ass() {
+        let ass = export_lyrics(&solo(), LyricsFormat::Ass).unwrap();
+        assert!(ass.starts_with("[Script Info]\nTitle: Artist - Title\n"));
+        assert!(ass.contains("\nStyle: P1,Arial,64,&H00FFB400,"));
+        assert!(ass.ends_with(
+            "Dialogue: 0,0:00:01.00,0:00:01.50,P1,P1,0,0,0,,{\\k10}Hel{\\k10}lo{\\k10}{\\k10} world\n\
+             Dialogue: 0,0:00:02.00,0:00:02.35,P1,P1,0,0,0,,{\\k20}Bye{\\k10}\n"
+        ));
+    }
+
+    #[test]
+    fn writes_shared_lines_once_for_both_singers() {
+        assert_eq!(
+            export_lyrics(&duet(), LyricsFormat::Lrc).unwrap(),
+            "[ti:Title]\n[ar:Artist]\n\
+             [00:01.00]Ann: One\n[00:01.30]Bob: Two\n[00:01.60]Ann & Bob: Both\n"
+        );
+        let srt = export_lyrics(&duet(), LyricsFormat::Srt).unwrap();
+        assert_eq!(srt.matches("Both").count(), 1);
+        assert!(srt.contains("3\n00:00:01,600 --> 00:00:01,750\nAnn & Bob: Both\n"));
+        let vtt = export_lyrics(&duet(), LyricsFormat::WebVtt).unwrap();
+        assert_eq!(vtt.matches("Both").count(), 1);
+        assert!(vtt.contains("<v Ann &amp; Bob>Both\n"));
+        let ass = export_lyrics(&duet(), LyricsFormat::Ass).unwrap();
+        assert!(ass.contains(",P1,Ann & Bob,0,0,0,,{\\k10}Both\n"));
+    }
+}
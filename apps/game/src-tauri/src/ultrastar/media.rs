@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::ultrastar::filesystem::{
+    FileEntry, AUDIO_EXTENSIONS, IMAGE_EXTENSIONS, VIDEO_EXTENSIONS,
+};
+
+/// The header tag a media file was named in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum MediaTag {
+    Audio,
+    Instrumental,
+    Video,
+    Cover,
+    Background,
+}
+
+impl MediaTag {
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            MediaTag::Audio | MediaTag::Instrumental => &AUDIO_EXTENSIONS,
+            MediaTag::Video => &VIDEO_EXTENSIONS,
+            MediaTag::Cover | MediaTag::Background => &IMAGE_EXTENSIONS,
+        }
+    }
+}
+
+/// Why a file was used in place of the one named in the song, from the
+/// most to the least certain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum MediaMatch {
+    /// Same name with another extension, e.g. `song.m4a` for `song.mp3`.
+    OtherExtension,
+    /// The only audio file in the folder.
+    OnlyAudioFile,
+    /// Same name when ignoring case, whitespace and punctuation.
+    SimilarName,
+}
+
+/// A media file the song names that was not found, and the file used instead.
+/// The UI can offer to rewrite the tag to `found`.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct MediaSubstitution {
+    pub tag: MediaTag,
+    pub specified: String,
+    pub found: String,
+    #[serde(rename = "match")]
+    pub media_match: MediaMatch,
+}
+
+fn normalize(filename: &str) -> String {
+    filename.nfc().collect::<String>().to_lowercase()
+}
+
+/// Splits a normalized filename into its stem and extension.
+fn split_extension(filename: &str) -> (&str, &str) {
+    filename.rsplit_once('.').unwrap_or((filename, ""))
+}
+
+/// Only letters and digits, so `Artist - Title (Live).mp3` and
+/// `artist_title_live.mp3` have the same fuzzy stem.
+fn fuzzy_stem(stem: &str) -> String {
+    stem.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/// Finds the file for a media tag. An exact match ignores case and Unicode
+/// normalization. Otherwise the first of these fallbacks that finds a file of
+/// the tag's type is used and returned as a substitution: the same name with
+/// another extension, the only audio file in the folder (for `Audio` only)
+/// and the same fuzzy name.
+pub fn resolve_media_file<'a>(
+    files: &'a [FileEntry],
+    tag: MediaTag,
+    filename: &str,
+) -> Option<(&'a FileEntry, Option<MediaSubstitution>)> {
+    let target = normalize(filename);
+    let normalized: Vec<(&FileEntry, String)> = files
+        .iter()
+        .map(|file| (file, normalize(&file.filename)))
+        .collect();
+
+    if let Some((file, _)) = normalized.iter().find(|(_, name)| *name == target) {
+        return Some((file, None));
+    }
+
+    // Candidates of the tag's type, since a cover never stands in for audio
+    let candidates: Vec<&(&FileEntry, String)> = normalized
+        .iter()
+        .filter(|(_, name)| tag.extensions().contains(&split_extension(name).1))
+        .collect();
+    let (target_stem, _) = split_extension(&target);
+
+    let other_extension = || {
+        candidates
+            .iter()
+            .find(|(_, name)| split_extension(name).0 == target_stem)
+            .map(|(file, _)| (*file, MediaMatch::OtherExtension))
+    };
+    let only_audio_file = || match candidates.as_slice() {
+        [(file, _)] if tag == MediaTag::Audio => Some((*file, MediaMatch::OnlyAudioFile)),
+        _ => None,
+    };
+    let similar_name = || {
+        let target_fuzzy = fuzzy_stem(target_stem);
+        if target_fuzzy.is_empty() {
+            return None;
+        }
+        candidates
+            .iter()
+            .find(|(_, name)| fuzzy_stem(split_extension(name).0) == target_fuzzy)
+            .map(|(file, _)| (*file, MediaMatch::SimilarName))
+    };
+
+    let (file, media_match) = other_extension()
+        .or_else(only_audio_file)
+        .or_else(similar_name)?;
+
+    Some((
+        file,
+        Some(MediaSubstitution {
+            tag,
+            specified: filename.to_string(),
+            found: file.filename.clone(),
+            media_match,
+        }),
+    ))
+}
+
+/// Finds a file for a tag the song leaves out, or whose file is missing, by
+/// the names common song packs use: `[CO]` or `[BG]` in the name of the cover
+/// or background image, `cover.jpg` or `background.jpg`, and the only video
+/// in the folder.
+pub fn infer_media_file(files: &[FileEntry], tag: MediaTag) -> Option<&FileEntry> {
+    let candidates: Vec<(&FileEntry, String)> = files
+        .iter()
+        .map(|file| (file, normalize(&file.filename)))
+        .filter(|(_, name)| tag.extensions().contains(&split_extension(name).1))
+        .collect();
+
+    let (marker, stems): (&str, &[&str]) = match tag {
+        MediaTag::Cover => ("[co]", &["cover", "folder"]),
+        MediaTag::Background => ("[bg]", &["background", "bg"]),
+        MediaTag::Video => {
+            return match candidates.as_slice() {
+                [(file, _)] => Some(file),
+                _ => None,
+            };
+        }
+        MediaTag::Audio | MediaTag::Instrumental => return None,
+    };
+
+    candidates
+        .iter()
+        .find(|(_, name)| split_extension(name).0.contains(marker))
+        .or_else(|| {
+            stems.iter().find_map(|stem| {
+                candidates
+                    .iter()
+                    .find(|(_, name)| split_extension(name).0 == *stem)
+            })
+        })
+        .map(|(file, _)| *file)
+}
@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+
+use crate::{
+    error::AppError,
+    ultrastar::{
+        diagnostics::{DiagnosticCode, ParseDiagnostic},
+        filesystem::{find_media, AUDIO_EXTENSIONS, IMAGE_EXTENSIONS, VIDEO_EXTENSIONS},
+        song::{Note, NoteType, Phrase, Song, Voice},
+    },
+};
+
+const AUDIO_STEMS: [&str; 2] = ["music", "song"];
+
+/// Whether a file is a SingStar/Performous MelodyXML file.
+pub fn is_melody_chart(filename: &str) -> bool {
+    filename.eq_ignore_ascii_case("notes.xml")
+}
+
+/// 1-based lines of increasing byte offsets, for diagnostics. Counts from
+/// the previous offset on, so the content is only scanned once.
+struct LineCounter<'a> {
+    content: &'a [u8],
+    position: usize,
+    line: u32,
+}
+
+impl<'a> LineCounter<'a> {
+    fn new(content: &'a str) -> Self {
+        Self {
+            content: content.as_bytes(),
+            position: 0,
+            line: 1,
+        }
+    }
+
+    fn line_at(&mut self, position: u64) -> u32 {
+        let position = (position as usize).clamp(self.position, self.content.len());
+        self.line += self.content[self.position..position]
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count() as u32;
+        self.position = position;
+        self.line
+    }
+}
+
+/// Attributes of an element by their lowercased local name, so namespaced
+/// and differently cased files read the same.
+fn attributes(element: &BytesStart) -> HashMap<String, String> {
+    element
+        .attributes()
+        .filter_map(Result::ok)
+        .filter_map(|attribute| {
+            let key = String::from_utf8_lossy(attribute.key.local_name().as_ref()).to_lowercase();
+            let value = attribute.unescape_value().ok()?;
+            Some((key, value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn is_yes(attributes: &HashMap<String, String>, key: &str) -> bool {
+    attributes
+        .get(key)
+        .is_some_and(|value| value.eq_ignore_ascii_case("yes"))
+}
+
+/// Maps the `Singer` of a sentence to voice indices, `Group` sentences are sung by both.
+fn singer_to_voices(singer: &str) -> Option<Vec<usize>> {
+    match singer.replace(' ', "").to_lowercase().as_str() {
+        "solo1" => Some(vec![0]),
+        "solo2" => Some(vec![1]),
+        "group" => Some(vec![0, 1]),
+        _ => None,
+    }
+}
+
+struct Sentence {
+    voices: Vec<usize>,
+    phrase: Phrase,
+    joins_previous: bool,
+}
+
+/// Builds a `Song` from a SingStar/Performous `notes.xml`. Notes follow each
+/// other without gaps, rests are notes with `MidiNote="0"`. A `Semiquaver`
+/// resolution counts durations in sixteenth notes like UltraStar beats, so
+/// the tempo is the BPM as is, `Demisemiquaver` doubles it.
+///
+/// Duets either have one `<TRACK>` per singer or mark sentences with
+/// `Singer="Solo 1"`, `"Solo 2"` or `"Group"`. Title and artist come from the
+/// `<!-- Title: -->` and `<!-- Artist: -->` comments, or from a folder named
+/// `Artist - Title`. `files` are the file names in the song folder, used to
+/// pick the audio, video and images by their conventional names. The hash
+/// covers the XML, title and artist.
+pub fn parse_melody_xml(
+    content: &str,
+    files: &[String],
+    folder_name: &str,
+) -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
+    let mut reader = Reader::from_str(content);
+    reader.config_mut().trim_text(true);
+
+    let mut diagnostics = Vec::new();
+    let mut tempo: Option<f64> = None;
+    let mut resolution = 1.0;
+    let mut genre = None;
+    let mut year = None;
+    let mut title = None;
+    let mut artist = None;
+
+    let mut voices: Vec<Vec<Phrase>> = Vec::new();
+    let mut voice_names: HashMap<usize, String> = HashMap::new();
+    let mut track: Option<usize> = None;
+    let mut track_count = 0;
+    let mut sentence: Option<Sentence> = None;
+    let mut beat = 0;
+
+    let mut lines = LineCounter::new(content);
+    loop {
+        let position = reader.buffer_position();
+        let event = reader.read_event().map_err(|e| {
+            AppError::from(ParseDiagnostic::error(
+                Some(lines.line_at(reader.error_position())),
+                DiagnosticCode::UnknownLine,
+                format!("Invalid XML: {}", e),
+            ))
+        })?;
+        // Events start after the whitespace trimmed from the text before them
+        let rest = content.get(position as usize..).unwrap_or_default();
+        let start = position + (rest.len() - rest.trim_start().len()) as u64;
+        let line = Some(lines.line_at(start));
+
+        match event {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"MELODY" =>
+            {
+                let values = attributes(&element);
+                tempo = values
+                    .get("tempo")
+                    .and_then(|tempo| tempo.replace(',', ".").parse::<f64>().ok())
+                    .filter(|tempo| *tempo > 0.0);
+                resolution = match values.get("resolution").map(|value| value.as_str()) {
+                    None | Some("Semiquaver") => 1.0,
+                    Some("Demisemiquaver") => 2.0,
+                    Some(other) => {
+                        diagnostics.push(ParseDiagnostic::warning(
+                            line,
+                            DiagnosticCode::MalformedHeader,
+                            format!("Unknown resolution '{}', assuming Semiquaver", other),
+                        ));
+                        1.0
+                    }
+                };
+                genre = values
+                    .get("genre")
+                    .filter(|genre| !genre.is_empty())
+                    .cloned();
+                year = values.get("year").and_then(|year| year.parse().ok());
+            }
+            Event::Start(element) if element.local_name().as_ref() == b"TRACK" => {
+                let values = attributes(&element);
+                if let Some(name) = values
+                    .get("artist")
+                    .or_else(|| values.get("name"))
+                    .filter(|name| !name.is_empty())
+                {
+                    voice_names.insert(track_count, name.clone());
+                }
+                track = Some(track_count);
+                track_count += 1;
+                // Every track starts at the beginning of the song
+                beat = 0;
+            }
+            Event::End(element) if element.local_name().as_ref() == b"TRACK" => {
+                track = None;
+            }
+            Event::Start(element) if element.local_name().as_ref() == b"SENTENCE" => {
+                let singer_voices = match (track, attributes(&element).get("singer")) {
+                    (Some(track), _) => vec![track],
+                    (None, None) => vec![0],
+                    (None, Some(singer)) => singer_to_voices(singer).unwrap_or_else(|| {
+                        diagnostics.push(ParseDiagnostic::warning(
+                            line,
+                            DiagnosticCode::UnknownLine,
+                            format!("Unknown singer '{}', assigning to P1", singer),
+                        ));
+                        vec![0]
+                    }),
+                };
+                sentence = Some(Sentence {
+                    phrase: Phrase {
+                        disappear_beat: beat,
+                        next_start_beat: None,
+                        shared: singer_voices.len() > 1,
+                        notes: Vec::new(),
+                    },
+                    voices: singer_voices,
+                    joins_previous: true,
+                });
+            }
+            Event::End(element) if element.local_name().as_ref() == b"SENTENCE" => {
+                let Some(mut finished) = sentence.take() else {
+                    continue;
+                };
+                if finished.phrase.notes.is_empty() {
+                    continue;
+                }
+                finished.phrase.disappear_beat = beat;
+                for voice in finished.voices {
+                    if voices.len() <= voice {
+                        voices.resize_with(voice + 1, Vec::new);
+                    }
+                    voices[voice].push(finished.phrase.clone());
+                }
+            }
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"NOTE" =>
+            {
+                let values = attributes(&element);
+                let Some(duration) = values
+                    .get("duration")
+                    .and_then(|duration| duration.parse::<i32>().ok())
+                    .filter(|duration| *duration > 0)
+                else {
+                    diagnostics.push(ParseDiagnostic::warning(
+                        line,
+                        DiagnosticCode::InvalidNumber,
+                        "Note without a valid duration, skipping it",
+                    ));
+                    continue;
+                };
+                let midi_note = values
+                    .get("midinote")
+                    .and_then(|note| note.parse::<i32>().ok())
+                    .unwrap_or(0);
+
+                let start_beat = beat;
+                beat += duration;
+                // Rests only move the time forward
+                if midi_note <= 0 {
+                    continue;
+                }
+                let Some(sentence) = sentence.as_mut() else {
+                    diagnostics.push(ParseDiagnostic::warning(
+                        line,
+                        DiagnosticCode::IncompleteLine,
+                        "Note outside of a sentence, skipping it",
+                    ));
+                    continue;
+                };
+
+                // A trailing `-` joins the syllable with the next one, a note
+                // without a lyric holds the previous syllable
+                let mut text = values.get("lyric").cloned().unwrap_or_default();
+                let joins_next = text.ends_with('-');
+                if joins_next {
+                    text.pop();
+                }
+                if text.trim().is_empty() {
+                    text = "~".to_string();
+                } else {
+                    if !sentence.joins_previous {
+                        text.insert(0, ' ');
+                    }
+                    sentence.joins_previous = joins_next;
+                }
+
+                let note_type = match (
+                    is_yes(&values, "freestyle"),
+                    is_yes(&values, "rap"),
+                    is_yes(&values, "bonus"),
+                ) {
+                    (true, _, _) => NoteType::Freestyle,
+                    (false, true, true) => NoteType::RapGolden,
+                    (false, true, false) => NoteType::Rap,
+                    (false, false, true) => NoteType::Golden,
+                    (false, false, false) => NoteType::Normal,
+                };
+
+                sentence.phrase.notes.push(Note {
+                    note_type,
+                    start_beat,
+                    length: duration,
+                    text,
+                    txt_pitch: midi_note - 60,
+                    midi_note,
+                });
+            }
+            Event::Comment(comment) => {
+                let comment = comment.decode().unwrap_or_default();
+                if let Some((key, value)) = comment.split_once(':') {
+                    let value = value.trim().to_string();
+                    match key.trim().to_lowercase().as_str() {
+                        "title" if !value.is_empty() => title = Some(value),
+                        "artist" if !value.is_empty() => artist = Some(value),
+                        _ => {}
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if title.is_none() || artist.is_none() {
+        if let Some((folder_artist, folder_title)) = folder_name.split_once(" - ") {
+            artist.get_or_insert_with(|| folder_artist.trim().to_string());
+            title.get_or_insert_with(|| folder_title.trim().to_string());
+        }
+    }
+    let title = title.unwrap_or_default();
+    let artist = artist.unwrap_or_default();
+
+    let mut md5_context = md5::Context::new();
+    md5_context.consume(content.as_bytes());
+    md5_context.consume(title.as_bytes());
+    md5_context.consume(artist.as_bytes());
+
+    let song = Song {
+        title,
+        artist,
+        bpm: tempo.unwrap_or(0.0) * resolution,
+        tempo_changes: Vec::new(),
+        gap: 0.0,
+        video_gap: 0.0,
+        start: None,
+        end: None,
+        hash: format!("{:x}", md5_context.finalize()),
+        album: None,
+        language: None,
+        edition: None,
+        genre: genre.map(|genre| vec![genre]),
+        year,
+        creator: None,
+        relative: None,
+        audio: find_media(files, &AUDIO_STEMS, &AUDIO_EXTENSIONS),
+        instrumental: None,
+        cover: find_media(files, &["cover"], &IMAGE_EXTENSIONS),
+        video: find_media(files, &["video"], &VIDEO_EXTENSIONS),
+        background: find_media(files, &["background"], &IMAGE_EXTENSIONS),
+        p1: voice_names.get(&0).cloned(),
+        p2: voice_names.get(&1).cloned(),
+        preview_start: None,
+        version: Some("1.0.0".to_string()),
+        tags: None,
+        medley_start_beat: None,
+        medley_end_beat: None,
+        medley_start: None,
+        medley_end: None,
+        resolution: None,
+        notes_gap: None,
+        calc_medley: None,
+        vocals: find_media(files, &["vocals"], &AUDIO_EXTENSIONS),
+        comment: None,
+        provided_by: None,
+        encoding: None,
+        audio_web_url: None,
+        video_web_url: None,
+        cover_web_url: None,
+        background_web_url: None,
+        custom_tags: Vec::new(),
+        voices: voices
+            .into_iter()
+            .enumerate()
+            .filter(|(_, phrases)| !phrases.is_empty())
+            .map(|(index, phrases)| Voice {
+                name: voice_names.get(&index).cloned(),
+                phrases,
+            })
+            .collect(),
+    };
+
+    let missing = |code, message: &str| -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
+        Err(ParseDiagnostic::error(None, code, message).into())
+    };
+    if tempo.is_none() {
+        return missing(
+            DiagnosticCode::MissingBpm,
+            "Missing or invalid Tempo on MELODY",
+        );
+    }
+    if song.title.is_empty() {
+        return missing(DiagnosticCode::MissingTitle, "Missing title in notes.xml");
+    }
+    if song.artist.is_empty() {
+        return missing(DiagnosticCode::MissingArtist, "Missing artist in notes.xml");
+    }
+    if song.voices.is_empty() {
+        return missing(DiagnosticCode::IncompleteLine, "notes.xml has no notes");
+    }
+    if song.audio.is_none() && song.video.is_none() {
+        return missing(
+            DiagnosticCode::MissingMedia,
+            "Song folder has no music or video",
+        );
+    }
+
+    Ok((song, diagnostics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEMIQUAVER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Title: Comment Title -->
+<!-- Artist: Comment Artist -->
+<MELODY xmlns="http://www.singstargame.com" Version="1" Tempo="120" Resolution="Semiquaver" Genre="Pop" Year="1999">
+  <SENTENCE>
+    <NOTE MidiNote="0" Duration="4" Lyric=""/>
+    <NOTE MidiNote="60" Duration="2" Lyric="Hel-"/>
+    <NOTE MidiNote="62" Duration="2" Lyric="lo"/>
+    <NOTE MidiNote="64" Duration="2" Lyric="world"/>
+  </SENTENCE>
+</MELODY>"#;
+
+    const TRACKS: &str = r#"<MELODY Tempo="100">
+  <TRACK Name="Player1" Artist="Alice">
+    <SENTENCE>
+      <NOTE MidiNote="60" Duration="4" Lyric="one"/>
+    </SENTENCE>
+  </TRACK>
+  <TRACK Name="Player2" Artist="Bob">
+    <SENTENCE>
+      <NOTE MidiNote="0" Duration="2" Lyric=""/>
+      <NOTE MidiNote="67" Duration="4" Lyric="two"/>
+    </SENTENCE>
+  </TRACK>
+</MELODY>"#;
+
+    const SINGERS: &str = r#"<MELODY Tempo="100">
+  <SENTENCE Singer="Solo 1">
+    <NOTE MidiNote="60" Duration="4" Lyric="first"/>
+  </SENTENCE>
+  <SENTENCE Singer="Solo 2">
+    <NOTE MidiNote="62" Duration="4" Lyric="second"/>
+  </SENTENCE>
+  <SENTENCE Singer="Group">
+    <NOTE MidiNote="64" Duration="4" Lyric="both"/>
+  </SENTENCE>
+</MELODY>"#;
+
+    fn parse(content: &str, folder_name: &str) -> Song {
+        parse_melody_xml(content, &["music.mp3".to_string()], folder_name)
+            .unwrap()
+            .0
+    }
+
+    fn texts(voice: &Voice) -> Vec<Vec<&str>> {
+        voice
+            .phrases
+            .iter()
+            .map(|phrase| phrase.notes.iter().map(|note| note.text.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn scales_the_tempo_by_the_resolution() {
+        let song = parse(SEMIQUAVER, "Folder");
+        assert_eq!(song.bpm, 120.0);
+
+        let demisemiquaver = SEMIQUAVER.replace("Semiquaver", "Demisemiquaver");
+        let song = parse(&demisemiquaver, "Folder");
+        assert_eq!(song.bpm, 240.0);
+    }
+
+    #[test]
+    fn reads_notes_after_rests() {
+        let song = parse(SEMIQUAVER, "Folder");
+        assert_eq!(song.genre, Some(vec!["Pop".to_string()]));
+        assert_eq!(song.year, Some(1999));
+        assert_eq!(song.audio.as_deref(), Some("music.mp3"));
+        assert_eq!(song.voices.len(), 1);
+
+        let notes = &song.voices[0].phrases[0].notes;
+        let starts: Vec<_> = notes.iter().map(|note| note.start_beat).collect();
+        assert_eq!(starts, [4, 6, 8]);
+        assert_eq!(notes[0].txt_pitch, 0);
+        assert_eq!(notes[2].midi_note, 64);
+        assert_eq!(texts(&song.voices[0]), [["Hel", "lo", " world"]]);
+        assert_eq!(song.voices[0].phrases[0].disappear_beat, 10);
+    }
+
+    #[test]
+    fn reads_one_voice_per_track() {
+        let song = parse(TRACKS, "Folder - Name");
+        assert_eq!(song.voices.len(), 2);
+        assert_eq!(song.p1.as_deref(), Some("Alice"));
+        assert_eq!(song.p2.as_deref(), Some("Bob"));
+        assert_eq!(song.voices[1].name.as_deref(), Some("Bob"));
+        assert_eq!(texts(&song.voices[0]), [["one"]]);
+        assert_eq!(texts(&song.voices[1]), [["two"]]);
+        // Every track starts at the beginning of the song
+        assert_eq!(song.voices[1].phrases[0].notes[0].start_beat, 2);
+    }
+
+    #[test]
+    fn assigns_sentences_to_singers() {
+        let song = parse(SINGERS, "Folder - Name");
+        assert_eq!(song.voices.len(), 2);
+        assert_eq!(texts(&song.voices[0]), [["first"], ["both"]]);
+        assert_eq!(texts(&song.voices[1]), [["second"], ["both"]]);
+
+        let shared: Vec<_> = song.voices[0]
+            .phrases
+            .iter()
+            .map(|phrase| phrase.shared)
+            .collect();
+        assert_eq!(shared, [false, true]);
+        assert!(song.voices[1].phrases[1].shared);
+        assert_eq!(song.voices[1].phrases[1].notes[0].start_beat, 8);
+    }
+
+    #[test]
+    fn takes_title_and_artist_from_comments_before_the_folder() {
+        let song = parse(SEMIQUAVER, "Folder Artist - Folder Title");
+        assert_eq!(song.title, "Comment Title");
+        assert_eq!(song.artist, "Comment Artist");
+
+        let without_title = SEMIQUAVER.replace("<!-- Title: Comment Title -->", "");
+        let song = parse(&without_title, "Folder Artist - Folder Title");
+        assert_eq!(song.title, "Folder Title");
+        assert_eq!(song.artist, "Comment Artist");
+
+        let song = parse(TRACKS, "Folder Artist - Folder Title");
+        assert_eq!(song.title, "Folder Title");
+        assert_eq!(song.artist, "Folder Artist");
+    }
+
+    #[test]
+    fn requires_a_title_and_artist() {
+        let result = parse_melody_xml(TRACKS, &["music.mp3".to_string()], "Folder");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn requires_a_tempo() {
+        let without_tempo = TRACKS.replace(r#" Tempo="100""#, "");
+        let result = parse_melody_xml(&without_tempo, &["music.mp3".to_string()], "A - B");
+        assert!(result.is_err());
+    }
+}
@@ -1,13 +1,23 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use lofty::{
-    file::FileType,
-    file::TaggedFileExt,
+    file::{AudioFile, FileType, TaggedFile, TaggedFileExt},
+    picture::{Picture, PictureType},
     probe::Probe,
-    tag::{ItemKey, Tag, TagType},
+    tag::{Accessor, ItemKey, Tag, TagType},
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use specta::Type;
 
 use crate::error::AppError;
 
+const COVER_CACHE_DIR: &str = "covers";
+static TEMPORARY_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug, Serialize, specta::Type)]
 pub struct ReplayGainInfo {
     pub track_gain: Option<f32>,
@@ -31,38 +41,182 @@ fn parse_opus_r128_gain(value: Option<&str>) -> Option<f32> {
     })
 }
 
-pub fn get_replay_gain(path: &str) -> Result<ReplayGainInfo, AppError> {
+/// Stream properties of an audio or video file.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaInfo {
+    pub duration_ms: f64,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    /// The container format of the file, e.g. `Mpeg`, `Opus`, `Flac` or
+    /// `Mp4`. This is not the codec, an `Mp4` file can hold AAC or ALAC.
+    pub container: String,
+}
+
+fn media_info(file: &TaggedFile) -> MediaInfo {
+    let properties = file.properties();
+    MediaInfo {
+        duration_ms: properties.duration().as_secs_f64() * 1000.0,
+        sample_rate: properties.sample_rate(),
+        channels: properties.channels(),
+        container: format!("{:?}", file.file_type()),
+    }
+}
+
+/// Reads the stream properties of a file. Only formats lofty knows can be
+/// probed, which for videos means MP4.
+pub fn probe_media(path: &str) -> Result<MediaInfo, AppError> {
     let file = Probe::open(path)?.read()?;
+    Ok(media_info(&file))
+}
 
-    let tag = match file.primary_tag().or_else(|| file.first_tag()) {
-        Some(tag) => tag,
-        None => {
-            return Ok(ReplayGainInfo {
-                track_gain: None,
-                track_peak: None,
-                album_gain: None,
-                album_peak: None,
-            })
-        }
-    };
+/// A picture from the tags of an audio file, like an ID3 `APIC` frame, a
+/// FLAC picture block or the MP4 `covr` atom.
+#[derive(Debug)]
+pub struct EmbeddedPicture {
+    pub data: Vec<u8>,
+    pub extension: &'static str,
+}
 
-    if file.file_type() == FileType::Opus {
-        return Ok(ReplayGainInfo {
+/// What the library takes from the tags of a song's audio file.
+#[derive(Debug)]
+pub struct AudioTags {
+    pub info: MediaInfo,
+    pub replay_gain: ReplayGainInfo,
+    pub album: Option<String>,
+    pub year: Option<i32>,
+    pub genre: Option<Vec<String>>,
+    /// Only read if asked for, since the picture can be large.
+    pub cover: Option<EmbeddedPicture>,
+}
+
+fn get_replay_gain(file_type: FileType, tag: &Tag) -> ReplayGainInfo {
+    if file_type == FileType::Opus {
+        return ReplayGainInfo {
             track_gain: parse_opus_r128_gain(get_custom_string(tag, "R128_TRACK_GAIN")),
             track_peak: None,
             album_gain: parse_opus_r128_gain(get_custom_string(tag, "R128_ALBUM_GAIN")),
             album_peak: None,
-        });
+        };
     }
 
-    Ok(ReplayGainInfo {
+    ReplayGainInfo {
         track_gain: parse_replay_gain(tag.get_string(ItemKey::ReplayGainTrackGain)),
         track_peak: parse_replay_gain(tag.get_string(ItemKey::ReplayGainTrackPeak)),
         album_gain: parse_replay_gain(tag.get_string(ItemKey::ReplayGainAlbumGain)),
         album_peak: parse_replay_gain(tag.get_string(ItemKey::ReplayGainAlbumPeak)),
+    }
+}
+
+/// The extension for a picture's format, from its MIME type or, if that is
+/// missing, from the first bytes. Formats a browser cannot show are skipped.
+fn picture_extension(picture: &Picture) -> Option<&'static str> {
+    match picture.mime_type().and_then(|mime_type| mime_type.ext()) {
+        Some("jpg") => Some("jpg"),
+        Some("png") => Some("png"),
+        Some("gif") => Some("gif"),
+        Some("bmp") => Some("bmp"),
+        Some(_) => None,
+        None if picture.data().starts_with(b"\x89PNG") => Some("png"),
+        None if picture.data().starts_with(b"\xFF\xD8") => Some("jpg"),
+        None => None,
+    }
+}
+
+/// The front cover, or the first picture if none is marked as one.
+fn find_cover(tag: &Tag) -> Option<EmbeddedPicture> {
+    let pictures = tag.pictures();
+    let picture = pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or_else(|| pictures.first())?;
+
+    Some(EmbeddedPicture {
+        extension: picture_extension(picture)?,
+        data: picture.data().to_vec(),
     })
 }
 
+pub fn read_audio_tags(path: &str, read_cover: bool) -> Result<AudioTags, AppError> {
+    let file = Probe::open(path)?.read()?;
+
+    let info = media_info(&file);
+
+    let Some(tag) = file.primary_tag().or_else(|| file.first_tag()) else {
+        return Ok(AudioTags {
+            info,
+            replay_gain: ReplayGainInfo {
+                track_gain: None,
+                track_peak: None,
+                album_gain: None,
+                album_peak: None,
+            },
+            album: None,
+            year: None,
+            genre: None,
+            cover: None,
+        });
+    };
+
+    let genre: Vec<String> = tag
+        .genre()
+        .map(|genre| {
+            genre
+                .split([',', ';', '/'])
+                .map(str::trim)
+                .filter(|genre| !genre.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(AudioTags {
+        info,
+        replay_gain: get_replay_gain(file.file_type(), tag),
+        album: tag
+            .album()
+            .map(|album| album.trim().to_string())
+            .filter(|album| !album.is_empty()),
+        year: tag.date().map(|date| date.year as i32),
+        genre: (!genre.is_empty()).then_some(genre),
+        cover: if read_cover { find_cover(tag) } else { None },
+    })
+}
+
+pub fn embedded_cover_dir(cache_dir: &Path) -> PathBuf {
+    cache_dir.join(COVER_CACHE_DIR)
+}
+
+/// Stores an embedded cover in the cache directory under the hash of its
+/// data, so songs sharing an album cover share the file.
+pub fn cache_embedded_cover(
+    cache_dir: &Path,
+    picture: &EmbeddedPicture,
+) -> Result<PathBuf, AppError> {
+    let dir = embedded_cover_dir(cache_dir);
+    let path = dir.join(format!(
+        "{:x}.{}",
+        md5::compute(&picture.data),
+        picture.extension
+    ));
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&dir)?;
+    // Parsing workers can cache the same cover at the same time, so each
+    // writes its own temporary file and the last rename wins
+    let temporary_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        picture.extension,
+        TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temporary_path, &picture.data)?;
+    fs::rename(&temporary_path, &path)?;
+
+    Ok(path)
+}
+
 /// Reads a non-standard tag value (e.g. Opus R128 gain) by its raw Vorbis
 /// comment key, which lofty does not expose as a typed [`ItemKey`].
 fn get_custom_string<'a>(tag: &'a Tag, key: &str) -> Option<&'a str> {
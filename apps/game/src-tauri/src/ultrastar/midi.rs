@@ -0,0 +1,530 @@
+use crate::{
+    error::AppError,
+    ultrastar::song::{NoteType, Song},
+};
+
+/// Ticks per quarter note. An UltraStar beat is a sixteenth note, so it is a
+/// quarter of this.
+const TICKS_PER_QUARTER: u32 = 480;
+const TICKS_PER_BEAT: i64 = TICKS_PER_QUARTER as i64 / 4;
+/// Largest tempo a set tempo event can hold, in microseconds per quarter note.
+const MAX_TEMPO: f64 = 0xFF_FFFF as f64;
+/// Rock Band's phrase marker note, so exported files can be imported again.
+const PHRASE_MARKER_NOTE: u8 = 105;
+
+struct TrackEvent {
+    tick: i64,
+    /// Events on the same tick are written in this order, note offs first so
+    /// repeated notes of the same pitch do not cut each other off.
+    order: u8,
+    data: Vec<u8>,
+}
+
+fn push_variable_length(output: &mut Vec<u8>, value: u32) {
+    let mut buffer = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        buffer.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    output.extend(buffer.iter().rev());
+}
+
+fn meta_event(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut event = vec![0xFF, kind];
+    push_variable_length(&mut event, data.len() as u32);
+    event.extend_from_slice(data);
+    event
+}
+
+fn tempo_event(microseconds_per_quarter: f64) -> Vec<u8> {
+    let tempo = microseconds_per_quarter.round().clamp(1.0, MAX_TEMPO) as u32;
+    meta_event(0x51, &tempo.to_be_bytes()[1..])
+}
+
+fn write_track(output: &mut Vec<u8>, mut events: Vec<TrackEvent>) {
+    events.sort_by_key(|event| (event.tick, event.order));
+
+    let mut data = Vec::new();
+    let mut last_tick = 0;
+    for event in events {
+        let tick = event.tick.max(last_tick);
+        push_variable_length(&mut data, (tick - last_tick) as u32);
+        data.extend(event.data);
+        last_tick = tick;
+    }
+    push_variable_length(&mut data, 0);
+    data.extend(meta_event(0x2F, &[]));
+
+    output.extend_from_slice(b"MTrk");
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend(data);
+}
+
+/// Velocity that tells the note types apart in a piano roll.
+fn note_velocity(note_type: &NoteType) -> u8 {
+    match note_type {
+        NoteType::Normal => 96,
+        NoteType::Golden => 127,
+        NoteType::Rap => 64,
+        NoteType::RapGolden => 80,
+        NoteType::Freestyle => 32,
+    }
+}
+
+fn note_type_marker(note_type: &NoteType) -> Option<&'static str> {
+    match note_type {
+        NoteType::Normal => None,
+        NoteType::Golden => Some("golden"),
+        NoteType::Rap => Some("rap"),
+        NoteType::RapGolden => Some("rap golden"),
+        NoteType::Freestyle => Some("freestyle"),
+    }
+}
+
+/// Converts a song into a format 1 Standard MIDI File with a tempo track and
+/// one track per voice.
+///
+/// `#GAP` becomes a lead-in of whole bars with its own tempo, so beat 0 of
+/// the song starts on a bar line and the notes stay on the grid. Every note
+/// gets a lyric event with its syllable, its type is encoded in the velocity
+/// and in a text event for anything but normal notes. Phrases are marked with
+/// note 105 like in Rock Band charts.
+pub fn write_midi(song: &Song) -> Result<Vec<u8>, AppError> {
+    if song.bpm <= 0.0 || song.tempo_changes.iter().any(|change| change.bpm <= 0.0) {
+        return Err(AppError::UltrastarError(
+            "Song needs a positive BPM to export MIDI".to_string(),
+        ));
+    }
+
+    let gap_microseconds = song.gap.max(0.0) * 1000.0;
+    let lead_in_bars = (gap_microseconds / (MAX_TEMPO * 4.0)).ceil() as i64;
+    let lead_in_ticks = lead_in_bars * 4 * TICKS_PER_QUARTER as i64;
+    let beat_to_tick = |beat: i32| lead_in_ticks + beat as i64 * TICKS_PER_BEAT;
+
+    let mut tempo_track = vec![
+        TrackEvent {
+            tick: 0,
+            order: 0,
+            data: meta_event(0x03, song.title.as_bytes()),
+        },
+        TrackEvent {
+            tick: 0,
+            order: 0,
+            data: meta_event(0x58, &[4, 2, 24, 8]),
+        },
+    ];
+    if lead_in_bars > 0 {
+        tempo_track.push(TrackEvent {
+            tick: 0,
+            order: 1,
+            data: tempo_event(gap_microseconds / (lead_in_bars * 4) as f64),
+        });
+    }
+    tempo_track.push(TrackEvent {
+        tick: lead_in_ticks,
+        order: 1,
+        data: tempo_event(60_000_000.0 / song.bpm),
+    });
+    for change in &song.tempo_changes {
+        tempo_track.push(TrackEvent {
+            tick: beat_to_tick(change.beat),
+            order: 1,
+            data: tempo_event(60_000_000.0 / change.bpm),
+        });
+    }
+
+    let mut tracks = vec![tempo_track];
+    for (index, voice) in song.voices.iter().enumerate() {
+        let channel = (index % 16) as u8;
+        let name = voice
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("P{}", index + 1));
+        let mut events = vec![TrackEvent {
+            tick: 0,
+            order: 0,
+            data: meta_event(0x03, name.as_bytes()),
+        }];
+
+        for (index, phrase) in voice.phrases.iter().enumerate() {
+            if let Some(first_note) = phrase.notes.first() {
+                let start = beat_to_tick(first_note.start_beat);
+                // Line breaks may lie after the start of the next line, but
+                // phrase markers must not overlap
+                let next_start = voice.phrases[index + 1..]
+                    .iter()
+                    .find_map(|phrase| phrase.notes.first())
+                    .map(|note| beat_to_tick(note.start_beat));
+                let mut end = beat_to_tick(phrase.disappear_beat);
+                if let Some(next_start) = next_start {
+                    end = end.min(next_start);
+                }
+                events.push(TrackEvent {
+                    tick: start,
+                    order: 2,
+                    data: vec![0x90 | channel, PHRASE_MARKER_NOTE, 100],
+                });
+                events.push(TrackEvent {
+                    tick: end.max(start + 1),
+                    order: 1,
+                    data: vec![0x80 | channel, PHRASE_MARKER_NOTE, 0],
+                });
+            }
+
+            for note in &phrase.notes {
+                let start = beat_to_tick(note.start_beat);
+                // Note-offs sort before note-ons on the same tick, so even
+                // notes without a length have to last one tick
+                let end = beat_to_tick(note.start_beat + note.length).max(start + 1);
+                let pitch = note.midi_note.clamp(0, 127) as u8;
+
+                if let Some(marker) = note_type_marker(&note.note_type) {
+                    events.push(TrackEvent {
+                        tick: start,
+                        order: 2,
+                        data: meta_event(0x01, marker.as_bytes()),
+                    });
+                }
+                events.push(TrackEvent {
+                    tick: start,
+                    order: 2,
+                    data: meta_event(0x05, note.text.as_bytes()),
+                });
+                events.push(TrackEvent {
+                    tick: start,
+                    order: 3,
+                    data: vec![0x90 | channel, pitch, note_velocity(&note.note_type)],
+                });
+                events.push(TrackEvent {
+                    tick: end,
+                    order: 1,
+                    data: vec![0x80 | channel, pitch, 0],
+                });
+            }
+        }
+
+        tracks.push(events);
+    }
+
+    let mut output = Vec::new();
+    output.extend_from_slice(b"MThd");
+    output.extend_from_slice(&6u32.to_be_bytes());
+    output.extend_from_slice(&1u16.to_be_bytes());
+    output.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    output.extend_from_slice(&(TICKS_PER_QUARTER as u16).to_be_bytes());
+    for events in tracks {
+        write_track(&mut output, events);
+    }
+
+    Ok(output)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiEventKind {
+    NoteOn {
+        key: u8,
+    },
+    NoteOff {
+        key: u8,
+    },
+    Meta {
+        kind: u8,
+        data: Vec<u8>,
+    },
+    /// Controller, program, sysex and other events the importers do not need.
+    Other,
+}
+
+/// An event with its absolute position in ticks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiEvent {
+    pub tick: u32,
+    pub kind: MidiEventKind,
+}
+
+impl MidiEvent {
+    /// Text of a meta event, `kind` 0x03 is the track name and 0x05 a lyric.
+    pub fn meta_text(&self, meta_kind: u8) -> Option<String> {
+        match &self.kind {
+            MidiEventKind::Meta { kind, data } if *kind == meta_kind => {
+                Some(String::from_utf8_lossy(data).into_owned())
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct MidiFile {
+    pub ticks_per_quarter: u16,
+    pub tracks: Vec<Vec<MidiEvent>>,
+}
+
+struct MidiReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> MidiReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], AppError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| AppError::UltrastarError("Unexpected end of MIDI data".to_string()))?;
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, AppError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, AppError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn variable_length(&mut self) -> Result<u32, AppError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(AppError::UltrastarError(
+            "Invalid variable length value in MIDI data".to_string(),
+        ))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+}
+
+fn read_track(data: &[u8]) -> Result<Vec<MidiEvent>, AppError> {
+    let mut reader = MidiReader {
+        bytes: data,
+        position: 0,
+    };
+    let mut events = Vec::new();
+    let mut tick = 0u32;
+    let mut running_status = None;
+
+    while !reader.is_empty() {
+        tick = tick.saturating_add(reader.variable_length()?);
+        let mut status = reader.byte()?;
+
+        let kind = match status {
+            0xFF => {
+                let kind = reader.byte()?;
+                let length = reader.variable_length()? as usize;
+                let data = reader.take(length)?.to_vec();
+                if kind == 0x2F {
+                    break;
+                }
+                MidiEventKind::Meta { kind, data }
+            }
+            0xF0 | 0xF7 => {
+                let length = reader.variable_length()? as usize;
+                reader.take(length)?;
+                MidiEventKind::Other
+            }
+            _ => {
+                // Running status repeats the previous status byte, the byte just
+                // read is already the first data byte
+                let first_data = if status & 0x80 == 0 {
+                    let data = status;
+                    status = running_status.ok_or_else(|| {
+                        AppError::UltrastarError("MIDI data without status byte".to_string())
+                    })?;
+                    data
+                } else {
+                    running_status = Some(status);
+                    reader.byte()?
+                };
+
+                match status & 0xF0 {
+                    0x80 => {
+                        reader.byte()?;
+                        MidiEventKind::NoteOff { key: first_data }
+                    }
+                    // A note on with velocity 0 is a note off
+                    0x90 => match reader.byte()? {
+                        0 => MidiEventKind::NoteOff { key: first_data },
+                        _ => MidiEventKind::NoteOn { key: first_data },
+                    },
+                    0xC0 | 0xD0 => MidiEventKind::Other,
+                    _ => {
+                        reader.byte()?;
+                        MidiEventKind::Other
+                    }
+                }
+            }
+        };
+
+        events.push(MidiEvent { tick, kind });
+    }
+
+    Ok(events)
+}
+
+/// Reads a Standard MIDI File. Only files with a ticks per quarter note
+/// division are supported, which is what every charting tool writes.
+pub fn read_midi(bytes: &[u8]) -> Result<MidiFile, AppError> {
+    let mut reader = MidiReader { bytes, position: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(AppError::UltrastarError("Not a MIDI file".to_string()));
+    }
+    let header_length = reader.u32()? as usize;
+    let header = reader.take(header_length)?;
+    if header.len() < 6 {
+        return Err(AppError::UltrastarError("Invalid MIDI header".to_string()));
+    }
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if division & 0x8000 != 0 || division == 0 {
+        return Err(AppError::UltrastarError(
+            "MIDI files with SMPTE timing are not supported".to_string(),
+        ));
+    }
+
+    let mut tracks = Vec::new();
+    while !reader.is_empty() {
+        let chunk_type = reader.take(4)?;
+        let length = reader.u32()? as usize;
+        let data = reader.take(length)?;
+        if chunk_type == b"MTrk" {
+            tracks.push(read_track(data)?);
+        }
+    }
+
+    Ok(MidiFile {
+        ticks_per_quarter: division,
+        tracks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ultrastar::parser::parse_ultrastar_txt;
+
+    /// One lead-in bar of 1920 ticks for the gap, then 120 ticks per beat.
+    fn song(gap: &str) -> Song {
+        let content = format!(
+            "#TITLE:Title\n#ARTIST:Artist\n#MP3:song.mp3\n#BPM:300\n#GAP:{}\n\
+             : 0 2 0 One\n* 2 2 2 two\n- 6\n\
+             B 8 150\nR 8 2 4 Three\nG 10 2 5 four\nF 12 2 7 five\nE\n",
+            gap
+        );
+        parse_ultrastar_txt(&content).unwrap().0
+    }
+
+    fn tempos(file: &MidiFile) -> Vec<(u32, u32)> {
+        file.tracks[0]
+            .iter()
+            .filter_map(|event| match &event.kind {
+                MidiEventKind::Meta { kind: 0x51, data } => Some((
+                    event.tick,
+                    u32::from_be_bytes([0, data[0], data[1], data[2]]),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn texts(events: &[MidiEvent], meta_kind: u8) -> Vec<(u32, String)> {
+        events
+            .iter()
+            .filter_map(|event| Some((event.tick, event.meta_text(meta_kind)?)))
+            .collect()
+    }
+
+    fn notes(events: &[MidiEvent], key: u8) -> Vec<(u32, bool)> {
+        events
+            .iter()
+            .filter_map(|event| match event.kind {
+                MidiEventKind::NoteOn { key: k } if k == key => Some((event.tick, true)),
+                MidiEventKind::NoteOff { key: k } if k == key => Some((event.tick, false)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_the_gap_as_lead_in_bars() {
+        let file = read_midi(&write_midi(&song("1000")).unwrap()).unwrap();
+        assert_eq!(file.ticks_per_quarter, 480);
+        assert_eq!(file.tracks.len(), 2);
+        // 1000 ms over four quarters, then 300 and 150 BPM
+        assert_eq!(
+            tempos(&file),
+            vec![(0, 250_000), (1920, 200_000), (2880, 400_000)]
+        );
+
+        let file = read_midi(&write_midi(&song("0")).unwrap()).unwrap();
+        assert_eq!(tempos(&file), vec![(0, 200_000), (960, 400_000)]);
+
+        // A set tempo event holds at most about 16.7 s per quarter
+        let file = read_midi(&write_midi(&song("100000")).unwrap()).unwrap();
+        assert_eq!(tempos(&file)[..2], [(0, 12_500_000), (3840, 200_000)]);
+    }
+
+    #[test]
+    fn writes_notes_lyrics_and_phrase_markers() {
+        let file = read_midi(&write_midi(&song("1000")).unwrap()).unwrap();
+        let track = &file.tracks[1];
+        assert_eq!(texts(track, 0x03), vec![(0, "P1".to_string())]);
+        assert_eq!(
+            texts(track, 0x05),
+            vec![
+                (1920, "One".to_string()),
+                (2160, "two".to_string()),
+                (2880, "Three".to_string()),
+                (3120, "four".to_string()),
+                (3360, "five".to_string()),
+            ]
+        );
+        assert_eq!(notes(track, 60), vec![(1920, true), (2160, false)]);
+        assert_eq!(notes(track, 67), vec![(3360, true), (3600, false)]);
+        // The first phrase ends at its line break, the last one after its last note
+        assert_eq!(
+            notes(track, PHRASE_MARKER_NOTE),
+            vec![(1920, true), (2640, false), (2880, true), (3720, false)]
+        );
+    }
+
+    #[test]
+    fn encodes_note_types_in_velocities_and_text_markers() {
+        let midi = write_midi(&song("1000")).unwrap();
+        for (key, velocity) in [(60, 96), (62, 127), (64, 64), (65, 80), (67, 32)] {
+            assert!(
+                midi.windows(3).any(|bytes| bytes == [0x90, key, velocity]),
+                "note {} with velocity {}",
+                key,
+                velocity
+            );
+        }
+
+        let file = read_midi(&midi).unwrap();
+        assert_eq!(
+            texts(&file.tracks[1], 0x01),
+            vec![
+                (2160, "golden".to_string()),
+                (2880, "rap".to_string()),
+                (3120, "rap golden".to_string()),
+                (3360, "freestyle".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_non_positive_bpm() {
+        let mut song = song("0");
+        song.bpm = 0.0;
+        assert!(write_midi(&song).is_err());
+    }
+}
@@ -1,4 +1,19 @@
+pub mod archive;
+pub mod cdg;
+pub mod diagnostics;
+pub mod encoding;
 pub mod filesystem;
+pub mod library_index;
+pub mod lint;
+pub mod loudness;
+pub mod lyrics;
+pub mod media;
+pub mod melody;
 pub mod meta;
+pub mod midi;
 pub mod parser;
+pub mod rockband;
 pub mod song;
+pub mod thumbnail;
+pub mod timeline;
+pub mod writer;
@@ -1,40 +1,97 @@
-use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
 use semver::Version;
-use std::fs;
-use unicode_normalization::UnicodeNormalization;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::{
     error::AppError,
     ultrastar::{
+        archive::{canonicalize_media_path, read_media_file, split_archive_path},
+        cdg::{is_cdg_file, parse_cdg_song},
+        diagnostics::{DiagnosticCode, ParseDiagnostic},
+        encoding::{read_txt_file, DecodedTxt, EncodingConfidence},
         filesystem::FileEntry,
-        meta::get_replay_gain,
-        song::{LocalSong, Note, NoteType, Phrase, Song, Voice},
+        loudness::LoudnessCache,
+        media::{infer_media_file, resolve_media_file, MediaTag},
+        melody::{is_melody_chart, parse_melody_xml},
+        meta::{cache_embedded_cover, probe_media, read_audio_tags, MediaInfo, ReplayGainInfo},
+        rockband::{is_rockband_chart, parse_rockband_song},
+        song::{LocalSong, Note, NoteType, Phrase, Song, TempoChange, Voice},
+        thumbnail::ThumbnailUrls,
     },
 };
 
-fn tag_to_note_type(tag: &str) -> NoteType {
+fn tag_to_note_type(tag: &str) -> Option<NoteType> {
     match tag {
-        ":" => NoteType::Normal,
-        "*" => NoteType::Golden,
-        "F" => NoteType::Freestyle,
-        "R" => NoteType::Rap,
-        "G" => NoteType::RapGolden,
-        _ => NoteType::Freestyle,
+        ":" => Some(NoteType::Normal),
+        "*" => Some(NoteType::Golden),
+        "F" => Some(NoteType::Freestyle),
+        "R" => Some(NoteType::Rap),
+        "G" => Some(NoteType::RapGolden),
+        _ => None,
+    }
+}
+
+/// Upper bound for voice indices, so a bogus `P` line cannot allocate huge amounts of memory.
+const MAX_VOICES: usize = 64;
+
+/// Reads the player number of a `#P<n>` or `#DUETSINGERP<n>` header.
+fn parse_singer_header(property: &str) -> Option<usize> {
+    let number = property
+        .strip_prefix("duetsingerp")
+        .or_else(|| property.strip_prefix('p'))?;
+    number.parse::<usize>().ok().filter(|number| *number >= 1)
+}
+
+/// Maps the player number of a `P<n>` line to voice indices. `P3` is sung by
+/// both singers of a duet, unless the song names a third singer with `#P3`.
+fn player_to_voices(player: usize, has_third_singer: bool) -> Vec<usize> {
+    if player == 3 && !has_third_singer {
+        vec![0, 1]
+    } else {
+        vec![player - 1]
     }
 }
 
-fn parse_us_int(value: &str, property: &str) -> Result<i32, AppError> {
+/// Closes the current line at `last note end + 1`, which is where the next
+/// line break would usually be.
+fn close_implicit_phrase(
+    notes: &mut Vec<Note>,
+    voice_phrases: &mut [Vec<Phrase>],
+    targets: &[usize],
+) {
+    if let Some(last_note) = notes.last() {
+        let phrase = Phrase {
+            disappear_beat: last_note.start_beat + last_note.length + 1,
+            next_start_beat: None,
+            shared: targets.len() > 1,
+            notes: std::mem::take(notes),
+        };
+        for &target in targets {
+            voice_phrases[target].push(phrase.clone());
+        }
+    }
+}
+
+fn parse_us_int(value: &str, property: &str, line: u32) -> Result<i32, ParseDiagnostic> {
     value.replace(",", ".").parse::<i32>().map_err(|_| {
-        AppError::UltrastarError(format!(
-            "Failed to parse integer for {}: {}",
-            property, value
-        ))
+        ParseDiagnostic::error(
+            Some(line),
+            DiagnosticCode::InvalidNumber,
+            format!("Failed to parse integer for {}: {}", property, value),
+        )
     })
 }
 
-fn parse_us_float(value: &str, property: &str) -> Result<f64, AppError> {
+fn parse_us_float(value: &str, property: &str, line: u32) -> Result<f64, ParseDiagnostic> {
     value.replace(",", ".").parse::<f64>().map_err(|_| {
-        AppError::UltrastarError(format!("Failed to parse float for {}: {}", property, value))
+        ParseDiagnostic::error(
+            Some(line),
+            DiagnosticCode::InvalidNumber,
+            format!("Failed to parse float for {}: {}", property, value),
+        )
     })
 }
 
@@ -42,11 +99,16 @@ fn parse_us_bool(value: &str) -> bool {
     matches!(value.to_lowercase().as_str(), "yes" | "true" | "1")
 }
 
-fn parse_version(value: &str) -> Result<String, AppError> {
+fn parse_version(value: &str, line: u32) -> Result<String, ParseDiagnostic> {
     let version_str = value.trim().trim_start_matches('v').trim_start_matches('V');
     // Validate it's a valid semver version
-    Version::parse(version_str)
-        .map_err(|_| AppError::UltrastarError(format!("Invalid version format: {}", value)))?;
+    Version::parse(version_str).map_err(|_| {
+        ParseDiagnostic::error(
+            Some(line),
+            DiagnosticCode::InvalidVersion,
+            format!("Invalid version format: {}", value),
+        )
+    })?;
     Ok(version_str.to_string())
 }
 
@@ -75,8 +137,13 @@ fn parse_multi_value_field(value: &str, supports_multi_value: bool) -> Option<Ve
     }
 }
 
-fn parse_time_value(value: &str, property: &str, uses_milliseconds: bool) -> Result<f64, AppError> {
-    let parsed = parse_us_float(value, property)?;
+fn parse_time_value(
+    value: &str,
+    property: &str,
+    uses_milliseconds: bool,
+    line: u32,
+) -> Result<f64, ParseDiagnostic> {
+    let parsed = parse_us_float(value, property, line)?;
     // Convert seconds to milliseconds for versions < 2.0.0
     Ok(if uses_milliseconds {
         parsed
@@ -85,13 +152,49 @@ fn parse_time_value(value: &str, property: &str, uses_milliseconds: bool) -> Res
     })
 }
 
-pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
+/// Hashes the parsed notes, line breaks, tempo changes and voices rather than
+/// the text, so the hash only changes with the song and not with the way it
+/// is written. Songs with one voice hash no voice numbers, since they need no
+/// `P` lines.
+fn song_hash(song: &Song) -> String {
+    let mut md5_context = md5::Context::new();
+    for (index, voice) in song.voices.iter().enumerate() {
+        if song.voices.len() > 1 {
+            md5_context.consume(format!("P{}", index + 1));
+        }
+        for phrase in &voice.phrases {
+            for note in &phrase.notes {
+                md5_context.consume(format!(
+                    "{:?} {} {} {}",
+                    note.note_type, note.start_beat, note.length, note.txt_pitch
+                ));
+            }
+            md5_context.consume(format!(
+                "- {} {:?}",
+                phrase.disappear_beat, phrase.next_start_beat
+            ));
+        }
+    }
+    for change in &song.tempo_changes {
+        md5_context.consume(format!("B {} {}", change.beat, change.bpm));
+    }
+    md5_context.consume(song.title.as_bytes());
+    md5_context.consume(song.artist.as_bytes());
+
+    format!("{:x}", md5_context.finalize())
+}
+
+/// Parses an UltraStar txt file. Problems that don't prevent parsing are
+/// returned as warnings next to the song, the first fatal one as
+/// [`AppError::UltrastarParseError`].
+pub fn parse_ultrastar_txt(content: &str) -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
     let content = content.strip_prefix('\u{FEFF}').unwrap_or(content);
 
     let mut song = Song {
         title: String::new(),
         artist: String::new(),
         bpm: 0.0,
+        tempo_changes: Vec::new(),
         gap: 0.0,
         video_gap: 0.0,
         start: None,
@@ -118,26 +221,45 @@ pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
         medley_end_beat: None,
         medley_start: None,
         medley_end: None,
+        resolution: None,
+        notes_gap: None,
+        calc_medley: None,
+        vocals: None,
+        comment: None,
+        provided_by: None,
+        encoding: None,
+        audio_web_url: None,
+        video_web_url: None,
+        cover_web_url: None,
+        background_web_url: None,
+        custom_tags: Vec::new(),
         voices: Vec::new(),
     };
 
     let mut notes: Vec<Note> = Vec::new();
-    let mut phrases: Vec<Phrase> = Vec::new();
-    let mut voices: Vec<Voice> = Vec::new();
-    let mut md5_context = md5::Context::new();
+    // Phrases of each voice, and how many `P` sections contributed to it
+    let mut voice_phrases: Vec<Vec<Phrase>> = vec![Vec::new()];
+    let mut voice_sections: Vec<u32> = vec![0];
+    // Voices the current section is sung by, notes before any `P` line belong to the first one
+    let mut targets: Vec<usize> = vec![0];
+    let mut singers: BTreeMap<usize, String> = BTreeMap::new();
+    let mut diagnostics: Vec<ParseDiagnostic> = Vec::new();
+    let mut has_end = false;
+    // In relative mode every beat is an offset to the start of the current line
+    let mut beat_offset = 0;
 
     let lines: Vec<&str> = content.lines().collect();
 
     // First pass: find version to determine parsing behavior
     let mut file_version: Option<String> = None;
-    for line in lines.iter() {
+    for (index, line) in lines.iter().enumerate() {
         let line = line.trim_start();
         if line.starts_with('#') {
             let line = line.trim();
             if let Some((property, value)) = line[1..].split_once(':') {
                 let property = property.trim().to_lowercase();
                 if property == "version" {
-                    file_version = Some(parse_version(value.trim())?);
+                    file_version = Some(parse_version(value.trim(), index as u32 + 1)?);
                     break;
                 }
             }
@@ -147,12 +269,19 @@ pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
     // Default to 1.0.0 if no version specified
     let version_str = file_version.as_deref().unwrap_or("1.0.0");
     song.version = Some(version_str.to_string());
-    let version = Version::parse(version_str)
-        .map_err(|_| AppError::UltrastarError(format!("Invalid version: {}", version_str)))?;
+    let version = Version::parse(version_str).map_err(|_| {
+        ParseDiagnostic::error(
+            None,
+            DiagnosticCode::InvalidVersion,
+            format!("Invalid version: {}", version_str),
+        )
+    })?;
     let supports_multi_value = version >= Version::parse("1.1.0").unwrap();
     let uses_milliseconds = version >= Version::parse("2.0.0").unwrap();
 
-    for (_index, line) in lines.iter().enumerate() {
+    let mut lines = lines.iter().enumerate();
+    for (index, line) in lines.by_ref() {
+        let line_number = index as u32 + 1;
         let line = line.trim_start();
         if line.is_empty() {
             continue;
@@ -160,13 +289,15 @@ pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
 
         if line.starts_with('#') {
             let line = line.trim();
-            if let Some((property, value)) = line[1..].split_once(':') {
-                let property = property.trim().to_lowercase();
+            if let Some((raw_property, value)) = line[1..].split_once(':') {
+                let raw_property = raw_property.trim();
+                let property = raw_property.to_lowercase();
                 let value = value.trim();
 
                 match property.as_str() {
                     "title" => song.title = value.to_string(),
                     "artist" => song.artist = value.to_string(),
+                    "album" => song.album = Some(value.to_string()),
                     "language" => {
                         song.language = parse_multi_value_field(value, supports_multi_value)
                     }
@@ -178,65 +309,112 @@ pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
                         song.year = if value.is_empty() {
                             None
                         } else {
-                            Some(parse_us_int(value, &property)?)
+                            Some(parse_us_int(value, &property, line_number)?)
                         }
                     }
-                    "bpm" => song.bpm = parse_us_float(value, &property)?,
-                    "gap" => song.gap = parse_us_float(value, &property)?,
+                    "bpm" => song.bpm = parse_us_float(value, &property, line_number)?,
+                    "gap" => song.gap = parse_us_float(value, &property, line_number)?,
                     "start" => {
-                        song.start = Some(parse_time_value(value, &property, uses_milliseconds)?)
+                        song.start = Some(parse_time_value(
+                            value,
+                            &property,
+                            uses_milliseconds,
+                            line_number,
+                        )?)
                     }
-                    "end" => song.end = Some(parse_us_int(value, &property)?),
+                    "end" => song.end = Some(parse_us_int(value, &property, line_number)?),
                     "mp3" | "audio" => song.audio = Some(value.to_string()),
                     "instrumental" => song.instrumental = Some(value.to_string()),
                     "cover" => song.cover = Some(value.to_string()),
                     "video" => song.video = Some(value.to_string()),
                     "background" => song.background = Some(value.to_string()),
+                    "vocals" => song.vocals = Some(value.to_string()),
+                    "comment" => song.comment = Some(value.to_string()),
+                    "providedby" => song.provided_by = Some(value.to_string()),
+                    "encoding" => song.encoding = Some(value.to_string()),
+                    "audiourl" => song.audio_web_url = Some(value.to_string()),
+                    "videourl" => song.video_web_url = Some(value.to_string()),
+                    "coverurl" => song.cover_web_url = Some(value.to_string()),
+                    "backgroundurl" => song.background_web_url = Some(value.to_string()),
                     "relative" => song.relative = Some(parse_us_bool(value)),
+                    "resolution" => {
+                        song.resolution = Some(parse_us_int(value, &property, line_number)?)
+                    }
+                    "notesgap" => {
+                        song.notes_gap = Some(parse_us_int(value, &property, line_number)?)
+                    }
+                    "calcmedley" => song.calc_medley = Some(!value.eq_ignore_ascii_case("off")),
                     "videogap" => {
-                        song.video_gap = parse_time_value(value, &property, uses_milliseconds)?
+                        song.video_gap =
+                            parse_time_value(value, &property, uses_milliseconds, line_number)?
                     }
                     "author" | "creator" => {
                         song.creator = parse_multi_value_field(value, supports_multi_value)
                     }
-                    "duetsingerp1" | "p1" => song.p1 = Some(value.to_string()),
-                    "duetsingerp2" | "p2" => song.p2 = Some(value.to_string()),
                     "preview" | "previewstart" => {
-                        song.preview_start =
-                            Some(parse_time_value(value, &property, uses_milliseconds)?)
+                        song.preview_start = Some(parse_time_value(
+                            value,
+                            &property,
+                            uses_milliseconds,
+                            line_number,
+                        )?)
                     }
                     "tags" => song.tags = parse_multi_value_field(value, supports_multi_value),
-                    "version" => song.version = Some(parse_version(value)?),
+                    "version" => song.version = Some(parse_version(value, line_number)?),
                     "medleystartbeat" => {
                         song.medley_start_beat = if value.is_empty() {
                             None
                         } else {
-                            Some(parse_us_int(value, &property)?)
+                            Some(parse_us_int(value, &property, line_number)?)
                         }
                     }
                     "medleyendbeat" => {
                         song.medley_end_beat = if value.is_empty() {
                             None
                         } else {
-                            Some(parse_us_int(value, &property)?)
+                            Some(parse_us_int(value, &property, line_number)?)
                         }
                     }
                     "medleystart" => {
                         song.medley_start = if value.is_empty() {
                             None
                         } else {
-                            Some(parse_time_value(value, &property, uses_milliseconds)?)
+                            Some(parse_time_value(
+                                value,
+                                &property,
+                                uses_milliseconds,
+                                line_number,
+                            )?)
                         }
                     }
                     "medleyend" => {
                         song.medley_end = if value.is_empty() {
                             None
                         } else {
-                            Some(parse_time_value(value, &property, uses_milliseconds)?)
+                            Some(parse_time_value(
+                                value,
+                                &property,
+                                uses_milliseconds,
+                                line_number,
+                            )?)
+                        }
+                    }
+                    _ => {
+                        if let Some(number) = parse_singer_header(&property) {
+                            singers.insert(number, value.to_string());
+                        } else {
+                            // Vendor tags are common, so they are kept without a warning
+                            song.custom_tags
+                                .push((raw_property.to_string(), value.to_string()));
                         }
                     }
-                    _ => (),
                 }
+            } else {
+                diagnostics.push(ParseDiagnostic::warning(
+                    Some(line_number),
+                    DiagnosticCode::MalformedHeader,
+                    format!("Header without value: {}", line),
+                ));
             }
         } else if [":", "*", "F", "R", "G"]
             .contains(&line.chars().next().unwrap_or(' ').to_string().as_str())
@@ -245,13 +423,22 @@ pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
             let parts: Vec<&str> = line.splitn(5, ' ').collect();
             if parts.len() >= 5 {
                 let tag = parts[0];
-                let start_beat = parse_us_int(parts[1], "start_beat")?;
-                let length = parse_us_int(parts[2], "length")?;
-                let txt_pitch = parse_us_int(parts[3], "txt_pitch")?;
+                let start_beat = parse_us_int(parts[1], "start_beat", line_number)? + beat_offset;
+                let length = parse_us_int(parts[2], "length", line_number)?;
+                let txt_pitch = parse_us_int(parts[3], "txt_pitch", line_number)?;
                 let text = parts[4..].join(" ");
 
+                let note_type = tag_to_note_type(tag).unwrap_or_else(|| {
+                    diagnostics.push(ParseDiagnostic::warning(
+                        Some(line_number),
+                        DiagnosticCode::UnknownNoteType,
+                        format!("Unknown note type '{}', treating it as freestyle", tag),
+                    ));
+                    NoteType::Freestyle
+                });
+
                 let note = Note {
-                    note_type: tag_to_note_type(tag),
+                    note_type,
                     start_beat,
                     length,
                     txt_pitch,
@@ -260,124 +447,307 @@ pub fn parse_ultrastar_txt(content: &str) -> Result<Song, AppError> {
                 };
 
                 notes.push(note);
-                md5_context.consume(format!("{} {} {} {}", tag, parts[1], parts[2], parts[3]));
+            } else {
+                diagnostics.push(ParseDiagnostic::warning(
+                    Some(line_number),
+                    DiagnosticCode::IncompleteLine,
+                    format!("Skipping incomplete note: {}", line),
+                ));
             }
-        } else if line.starts_with('-') {
+        } else if let Some(values) = line.strip_prefix('-') {
             // Line break
-            if let Some(disappear_beat_str) = line[1..].trim().split_whitespace().next() {
-                let disappear_beat = parse_us_int(disappear_beat_str, "disappear_beat")?;
+            let mut values = values.split_whitespace();
+            if let Some(disappear_beat_str) = values.next() {
+                let disappear_beat =
+                    parse_us_int(disappear_beat_str, "disappear_beat", line_number)?;
+                let next_start_beat = values
+                    .next()
+                    .and_then(|value| parse_us_int(value, "next_start_beat", line_number).ok());
+
+                // Relative line breaks move the origin by their second value, or
+                // to the break itself if the second value is missing
+                let disappear_beat = disappear_beat + beat_offset;
+                let next_start_beat = next_start_beat.map(|beat| beat + beat_offset);
+                if song.relative == Some(true) {
+                    beat_offset = next_start_beat.unwrap_or(disappear_beat);
+                }
                 let phrase = Phrase {
                     disappear_beat,
+                    next_start_beat,
+                    shared: targets.len() > 1,
                     notes: std::mem::take(&mut notes),
                 };
-                phrases.push(phrase);
-                md5_context.consume(line);
+                for &target in &targets {
+                    voice_phrases[target].push(phrase.clone());
+                }
+            } else {
+                diagnostics.push(ParseDiagnostic::warning(
+                    Some(line_number),
+                    DiagnosticCode::IncompleteLine,
+                    "Skipping line break without beat",
+                ));
+            }
+        } else if let Some(values) = line.strip_prefix('B') {
+            // Tempo change
+            let mut values = values.split_whitespace();
+            if let (Some(beat_str), Some(bpm_str)) = (values.next(), values.next()) {
+                let tempo_change = TempoChange {
+                    beat: parse_us_int(beat_str, "tempo_change_beat", line_number)? + beat_offset,
+                    bpm: parse_us_float(bpm_str, "tempo_change_bpm", line_number)?,
+                };
+                // Beats would never advance, or jump to infinity, at such a tempo
+                if tempo_change.bpm.is_finite() && tempo_change.bpm > 0.0 {
+                    song.tempo_changes.push(tempo_change);
+                } else {
+                    diagnostics.push(ParseDiagnostic::warning(
+                        Some(line_number),
+                        DiagnosticCode::InvalidNumber,
+                        format!("Skipping tempo change with invalid BPM: {}", line),
+                    ));
+                }
+            } else {
+                diagnostics.push(ParseDiagnostic::warning(
+                    Some(line_number),
+                    DiagnosticCode::IncompleteLine,
+                    format!("Skipping incomplete tempo change: {}", line),
+                ));
             }
-        } else if line.starts_with('P') {
+        } else if let Some(player) = line.strip_prefix('P') {
             // Player change
-            if !phrases.is_empty() || !notes.is_empty() {
-                if !notes.is_empty() {
-                    let last_note = notes.last().unwrap();
-                    let phrase = Phrase {
-                        disappear_beat: last_note.start_beat + last_note.length + 1,
-                        notes: std::mem::take(&mut notes),
-                    };
-                    phrases.push(phrase);
+            close_implicit_phrase(&mut notes, &mut voice_phrases, &targets);
+            beat_offset = 0;
+
+            let has_third_singer = singers.contains_key(&3);
+            let player_voices = player
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|player| *player >= 1)
+                .map(|player| player_to_voices(player, has_third_singer))
+                .filter(|voices| voices.iter().all(|voice| *voice < MAX_VOICES));
+            match player_voices {
+                Some(player_voices) => {
+                    targets = player_voices;
+                    for &target in &targets {
+                        if target >= voice_phrases.len() {
+                            voice_phrases.resize(target + 1, Vec::new());
+                            voice_sections.resize(target + 1, 0);
+                        }
+                        voice_sections[target] += 1;
+                    }
                 }
-                let voice = Voice {
-                    phrases: std::mem::take(&mut phrases),
-                };
-                voices.push(voice);
+                _ => diagnostics.push(ParseDiagnostic::warning(
+                    Some(line_number),
+                    DiagnosticCode::InvalidNumber,
+                    format!("Invalid player number, keeping the previous one: {}", line),
+                )),
             }
-            md5_context.consume(b"P");
         } else if line.starts_with('E') {
             // End
-            if !notes.is_empty() {
-                let last_note = notes.last().unwrap();
-                let phrase = Phrase {
-                    disappear_beat: last_note.start_beat + last_note.length + 1,
-                    notes: std::mem::take(&mut notes),
-                };
-                phrases.push(phrase);
+            has_end = true;
+            break;
+        } else {
+            diagnostics.push(ParseDiagnostic::warning(
+                Some(line_number),
+                DiagnosticCode::UnknownLine,
+                format!("Skipping unrecognized line: {}", line),
+            ));
+        }
+    }
+
+    if !has_end {
+        // Truncated files are still playable, so only warn about it
+        diagnostics.push(ParseDiagnostic::warning(
+            None,
+            DiagnosticCode::MissingEnd,
+            "Missing end marker 'E'",
+        ));
+    } else if let Some((index, _)) = lines.find(|(_, line)| !line.trim().is_empty()) {
+        diagnostics.push(ParseDiagnostic::warning(
+            Some(index as u32 + 1),
+            DiagnosticCode::ContentAfterEnd,
+            "Ignoring everything after the end marker 'E'",
+        ));
+    }
+
+    close_implicit_phrase(&mut notes, &mut voice_phrases, &targets);
+    song.voices = voice_phrases
+        .into_iter()
+        .zip(voice_sections)
+        .enumerate()
+        .map(|(index, (mut phrases, sections))| {
+            // Lines of shared sections end up behind the voice's own lines
+            if sections > 1 {
+                phrases.sort_by_key(|phrase| {
+                    phrase
+                        .notes
+                        .first()
+                        .map_or(phrase.disappear_beat, |note| note.start_beat)
+                });
             }
-            if !phrases.is_empty() {
-                let voice = Voice {
-                    phrases: std::mem::take(&mut phrases),
-                };
-                voices.push(voice);
+            Voice {
+                name: singers
+                    .get(&(index + 1))
+                    .filter(|name| !name.is_empty())
+                    .cloned(),
+                phrases,
             }
-            md5_context.consume(song.title.as_bytes());
-            md5_context.consume(song.artist.as_bytes());
-
-            song.hash = format!("{:x}", md5_context.finalize());
-            song.voices = voices;
-            break;
+        })
+        .filter(|voice| !voice.phrases.is_empty())
+        .collect();
+    song.p1 = singers.get(&1).cloned();
+    song.p2 = singers.get(&2).cloned();
+
+    song.tempo_changes.sort_by_key(|change| change.beat);
+    song.hash = song_hash(&song);
+
+    // 2.0.0 files set the medley window in milliseconds only
+    if song.bpm != 0.0 {
+        if let (None, Some(medley_start)) = (song.medley_start_beat, song.medley_start) {
+            song.medley_start_beat = Some(song.ms_to_beat(medley_start).round() as i32);
+        }
+        if let (None, Some(medley_end)) = (song.medley_end_beat, song.medley_end) {
+            song.medley_end_beat = Some(song.ms_to_beat(medley_end).round() as i32);
         }
     }
 
+    let missing = |code, message: &str| -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
+        Err(ParseDiagnostic::error(None, code, message).into())
+    };
     if song.title.is_empty() {
-        return Err(AppError::UltrastarError("Missing song title".to_string()));
+        return missing(DiagnosticCode::MissingTitle, "Missing song title");
     }
     if song.artist.is_empty() {
-        return Err(AppError::UltrastarError("Missing song artist".to_string()));
+        return missing(DiagnosticCode::MissingArtist, "Missing song artist");
     }
     if song.bpm == 0.0 {
-        return Err(AppError::UltrastarError("Missing song BPM".to_string()));
-    }
-    if song.hash.is_empty() {
-        return Err(AppError::UltrastarError("Missing song hash".to_string()));
+        return missing(DiagnosticCode::MissingBpm, "Missing song BPM");
     }
     if song.audio.is_none() && song.video.is_none() {
-        return Err(AppError::UltrastarError(
-            "Song must have either audio or video file".to_string(),
-        ));
+        return missing(
+            DiagnosticCode::MissingMedia,
+            "Song must have either audio or video file",
+        );
     }
 
-    Ok(song)
+    Ok((song, diagnostics))
 }
 
-pub fn parse_local_txt_file(
-    txt: &str,
+/// Reads a song file with the importer for its format, picked by its name.
+/// `filenames` are the files in the song's folder. Returns the decoded text,
+/// which is the `song.ini` for Rock Band charts and empty for CD+G songs,
+/// with the parsed song.
+pub fn read_song_file(
+    song_file: &str,
+    filenames: &[String],
+) -> Result<(DecodedTxt, (Song, Vec<ParseDiagnostic>)), AppError> {
+    let song_path = Path::new(song_file);
+    let song_filename = song_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    if is_rockband_chart(song_filename) {
+        let decoded = read_txt_file(&song_path.with_file_name("song.ini").to_string_lossy())?;
+        let parsed =
+            parse_rockband_song(&read_media_file(song_path)?, &decoded.content, filenames)?;
+        Ok((decoded, parsed))
+    } else if is_melody_chart(song_filename) {
+        let decoded = read_txt_file(song_file)?;
+        let folder_name = song_path
+            .parent()
+            .and_then(|parent| parent.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let parsed = parse_melody_xml(&decoded.content, filenames, &folder_name)?;
+        Ok((decoded, parsed))
+    } else if is_cdg_file(song_filename) {
+        // CD+G songs have no text file, the graphics are binary
+        let decoded = DecodedTxt {
+            content: String::new(),
+            encoding: encoding_rs::UTF_8,
+            confidence: EncodingConfidence::Certain,
+        };
+        let parsed = parse_cdg_song(song_filename, &read_media_file(song_path)?, filenames)?;
+        Ok((decoded, parsed))
+    } else {
+        let decoded = read_txt_file(song_file)?;
+        let parsed = parse_ultrastar_txt(&decoded.content)?;
+        Ok((decoded, parsed))
+    }
+}
+
+/// Parses a song found by the library scan and resolves its media files.
+/// `song_file` is an UltraStar txt file or the `notes.mid` of a Rock Band
+/// chart, whose text encoding is then the one of its `song.ini`. Covers
+/// embedded in the audio file are stored in `cache_dir`. Audio files without
+/// ReplayGain tags take the values measured for them before from
+/// `loudness_cache`.
+pub fn parse_local_song_file(
+    song_file: &str,
     files: &Vec<FileEntry>,
     media_base_url: &str,
+    cache_dir: Option<&Path>,
+    loudness_cache: Option<&LoudnessCache>,
 ) -> Result<LocalSong, AppError> {
-    let bytes = fs::read(txt)?;
-    let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
-    detector.feed(&bytes, true);
-    let encoding = detector.guess(None, Utf8Detection::Allow);
-    let (content, _, _) = encoding.decode(&bytes);
-
-    let song = parse_ultrastar_txt(&content)?;
-
-    let find_file = |filename: &Option<String>| -> Option<&FileEntry> {
-        if let Some(filename) = filename {
-            let normalized_target = filename.nfc().collect::<String>().to_lowercase();
-            files.iter().find(|f| {
-                let normalized_file = f.filename.nfc().collect::<String>().to_lowercase();
-                normalized_file == normalized_target
-            })
-        } else {
-            None
+    let song_path = Path::new(song_file);
+    let song_filename = song_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let filenames: Vec<String> = files.iter().map(|file| file.filename.clone()).collect();
+    let (decoded, (mut song, mut diagnostics)) = read_song_file(song_file, &filenames)?;
+
+    let mut media_substitutions = Vec::new();
+    let mut find_file = |filename: &Option<String>, tag: MediaTag| -> Option<&FileEntry> {
+        let (file, substitution) = resolve_media_file(files, tag, filename.as_deref()?)?;
+        if let Some(substitution) = substitution {
+            log::info!(
+                "Using '{}' for {:?} file '{}' of '{}'",
+                substitution.found,
+                tag,
+                substitution.specified,
+                song_file
+            );
+            media_substitutions.push(substitution);
         }
+        Some(file)
+    };
+    let mut inferred_media = Vec::new();
+    let mut infer_file = |tag: MediaTag| -> Option<&FileEntry> {
+        let file = infer_media_file(files, tag)?;
+        log::info!(
+            "Using '{}' as {:?} file of '{}'",
+            file.filename,
+            tag,
+            song_file
+        );
+        inferred_media.push(tag);
+        Some(file)
     };
 
+    let create_url = |path: &Path| -> Result<String, AppError> {
+        let path = canonicalize_media_path(path)?;
+        let path_string = path.to_string_lossy();
+        let encoded = urlencoding::encode(&path_string);
+        Ok(format!("{}/{}", media_base_url, encoded))
+    };
+    let create_thumbnail_urls = |path: &Path| -> Result<ThumbnailUrls, AppError> {
+        let path = canonicalize_media_path(path)?;
+        let encoded = urlencoding::encode(&path.to_string_lossy()).into_owned();
+        Ok(ThumbnailUrls::new(media_base_url, &encoded))
+    };
     let create_url_from_file =
         |file_entry: Option<&FileEntry>| -> Result<Option<String>, AppError> {
-            if let Some(file_entry) = file_entry {
-                let path = dunce::canonicalize(&file_entry.path)?;
-                let path_string = path.to_string_lossy();
-                let encoded = urlencoding::encode(&path_string);
-                Ok(Some(format!("{}/{}", media_base_url, encoded)))
-            } else {
-                Ok(None)
-            }
+            file_entry
+                .map(|file_entry| create_url(Path::new(&file_entry.path)))
+                .transpose()
         };
 
-    let audio_file = find_file(&song.audio);
-    let instrumental_file = find_file(&song.instrumental);
-    let video_file = find_file(&song.video);
-    let cover_file = find_file(&song.cover);
-    let background_file = find_file(&song.background);
+    let audio_file = find_file(&song.audio, MediaTag::Audio);
+    let instrumental_file = find_file(&song.instrumental, MediaTag::Instrumental);
+    let video_file = find_file(&song.video, MediaTag::Video);
+    let cover_file = find_file(&song.cover, MediaTag::Cover);
+    let background_file = find_file(&song.background, MediaTag::Background);
 
     if song.audio.is_some() && audio_file.is_none() {
         return Err(AppError::UltrastarError(format!(
@@ -411,6 +781,11 @@ pub fn parse_local_txt_file(
         );
     }
 
+    // Packs often leave out the tags for images and videos named by convention
+    let video_file = video_file.or_else(|| infer_file(MediaTag::Video));
+    let cover_file = cover_file.or_else(|| infer_file(MediaTag::Cover));
+    let background_file = background_file.or_else(|| infer_file(MediaTag::Background));
+
     let audio_url = create_url_from_file(audio_file)?;
     let instrumental_url = create_url_from_file(instrumental_file)?;
     let video_url = create_url_from_file(video_file)?;
@@ -423,9 +798,86 @@ pub fn parse_local_txt_file(
         ));
     }
 
-    let replay_gain = audio_file.and_then(|file| get_replay_gain(&file.path).ok());
+    // Songs without a cover image can still have one in their audio file
+    let audio_tags =
+        audio_file.and_then(|file| read_audio_tags(&file.path, cover_url.is_none()).ok());
+    let mut cover_url = cover_url;
+    let mut cover_path = cover_file.map(|file| PathBuf::from(&file.path));
+    if let (Some(cache_dir), Some(cover)) = (
+        cache_dir,
+        audio_tags.as_ref().and_then(|tags| tags.cover.as_ref()),
+    ) {
+        match cache_embedded_cover(cache_dir, cover) {
+            Ok(path) => {
+                cover_url = Some(create_url(&path)?);
+                cover_path = Some(path);
+            }
+            Err(e) => log::warn!("Failed to cache the cover of '{}': {}", song_file, e),
+        }
+    }
+    if let Some(tags) = &audio_tags {
+        if song.album.is_none() {
+            song.album = tags.album.clone();
+        }
+        if song.year.is_none() {
+            song.year = tags.year;
+        }
+        if song.genre.is_none() {
+            song.genre = tags.genre.clone();
+        }
+    }
+    let audio_info = audio_tags.as_ref().map(|tags| tags.info.clone());
+    let replay_gain = audio_tags.map(|tags| tags.replay_gain);
+    let (replay_gain_track_gain, replay_gain_track_peak) = match replay_gain {
+        Some(ReplayGainInfo {
+            track_gain: Some(gain),
+            track_peak,
+            ..
+        }) => (Some(gain), track_peak),
+        _ => audio_file
+            .zip(loudness_cache)
+            .and_then(|(file, cache)| cache.replay_gain(Path::new(&file.path)))
+            .map_or((None, None), |(gain, peak)| (Some(gain), Some(peak))),
+    };
+    let video_info = video_file.and_then(|file| probe_media(&file.path).ok());
+
+    for (tag, file) in [
+        (MediaTag::Audio, audio_file),
+        (MediaTag::Instrumental, instrumental_file),
+        (MediaTag::Video, video_file),
+    ] {
+        if let Some(file) = file.filter(|file| is_empty_file(&file.path)) {
+            diagnostics.push(ParseDiagnostic::warning(
+                None,
+                DiagnosticCode::EmptyMediaFile,
+                format!("{:?} file '{}' is empty", tag, file.filename),
+            ));
+        }
+    }
+    // The synthetic note of a CD+G song is as long as its graphics, not its audio
+    if !is_cdg_file(song_filename) {
+        if let Some(info) = audio_info.as_ref().or(video_info.as_ref()) {
+            check_media_duration(&song, info, &mut diagnostics);
+        }
+    }
+
+    // The song selection shows many covers at once, so it loads them scaled down
+    let cover_thumbnail_urls = cover_path
+        .map(|path| create_thumbnail_urls(&path))
+        .transpose()?;
+
+    let cdg_url = if is_cdg_file(song_filename) {
+        let path = canonicalize_media_path(song_path)?;
+        let encoded = urlencoding::encode(&path.to_string_lossy()).into_owned();
+        Some(format!("{}/cdg/{}", media_base_url, encoded))
+    } else {
+        None
+    };
 
-    let created_at = fs::metadata(txt).ok().and_then(|metadata| {
+    // Songs inside an archive take their dates from the archive
+    let metadata_path = split_archive_path(song_path)
+        .map_or_else(|| song_path.to_path_buf(), |(archive, _)| archive);
+    let created_at = fs::metadata(metadata_path).ok().and_then(|metadata| {
         metadata
             .created()
             .or_else(|_| metadata.modified())
@@ -440,9 +892,106 @@ pub fn parse_local_txt_file(
         instrumental_url,
         video_url,
         cover_url,
+        cover_thumbnail_urls,
         background_url,
-        replay_gain_track_gain: replay_gain.as_ref().and_then(|rg| rg.track_gain),
-        replay_gain_track_peak: replay_gain.as_ref().and_then(|rg| rg.track_peak),
+        cdg_url,
+        replay_gain_track_gain,
+        replay_gain_track_peak,
         created_at,
+        diagnostics,
+        text_encoding: decoded.encoding.name().to_string(),
+        text_encoding_confidence: decoded.confidence,
+        media_substitutions,
+        inferred_media,
+        audio_info,
+        video_info,
     })
 }
+
+/// Whether a file on disk is empty. Files inside archives are not checked.
+fn is_empty_file(path: &str) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.len() == 0)
+}
+
+/// Warns about notes and a `#START` the audio does not reach.
+fn check_media_duration(song: &Song, info: &MediaInfo, diagnostics: &mut Vec<ParseDiagnostic>) {
+    if info.duration_ms <= 0.0 {
+        return;
+    }
+
+    let last_note_end_ms = song
+        .voices
+        .iter()
+        .flat_map(|voice| &voice.phrases)
+        .flat_map(|phrase| &phrase.notes)
+        .map(|note| song.beat_to_ms((note.start_beat + note.length) as f64))
+        .reduce(f64::max);
+    if let Some(last_note_end_ms) = last_note_end_ms.filter(|end| *end > info.duration_ms) {
+        diagnostics.push(ParseDiagnostic::warning(
+            None,
+            DiagnosticCode::NotesAfterAudioEnd,
+            format!(
+                "The last note ends at {:.1}s, after the audio ends at {:.1}s",
+                last_note_end_ms / 1000.0,
+                info.duration_ms / 1000.0
+            ),
+        ));
+    }
+
+    if let Some(start) = song.start.filter(|start| *start >= info.duration_ms) {
+        diagnostics.push(ParseDiagnostic::warning(
+            None,
+            DiagnosticCode::StartAfterAudioEnd,
+            format!(
+                "#START is at {:.1}s, but the audio ends at {:.1}s",
+                start / 1000.0,
+                info.duration_ms / 1000.0
+            ),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "#TITLE:Title\n\
+                        #ARTIST:Artist\n\
+                        #MP3:song.mp3\n\
+                        #BPM:300\n\
+                        #GAP:0\n\
+                        : 0 2 0 One\n\
+                        - 4\n\
+                        : 6 2 0 Two\n\
+                        E\n";
+
+    fn hash(content: &str) -> String {
+        parse_ultrastar_txt(content).unwrap().0.hash
+    }
+
+    #[test]
+    fn keeps_unknown_headers_as_custom_tags() {
+        let (song, diagnostics) =
+            parse_ultrastar_txt(&SONG.replace("#BPM", "#CREATORID:42\n#Vendor: x \n#BPM")).unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(
+            song.custom_tags,
+            vec![
+                ("CREATORID".to_string(), "42".to_string()),
+                ("Vendor".to_string(), "x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn hashes_tempo_changes() {
+        let with_tempo_change = SONG.replace("- 4\n", "- 4\nB 4 150\n");
+        let with_other_tempo_change = SONG.replace("- 4\n", "- 4\nB 4 200\n");
+        assert_ne!(hash(SONG), hash(&with_tempo_change));
+        assert_ne!(hash(&with_tempo_change), hash(&with_other_tempo_change));
+        assert_eq!(
+            hash(&with_tempo_change),
+            hash(&SONG.replace("- 4\n", "- 4\nB 004 150.0\n"))
+        );
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+
+use crate::{
+    error::AppError,
+    ultrastar::{
+        diagnostics::{DiagnosticCode, ParseDiagnostic},
+        filesystem::{find_media, AUDIO_EXTENSIONS, IMAGE_EXTENSIONS, VIDEO_EXTENSIONS},
+        midi::{read_midi, MidiEvent, MidiEventKind},
+        song::{Note, NoteType, Phrase, Song, TempoChange, Voice},
+    },
+};
+
+/// UltraStar beats are 64th notes, so 32nd notes and triplets keep their own
+/// beats. The BPM is scaled to match, as UltraStar counts four beats per
+/// quarter note.
+const BEATS_PER_QUARTER: f64 = 16.0;
+/// Sung notes, everything outside is a marker or percussion.
+const SUNG_NOTES: std::ops::RangeInclusive<u8> = 36..=84;
+/// Phrase markers for player 1 and 2, both are treated as line boundaries.
+const PHRASE_MARKER_NOTES: [u8; 2] = [105, 106];
+/// Overdrive phrases, their notes become golden notes.
+const OVERDRIVE_NOTE: u8 = 116;
+
+const VOCAL_TRACK_NAMES: [&str; 2] = ["PART VOCALS", "HARM1"];
+const AUDIO_STEMS: [&str; 6] = ["song", "guitar", "rhythm", "bass", "keys", "drums"];
+
+/// Whether a file is a Rock Band/Frets on Fire chart, a `notes.mid` next to a `song.ini`.
+pub fn is_rockband_chart(filename: &str) -> bool {
+    filename.eq_ignore_ascii_case("notes.mid")
+}
+
+/// Converts a MIDI tempo in quarter notes per minute to an UltraStar BPM.
+fn quarter_bpm_to_bpm(quarter_bpm: f64) -> f64 {
+    quarter_bpm * BEATS_PER_QUARTER / 4.0
+}
+
+/// Reads the `[song]` section of a `song.ini`, keys are lowercased.
+fn parse_song_ini(content: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let mut in_song_section = true;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            in_song_section = section.trim().eq_ignore_ascii_case("song");
+        } else if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            if in_song_section && !value.is_empty() {
+                values.insert(key.trim().to_lowercase(), value.to_string());
+            }
+        }
+    }
+
+    values
+}
+
+struct ImportedNote {
+    start_tick: u32,
+    end_tick: u32,
+    key: u8,
+    lyric: String,
+}
+
+/// Turns a Rock Band lyric into UltraStar note text and type. `-` joins the
+/// syllable with the next one, `+` continues the previous syllable on a new
+/// pitch, `#` and `^` mark rap and talkie notes, `$` hides harmony lyrics and
+/// `=` stands for a literal hyphen.
+fn convert_lyric(lyric: &str) -> (String, bool, bool) {
+    let mut text = lyric.trim().replace('$', "");
+    let is_rap = text.ends_with('#') || text.ends_with('^');
+    text = text.trim_end_matches(['#', '^']).to_string();
+    let joins_next = text.ends_with('-');
+    if joins_next {
+        text.pop();
+    }
+    let text = match text.as_str() {
+        "+" => "~".to_string(),
+        _ => text.replace('=', "-").replace('§', "‿"),
+    };
+    (text, joins_next, is_rap)
+}
+
+/// Builds a `Song` from a Rock Band/Frets on Fire chart: the vocals track of
+/// `notes.mid` and the metadata of `song.ini`. `files` are the file names in
+/// the chart folder, used to pick the audio, video and images by their
+/// conventional names. The hash covers the MIDI data, title and artist.
+pub fn parse_rockband_song(
+    midi: &[u8],
+    song_ini: &str,
+    files: &[String],
+) -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
+    let midi_file = read_midi(midi)?;
+    let ini = parse_song_ini(song_ini);
+    let mut diagnostics = Vec::new();
+
+    let ticks_per_quarter = midi_file.ticks_per_quarter as f64;
+    let tick_to_beat =
+        |tick: u32| (tick as f64 * BEATS_PER_QUARTER / ticks_per_quarter).round() as i32;
+
+    // The tempo map lives in the first track
+    let mut tempo_changes: Vec<TempoChange> = midi_file
+        .tracks
+        .first()
+        .into_iter()
+        .flatten()
+        .filter_map(|event| match &event.kind {
+            MidiEventKind::Meta { kind: 0x51, data } if data.len() == 3 => {
+                let microseconds = u32::from_be_bytes([0, data[0], data[1], data[2]]) as f64;
+                Some(TempoChange {
+                    beat: tick_to_beat(event.tick),
+                    bpm: quarter_bpm_to_bpm(60_000_000.0 / microseconds),
+                })
+            }
+            _ => None,
+        })
+        .filter(|change| change.bpm.is_finite())
+        .collect();
+    // Of several tempo events on the same beat the last one wins
+    tempo_changes.dedup_by(|next, previous| {
+        let duplicate = next.beat == previous.beat;
+        if duplicate {
+            previous.bpm = next.bpm;
+        }
+        duplicate
+    });
+    let bpm = match tempo_changes.first() {
+        Some(change) if change.beat == 0 => tempo_changes.remove(0).bpm,
+        // MIDI defaults to 120 BPM until the first tempo event
+        _ => quarter_bpm_to_bpm(120.0),
+    };
+
+    let track_name = |track: &Vec<MidiEvent>| {
+        track
+            .iter()
+            .find_map(|event| event.meta_text(0x03))
+            .unwrap_or_default()
+    };
+    let vocals = VOCAL_TRACK_NAMES
+        .iter()
+        .find_map(|name| {
+            midi_file
+                .tracks
+                .iter()
+                .find(|track| track_name(track).trim() == *name)
+        })
+        .ok_or_else(|| AppError::UltrastarError("No PART VOCALS track in notes.mid".to_string()))?;
+
+    let mut notes: Vec<ImportedNote> = Vec::new();
+    let mut phrases: Vec<(u32, u32)> = Vec::new();
+    let mut overdrive: Vec<(u32, u32)> = Vec::new();
+    let mut lyrics: HashMap<u32, String> = HashMap::new();
+    let mut open_notes: HashMap<u8, u32> = HashMap::new();
+
+    for event in vocals {
+        match &event.kind {
+            MidiEventKind::NoteOn { key } => {
+                open_notes.insert(*key, event.tick);
+            }
+            MidiEventKind::NoteOff { key } => {
+                let Some(start_tick) = open_notes.remove(key) else {
+                    continue;
+                };
+                let span = (start_tick, event.tick);
+                if SUNG_NOTES.contains(key) {
+                    notes.push(ImportedNote {
+                        start_tick,
+                        end_tick: event.tick,
+                        key: *key,
+                        lyric: String::new(),
+                    });
+                } else if PHRASE_MARKER_NOTES.contains(key) {
+                    phrases.push(span);
+                } else if *key == OVERDRIVE_NOTE {
+                    overdrive.push(span);
+                }
+            }
+            MidiEventKind::Meta {
+                kind: kind @ (0x01 | 0x05),
+                data,
+            } => {
+                let text = String::from_utf8_lossy(data).into_owned();
+                // Text events in brackets are animation and practice markers,
+                // older charts use plain text events for lyrics
+                if text.starts_with('[') {
+                    continue;
+                } else if *kind == 0x05 {
+                    lyrics.insert(event.tick, text);
+                } else {
+                    lyrics.entry(event.tick).or_insert(text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    notes.sort_by_key(|note| note.start_tick);
+    for note in &mut notes {
+        match lyrics.get(&note.start_tick) {
+            Some(lyric) => note.lyric = lyric.clone(),
+            None => diagnostics.push(ParseDiagnostic::warning(
+                None,
+                DiagnosticCode::IncompleteLine,
+                format!("Note at tick {} has no lyric", note.start_tick),
+            )),
+        }
+    }
+
+    phrases.sort();
+    phrases.dedup_by_key(|phrase| phrase.0);
+    if phrases.is_empty() {
+        diagnostics.push(ParseDiagnostic::warning(
+            None,
+            DiagnosticCode::IncompleteLine,
+            "No phrase markers, all notes end up in one line",
+        ));
+        if let (Some(first), Some(last)) = (notes.first(), notes.last()) {
+            phrases.push((first.start_tick, last.end_tick));
+        }
+    }
+
+    let mut song_phrases: Vec<Phrase> = phrases
+        .iter()
+        .map(|(_, end_tick)| Phrase {
+            disappear_beat: tick_to_beat(*end_tick),
+            next_start_beat: None,
+            shared: false,
+            notes: Vec::new(),
+        })
+        .collect();
+    let mut joins_previous = true;
+    for note in &notes {
+        // Notes outside of any phrase go into the last phrase that started before them
+        let phrase_index = phrases
+            .iter()
+            .rposition(|(start_tick, _)| *start_tick <= note.start_tick)
+            .unwrap_or(0);
+        let Some(phrase) = song_phrases.get_mut(phrase_index) else {
+            continue;
+        };
+
+        let (mut text, joins_next, is_rap) = convert_lyric(&note.lyric);
+        if phrase.notes.is_empty() {
+            joins_previous = true;
+        }
+        if !joins_previous && text != "~" {
+            text.insert(0, ' ');
+        }
+        joins_previous = joins_next || text == "~";
+
+        let is_golden = overdrive
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&note.start_tick));
+        let note_type = match (is_rap, is_golden) {
+            (true, true) => NoteType::RapGolden,
+            (true, false) => NoteType::Rap,
+            (false, true) => NoteType::Golden,
+            (false, false) => NoteType::Normal,
+        };
+
+        let start_beat = tick_to_beat(note.start_tick);
+        let length = (tick_to_beat(note.end_tick) - start_beat).max(1);
+        phrase.notes.push(Note {
+            note_type,
+            start_beat,
+            length,
+            txt_pitch: note.key as i32 - 60,
+            midi_note: note.key as i32,
+            text,
+        });
+    }
+    song_phrases.retain(|phrase| !phrase.notes.is_empty());
+    for phrase in &mut song_phrases {
+        if let Some(end_beat) = phrase
+            .notes
+            .iter()
+            .map(|note| note.start_beat + note.length)
+            .max()
+        {
+            phrase.disappear_beat = phrase.disappear_beat.max(end_beat);
+        }
+    }
+
+    let value = |key: &str| ini.get(key).cloned();
+    let title = value("name").unwrap_or_default();
+    let artist = value("artist").unwrap_or_default();
+
+    let mut md5_context = md5::Context::new();
+    md5_context.consume(midi);
+    md5_context.consume(title.as_bytes());
+    md5_context.consume(artist.as_bytes());
+
+    let song = Song {
+        title,
+        artist,
+        bpm,
+        tempo_changes,
+        gap: value("delay")
+            .and_then(|delay| delay.parse::<f64>().ok())
+            .unwrap_or(0.0),
+        video_gap: value("video_start_time")
+            .and_then(|start| start.parse::<f64>().ok())
+            .unwrap_or(0.0),
+        start: None,
+        end: None,
+        hash: format!("{:x}", md5_context.finalize()),
+        album: value("album"),
+        language: None,
+        edition: None,
+        genre: value("genre").map(|genre| vec![genre]),
+        year: value("year").and_then(|year| {
+            year.trim_start_matches(|c: char| !c.is_ascii_digit())
+                .get(..4)
+                .and_then(|year| year.parse().ok())
+        }),
+        creator: value("charter")
+            .or_else(|| value("frets"))
+            .map(|creator| vec![creator]),
+        relative: None,
+        audio: find_media(files, &AUDIO_STEMS, &AUDIO_EXTENSIONS),
+        instrumental: None,
+        cover: find_media(files, &["album"], &IMAGE_EXTENSIONS),
+        video: find_media(files, &["video"], &VIDEO_EXTENSIONS),
+        background: find_media(files, &["background"], &IMAGE_EXTENSIONS),
+        p1: None,
+        p2: None,
+        preview_start: value("preview_start_time").and_then(|start| start.parse::<f64>().ok()),
+        version: Some("1.0.0".to_string()),
+        tags: None,
+        medley_start_beat: None,
+        medley_end_beat: None,
+        medley_start: None,
+        medley_end: None,
+        resolution: None,
+        notes_gap: None,
+        calc_medley: None,
+        vocals: find_media(files, &["vocals"], &AUDIO_EXTENSIONS),
+        comment: None,
+        provided_by: None,
+        encoding: None,
+        audio_web_url: None,
+        video_web_url: None,
+        cover_web_url: None,
+        background_web_url: None,
+        custom_tags: Vec::new(),
+        voices: vec![Voice {
+            name: None,
+            phrases: song_phrases,
+        }],
+    };
+
+    let missing = |code, message: &str| -> Result<(Song, Vec<ParseDiagnostic>), AppError> {
+        Err(ParseDiagnostic::error(None, code, message).into())
+    };
+    if song.title.is_empty() {
+        return missing(DiagnosticCode::MissingTitle, "Missing name in song.ini");
+    }
+    if song.artist.is_empty() {
+        return missing(DiagnosticCode::MissingArtist, "Missing artist in song.ini");
+    }
+    if song.audio.is_none() && song.video.is_none() {
+        return missing(
+            DiagnosticCode::MissingMedia,
+            "Chart folder has no song audio or video",
+        );
+    }
+
+    Ok((song, diagnostics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG_INI: &str = "[song]\nname = Title\nartist = Artist\ndelay = 250\n";
+
+    fn variable_length(value: u32) -> Vec<u8> {
+        let mut bytes = vec![(value & 0x7F) as u8];
+        let mut value = value >> 7;
+        while value > 0 {
+            bytes.insert(0, (value & 0x7F) as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes
+    }
+
+    fn meta(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut event = vec![0xFF, kind, data.len() as u8];
+        event.extend_from_slice(data);
+        event
+    }
+
+    /// A format 1 file at 480 ticks per quarter, which is 30 ticks per beat.
+    fn midi(tracks: Vec<Vec<(u32, Vec<u8>)>>) -> Vec<u8> {
+        let mut output = b"MThd".to_vec();
+        output.extend_from_slice(&6u32.to_be_bytes());
+        output.extend_from_slice(&1u16.to_be_bytes());
+        output.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        output.extend_from_slice(&480u16.to_be_bytes());
+        for mut events in tracks {
+            events.sort_by_key(|(tick, _)| *tick);
+            let mut data = Vec::new();
+            let mut last_tick = 0;
+            for (tick, event) in events {
+                data.extend(variable_length(tick - last_tick));
+                data.extend(event);
+                last_tick = tick;
+            }
+            data.extend([0, 0xFF, 0x2F, 0]);
+            output.extend_from_slice(b"MTrk");
+            output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            output.extend(data);
+        }
+        output
+    }
+
+    fn note(events: &mut Vec<(u32, Vec<u8>)>, tick: u32, length: u32, key: u8, lyric: &str) {
+        if !lyric.is_empty() {
+            events.push((tick, meta(0x05, lyric.as_bytes())));
+        }
+        events.push((tick, vec![0x90, key, 100]));
+        events.push((tick + length, vec![0x80, key, 0]));
+    }
+
+    fn chart() -> Vec<u8> {
+        let tempo_track = vec![
+            (0, meta(0x51, &500_000u32.to_be_bytes()[1..])),
+            (1920, meta(0x51, &1_000_000u32.to_be_bytes()[1..])),
+        ];
+
+        let mut vocals = vec![(0, meta(0x03, b"PART VOCALS")), (0, meta(0x01, b"[idle]"))];
+        note(&mut vocals, 0, 960, 105, "");
+        note(&mut vocals, 960, 960, 106, "");
+        note(&mut vocals, 960, 240, OVERDRIVE_NOTE, "");
+        note(&mut vocals, 0, 240, 60, "Hel-");
+        note(&mut vocals, 240, 240, 62, "lo");
+        note(&mut vocals, 480, 120, 64, "rap#");
+        note(&mut vocals, 960, 240, 65, "gold");
+        note(&mut vocals, 1200, 60, 67, "talk^");
+        note(&mut vocals, 1260, 60, 69, "+");
+
+        midi(vec![tempo_track, vocals])
+    }
+
+    fn parse(midi: &[u8]) -> (Song, Vec<ParseDiagnostic>) {
+        let files = ["notes.mid", "song.ini", "song.ogg", "album.png"].map(String::from);
+        parse_rockband_song(midi, SONG_INI, &files).unwrap()
+    }
+
+    #[test]
+    fn imports_the_tempo_map_at_64th_note_beats() {
+        let (song, diagnostics) = parse(&chart());
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        // 120 and 60 quarter notes per minute at 16 beats per quarter
+        assert_eq!(song.bpm, 480.0);
+        assert_eq!(
+            song.tempo_changes,
+            vec![TempoChange {
+                beat: 64,
+                bpm: 240.0
+            }]
+        );
+        assert_eq!(song.gap, 250.0);
+        assert_eq!(song.audio.as_deref(), Some("song.ogg"));
+        assert_eq!(song.cover.as_deref(), Some("album.png"));
+    }
+
+    #[test]
+    fn imports_phrases_overdrive_and_rap_notes() {
+        let (song, _) = parse(&chart());
+        let phrases = &song.voices[0].phrases;
+        let notes = |phrase: &Phrase| -> Vec<(NoteType, i32, i32, i32, String)> {
+            phrase
+                .notes
+                .iter()
+                .map(|note| {
+                    (
+                        note.note_type.clone(),
+                        note.start_beat,
+                        note.length,
+                        note.txt_pitch,
+                        note.text.clone(),
+                    )
+                })
+                .collect()
+        };
+
+        assert_eq!(phrases.len(), 2);
+        assert_eq!(phrases[0].disappear_beat, 32);
+        assert_eq!(
+            notes(&phrases[0]),
+            vec![
+                (NoteType::Normal, 0, 8, 0, "Hel".to_string()),
+                (NoteType::Normal, 8, 8, 2, "lo".to_string()),
+                (NoteType::Rap, 16, 4, 4, " rap".to_string()),
+            ]
+        );
+        assert_eq!(phrases[1].disappear_beat, 64);
+        assert_eq!(
+            notes(&phrases[1]),
+            vec![
+                (NoteType::Golden, 32, 8, 5, "gold".to_string()),
+                (NoteType::Rap, 40, 2, 7, " talk".to_string()),
+                (NoteType::Normal, 42, 2, 9, "~".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults_to_120_quarter_notes_per_minute() {
+        let mut vocals = vec![(0, meta(0x03, b"PART VOCALS"))];
+        note(&mut vocals, 30, 30, 60, "One");
+        let (song, diagnostics) = parse(&midi(vec![Vec::new(), vocals]));
+        assert_eq!(song.bpm, 480.0);
+        assert_eq!(song.voices[0].phrases[0].notes[0].start_beat, 1);
+        // Without phrase markers all notes end up in one line
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn requires_a_vocals_track() {
+        let files = ["song.ogg".to_string()];
+        assert!(parse_rockband_song(&midi(vec![Vec::new()]), SONG_INI, &files).is_err());
+    }
+}
@@ -1,7 +1,15 @@
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+use crate::ultrastar::{
+    diagnostics::ParseDiagnostic,
+    encoding::EncodingConfidence,
+    media::{MediaSubstitution, MediaTag},
+    meta::MediaInfo,
+    thumbnail::ThumbnailUrls,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
 pub enum NoteType {
     Normal,
     Golden,
@@ -10,7 +18,7 @@ pub enum NoteType {
     RapGolden,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
 pub struct Note {
     #[serde(rename = "type")]
     pub note_type: NoteType,
@@ -24,23 +32,41 @@ pub struct Note {
     pub midi_note: i32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
 pub struct Phrase {
     #[serde(rename = "disappearBeat")]
     pub disappear_beat: i32,
+    /// Optional second value of the `-` line that ends this phrase, the beat
+    /// at which the following line starts.
+    #[serde(rename = "nextStartBeat")]
+    pub next_start_beat: Option<i32>,
+    /// Sung by both duet singers, from a `P3` section.
+    pub shared: bool,
     pub notes: Vec<Note>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
 pub struct Voice {
+    /// Singer name from `#P<n>`/`#DUETSINGERP<n>`.
+    pub name: Option<String>,
     pub phrases: Vec<Phrase>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+/// A `B <beat> <bpm>` line in the note body, switching the tempo from `beat` on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct TempoChange {
+    pub beat: i32,
+    pub bpm: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
 pub struct Song {
     pub title: String,
     pub artist: String,
     pub bpm: f64,
+    /// Tempo changes sorted by beat. `bpm` applies until the first one.
+    #[serde(rename = "tempoChanges")]
+    pub tempo_changes: Vec<TempoChange>,
     pub gap: f64,
     #[serde(rename = "videoGap")]
     pub video_gap: f64,
@@ -73,9 +99,74 @@ pub struct Song {
     pub medley_start: Option<f64>,
     #[serde(rename = "medleyEnd")]
     pub medley_end: Option<f64>,
+    /// Beats per quarter note from `#RESOLUTION`, only kept for re-saving.
+    pub resolution: Option<i32>,
+    /// `#NOTESGAP`, only kept for re-saving.
+    #[serde(rename = "notesGap")]
+    pub notes_gap: Option<i32>,
+    /// `#CALCMEDLEY`, `false` if the song opts out of finding a medley window.
+    #[serde(rename = "calcMedley")]
+    pub calc_medley: Option<bool>,
+    pub vocals: Option<String>,
+    pub comment: Option<String>,
+    #[serde(rename = "providedBy")]
+    pub provided_by: Option<String>,
+    /// Text encoding declared by `#ENCODING`, legacy files only.
+    pub encoding: Option<String>,
+    /// Where the audio can be found online, from `#AUDIOURL`.
+    #[serde(rename = "audioWebUrl")]
+    pub audio_web_url: Option<String>,
+    #[serde(rename = "videoWebUrl")]
+    pub video_web_url: Option<String>,
+    #[serde(rename = "coverWebUrl")]
+    pub cover_web_url: Option<String>,
+    #[serde(rename = "backgroundWebUrl")]
+    pub background_web_url: Option<String>,
+    /// Header tags without a typed field, in file order, so they survive re-saving.
+    #[serde(rename = "customTags")]
+    pub custom_tags: Vec<(String, String)>,
     pub voices: Vec<Voice>,
 }
 
+/// UltraStar BPM counts quarter notes, so a beat lasts a quarter of `60000 / bpm`.
+fn beats_to_ms(beats: f64, bpm: f64) -> f64 {
+    beats * 60000.0 / (bpm * 4.0)
+}
+
+impl Song {
+    /// Converts a beat to milliseconds from the start of the audio, honoring
+    /// `#GAP` and the tempo changes.
+    pub fn beat_to_ms(&self, beat: f64) -> f64 {
+        let mut ms = self.gap;
+        let mut position = 0.0;
+        let mut bpm = self.bpm;
+
+        for change in &self.tempo_changes {
+            let change_beat = change.beat as f64;
+            if change_beat >= beat {
+                break;
+            }
+            ms += beats_to_ms(change_beat - position, bpm);
+            position = change_beat;
+            bpm = change.bpm;
+        }
+
+        ms + beats_to_ms(beat - position, bpm)
+    }
+
+    /// Inverse of [`Song::beat_to_ms`].
+    pub fn ms_to_beat(&self, ms: f64) -> f64 {
+        let (position, bpm) = self
+            .tempo_changes
+            .iter()
+            .rev()
+            .find(|change| self.beat_to_ms(change.beat as f64) < ms)
+            .map_or((0.0, self.bpm), |change| (change.beat as f64, change.bpm));
+
+        position + (ms - self.beat_to_ms(position)) * bpm * 4.0 / 60000.0
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 pub struct LocalSong {
     #[serde(flatten)]
@@ -88,12 +179,39 @@ pub struct LocalSong {
     pub video_url: Option<String>,
     #[serde(rename = "coverUrl")]
     pub cover_url: Option<String>,
+    #[serde(rename = "coverThumbnailUrls")]
+    pub cover_thumbnail_urls: Option<ThumbnailUrls>,
     #[serde(rename = "backgroundUrl")]
     pub background_url: Option<String>,
+    /// Graphics of a CD+G song, `<cdgUrl>?time=<ms>` returns the frame at
+    /// that time of the audio as a BMP.
+    #[serde(rename = "cdgUrl")]
+    pub cdg_url: Option<String>,
     #[serde(rename = "replayGainTrackGain")]
     pub replay_gain_track_gain: Option<f32>,
     #[serde(rename = "replayGainTrackPeak")]
     pub replay_gain_track_peak: Option<f32>,
     #[serde(rename = "createdAt")]
     pub created_at: Option<f64>,
+    /// Warnings from parsing the txt file and from checking it against its media.
+    pub diagnostics: Vec<ParseDiagnostic>,
+    /// Name of the encoding the txt file was read with, e.g. `windows-1252`.
+    #[serde(rename = "textEncoding")]
+    pub text_encoding: String,
+    #[serde(rename = "textEncodingConfidence")]
+    pub text_encoding_confidence: EncodingConfidence,
+    /// Media files that were not found under the name the song gives and
+    /// were replaced by a similar file in the folder.
+    #[serde(rename = "mediaSubstitutions")]
+    pub media_substitutions: Vec<MediaSubstitution>,
+    /// Media the song does not name, or names but is missing, that was found
+    /// by the conventional file names, like `[CO]` for the cover.
+    #[serde(rename = "inferredMedia")]
+    pub inferred_media: Vec<MediaTag>,
+    /// Properties of the audio file, `None` if it could not be probed.
+    #[serde(rename = "audioInfo")]
+    pub audio_info: Option<MediaInfo>,
+    /// Properties of the video file, which can only be probed for MP4.
+    #[serde(rename = "videoInfo")]
+    pub video_info: Option<MediaInfo>,
 }
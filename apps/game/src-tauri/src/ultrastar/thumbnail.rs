@@ -0,0 +1,226 @@
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    error::AppError,
+    ultrastar::{archive::read_media_file, library_index::file_stamp},
+};
+
+const THUMBNAIL_CACHE_DIR: &str = "thumbnails";
+const JPEG_QUALITY: u8 = 85;
+/// Thumbnails not used for this long are removed when the cache is pruned.
+const MAX_THUMBNAIL_AGE: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+/// The least recently used thumbnails are removed above this total size.
+const MAX_THUMBNAIL_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+/// Temporary files older than this were left by an interrupted write.
+const TEMPORARY_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+static TEMPORARY_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The sizes thumbnails are made in, the longer side in pixels.
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 256, 512];
+
+/// URLs of an image scaled down to each of [`THUMBNAIL_SIZES`], served by the
+/// media server as `/thumb/<size>/<path>`.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct ThumbnailUrls {
+    pub small: String,
+    pub medium: String,
+    pub large: String,
+}
+
+impl ThumbnailUrls {
+    pub fn new(media_base_url: &str, encoded_path: &str) -> Self {
+        let url = |size: u32| format!("{}/thumb/{}/{}", media_base_url, size, encoded_path);
+        Self {
+            small: url(THUMBNAIL_SIZES[0]),
+            medium: url(THUMBNAIL_SIZES[1]),
+            large: url(THUMBNAIL_SIZES[2]),
+        }
+    }
+}
+
+pub fn thumbnail_dir(cache_dir: &Path) -> PathBuf {
+    cache_dir.join(THUMBNAIL_CACHE_DIR)
+}
+
+/// JPEG has no alpha channel, so transparent images are kept as lossless WebP.
+fn encode_thumbnail(
+    image: &DynamicImage,
+) -> Result<(Vec<u8>, &'static str, &'static str), AppError> {
+    let mut data = Vec::new();
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::WebP)?;
+        Ok((data, "webp", "image/webp"))
+    } else {
+        JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).encode_image(&image.to_rgb8())?;
+        Ok((data, "jpg", "image/jpeg"))
+    }
+}
+
+/// Returns an image scaled down to fit `size`, one of [`THUMBNAIL_SIZES`],
+/// and its MIME type. Thumbnails are made on first use and kept in the
+/// cache directory. Their name includes the size and modification time of
+/// the image, so a changed image gets a new thumbnail, and the old one is
+/// left to [`prune_thumbnails`].
+pub fn get_thumbnail(
+    cache_dir: &Path,
+    image_path: &Path,
+    size: u32,
+) -> Result<(Vec<u8>, &'static str), AppError> {
+    if !THUMBNAIL_SIZES.contains(&size) {
+        return Err(AppError::IoError(format!(
+            "Unsupported thumbnail size: {}",
+            size
+        )));
+    }
+
+    let (len, modified_ms) = file_stamp(image_path)
+        .ok_or_else(|| AppError::IoError(format!("Image not found: {}", image_path.display())))?;
+    let name = format!(
+        "{:x}",
+        md5::compute(format!(
+            "{}:{}:{}:{:?}",
+            image_path.display(),
+            size,
+            len,
+            modified_ms
+        ))
+    );
+    let dir = thumbnail_dir(cache_dir);
+    for (extension, mime_type) in [("jpg", "image/jpeg"), ("webp", "image/webp")] {
+        let path = dir.join(format!("{}.{}", name, extension));
+        if let Ok(data) = fs::read(&path) {
+            // The modification time tells when a thumbnail was last used
+            if let Ok(file) = fs::File::options().write(true).open(&path) {
+                let _ = file.set_modified(SystemTime::now());
+            }
+            return Ok((data, mime_type));
+        }
+    }
+
+    let image = image::load_from_memory(&read_media_file(image_path)?)?;
+    // Smaller images are only re-encoded
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+    let (data, extension, mime_type) = encode_thumbnail(&image)?;
+
+    fs::create_dir_all(&dir)?;
+    // The media server can be asked for the same thumbnail more than once at
+    // a time, so each request writes its own temporary file
+    let path = dir.join(format!("{}.{}", name, extension));
+    let temporary_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        extension,
+        TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temporary_path, &data)?;
+    fs::rename(&temporary_path, &path)?;
+
+    Ok((data, mime_type))
+}
+
+/// Removes the thumbnails that were not used for [`MAX_THUMBNAIL_AGE`], then
+/// the least recently used ones until the cache fits in
+/// [`MAX_THUMBNAIL_CACHE_SIZE`]. Temporary files left by an interrupted
+/// write are removed too.
+pub fn prune_thumbnails(cache_dir: &Path) {
+    prune_thumbnail_dir(&thumbnail_dir(cache_dir), MAX_THUMBNAIL_CACHE_SIZE);
+}
+
+fn prune_thumbnail_dir(dir: &Path, max_size: u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    let now = SystemTime::now();
+    let mut thumbnails = Vec::new();
+    let mut total_size = 0;
+    for entry in entries.filter_map(Result::ok) {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let last_used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let age = now.duration_since(last_used).unwrap_or_default();
+        // Temporary files can still be written to by the media server
+        let is_temporary = path.extension().is_some_and(|extension| extension == "tmp");
+        let is_stale = if is_temporary {
+            age > TEMPORARY_FILE_AGE
+        } else {
+            age > MAX_THUMBNAIL_AGE
+        };
+        if is_stale {
+            remove_thumbnail(&path);
+        } else if !is_temporary {
+            total_size += metadata.len();
+            thumbnails.push((last_used, metadata.len(), path));
+        }
+    }
+
+    thumbnails.sort_by_key(|(last_used, _, _)| *last_used);
+    for (_, len, path) in thumbnails {
+        if total_size <= max_size {
+            break;
+        }
+        remove_thumbnail(&path);
+        total_size -= len;
+    }
+}
+
+fn remove_thumbnail(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        log::warn!("Failed to remove thumbnail '{}': {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_thumbnail(dir: &Path, name: &str, len: usize, age: Duration) {
+        let path = dir.join(name);
+        fs::write(&path, vec![0; len]).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn prunes_unused_and_least_recently_used_thumbnails() {
+        let dir =
+            std::env::temp_dir().join(format!("tuneperfect-thumbnails-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        write_thumbnail(&dir, "unused.jpg", 10, MAX_THUMBNAIL_AGE + hour);
+        write_thumbnail(&dir, "oldest.jpg", 10, 3 * hour);
+        write_thumbnail(&dir, "older.webp", 10, 2 * hour);
+        write_thumbnail(&dir, "recent.jpg", 10, Duration::ZERO);
+        write_thumbnail(&dir, "recent.jpg.0.tmp", 100, Duration::ZERO);
+        write_thumbnail(&dir, "left.jpg.1.tmp", 10, TEMPORARY_FILE_AGE + hour);
+
+        prune_thumbnail_dir(&dir, 25);
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        names.sort();
+        assert_eq!(names, ["older.webp", "recent.jpg", "recent.jpg.0.tmp"]);
+    }
+}
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::{
+    error::AppError,
+    ultrastar::song::{NoteType, Song, Voice},
+};
+
+/// A `Line` spans a phrase from its first note to the line break that removes it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Type)]
+pub enum TimelineEventKind {
+    Line,
+    Note,
+}
+
+/// A line or note of a voice with absolute times in milliseconds from the
+/// start of the audio. `phrase` and `note` index into `Voice::phrases` and
+/// `Phrase::notes`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct TimelineEvent {
+    pub kind: TimelineEventKind,
+    pub phrase: u32,
+    pub note: Option<u32>,
+    #[serde(rename = "startBeat")]
+    pub start_beat: i32,
+    #[serde(rename = "endBeat")]
+    pub end_beat: i32,
+    #[serde(rename = "startMs")]
+    pub start_ms: f64,
+    #[serde(rename = "endMs")]
+    pub end_ms: f64,
+    #[serde(rename = "noteType")]
+    pub note_type: Option<NoteType>,
+    #[serde(rename = "midiNote")]
+    pub midi_note: Option<i32>,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct VoiceTimeline {
+    /// Sorted by start time, a line comes before the notes it contains.
+    pub events: Vec<TimelineEvent>,
+}
+
+/// The playable part of a song in milliseconds. `startMs` and `endMs` come
+/// from `#START` and `#END`, or from the audio start and the last event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct SongTimeline {
+    #[serde(rename = "startMs")]
+    pub start_ms: f64,
+    #[serde(rename = "endMs")]
+    pub end_ms: f64,
+    #[serde(rename = "durationMs")]
+    pub duration_ms: f64,
+    pub voices: Vec<VoiceTimeline>,
+}
+
+fn voice_events(song: &Song, voice: &Voice) -> Vec<TimelineEvent> {
+    let mut events = Vec::new();
+
+    for (phrase_index, phrase) in voice.phrases.iter().enumerate() {
+        let start_beat = phrase
+            .notes
+            .first()
+            .map_or(phrase.disappear_beat, |note| note.start_beat);
+        events.push(TimelineEvent {
+            kind: TimelineEventKind::Line,
+            phrase: phrase_index as u32,
+            note: None,
+            start_beat,
+            end_beat: phrase.disappear_beat,
+            start_ms: song.beat_to_ms(start_beat as f64),
+            end_ms: song.beat_to_ms(phrase.disappear_beat as f64),
+            note_type: None,
+            midi_note: None,
+            text: None,
+        });
+
+        for (note_index, note) in phrase.notes.iter().enumerate() {
+            let end_beat = note.start_beat + note.length;
+            events.push(TimelineEvent {
+                kind: TimelineEventKind::Note,
+                phrase: phrase_index as u32,
+                note: Some(note_index as u32),
+                start_beat: note.start_beat,
+                end_beat,
+                start_ms: song.beat_to_ms(note.start_beat as f64),
+                end_ms: song.beat_to_ms(end_beat as f64),
+                note_type: Some(note.note_type.clone()),
+                midi_note: Some(note.midi_note),
+                text: Some(note.text.clone()),
+            });
+        }
+    }
+
+    // Stable, so lines stay in front of their first note
+    events.sort_by(|a, b| a.start_ms.total_cmp(&b.start_ms));
+    events
+}
+
+/// Flattens a song into per-voice events with absolute times. `Song` already
+/// stores `#START` and `#END` in milliseconds whatever the file version, so
+/// events that end before `#START` or begin after `#END` are left out.
+pub fn build_song_timeline(song: &Song) -> Result<SongTimeline, AppError> {
+    if song.bpm <= 0.0 || song.tempo_changes.iter().any(|change| change.bpm <= 0.0) {
+        return Err(AppError::UltrastarError(
+            "Song needs a positive BPM to build a timeline".to_string(),
+        ));
+    }
+
+    let start_ms = song.start.unwrap_or(0.0);
+    let end_limit = song.end.map(|end| end as f64);
+
+    let voices: Vec<VoiceTimeline> = song
+        .voices
+        .iter()
+        .map(|voice| VoiceTimeline {
+            events: voice_events(song, voice)
+                .into_iter()
+                .filter(|event| event.end_ms >= start_ms)
+                .filter(|event| end_limit.is_none_or(|end| event.start_ms <= end))
+                .collect(),
+        })
+        .collect();
+
+    let end_ms = end_limit.unwrap_or_else(|| {
+        voices
+            .iter()
+            .flat_map(|voice| voice.events.iter().map(|event| event.end_ms))
+            .fold(start_ms, f64::max)
+    });
+
+    Ok(SongTimeline {
+        start_ms,
+        end_ms,
+        duration_ms: (end_ms - start_ms).max(0.0),
+        voices,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ultrastar::parser::parse_ultrastar_txt;
+
+    /// 50 ms per beat until beat 10, 100 ms per beat from there on.
+    fn song(headers: &str) -> Song {
+        let content = format!(
+            "#TITLE:Title\n#ARTIST:Artist\n#MP3:song.mp3\n#BPM:300\n#GAP:1000\n{}\
+             : 0 2 0 One\n- 4\n: 10 4 0 Two\nB 10 150\n- 16\n: 30 2 0 Three\nE\n",
+            headers
+        );
+        parse_ultrastar_txt(&content).unwrap().0
+    }
+
+    fn notes(voice: &VoiceTimeline) -> Vec<(i32, f64, f64)> {
+        voice
+            .events
+            .iter()
+            .filter(|event| event.kind == TimelineEventKind::Note)
+            .map(|event| (event.start_beat, event.start_ms, event.end_ms))
+            .collect()
+    }
+
+    #[test]
+    fn follows_tempo_changes() {
+        let timeline = build_song_timeline(&song("")).unwrap();
+        assert_eq!(
+            notes(&timeline.voices[0]),
+            vec![
+                (0, 1000.0, 1100.0),
+                (10, 1500.0, 1900.0),
+                (30, 3500.0, 3700.0)
+            ]
+        );
+        let lines: Vec<(f64, f64)> = timeline.voices[0]
+            .events
+            .iter()
+            .filter(|event| event.kind == TimelineEventKind::Line)
+            .map(|event| (event.start_ms, event.end_ms))
+            .collect();
+        assert_eq!(lines[0], (1000.0, 1200.0));
+        assert_eq!(lines[1], (1500.0, 2100.0));
+        // The last line ends a beat after its last note
+        assert_eq!(lines[2], (3500.0, 3800.0));
+        assert_eq!(timeline.voices[0].events[0].kind, TimelineEventKind::Line);
+        assert_eq!(timeline.start_ms, 0.0);
+        assert_eq!(timeline.end_ms, 3800.0);
+    }
+
+    #[test]
+    fn leaves_out_events_outside_start_and_end() {
+        let timeline = build_song_timeline(&song("#START:1.25\n#END:3000\n")).unwrap();
+        assert_eq!(notes(&timeline.voices[0]), vec![(10, 1500.0, 1900.0)]);
+        assert!(timeline.voices[0]
+            .events
+            .iter()
+            .all(|event| event.phrase == 1));
+        assert_eq!(timeline.start_ms, 1250.0);
+        assert_eq!(timeline.end_ms, 3000.0);
+        assert_eq!(timeline.duration_ms, 1750.0);
+    }
+
+    #[test]
+    fn rejects_non_positive_bpm() {
+        let mut zero_bpm = song("");
+        zero_bpm.bpm = 0.0;
+        assert!(build_song_timeline(&zero_bpm).is_err());
+
+        let mut negative_tempo_change = song("");
+        negative_tempo_change.tempo_changes[0].bpm = -150.0;
+        assert!(build_song_timeline(&negative_tempo_change).is_err());
+    }
+}
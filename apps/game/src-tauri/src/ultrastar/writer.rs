@@ -0,0 +1,589 @@
+use semver::Version;
+use std::fmt::Write;
+
+use crate::{
+    error::AppError,
+    ultrastar::song::{NoteType, Phrase, Song, TempoChange, Voice},
+};
+
+fn note_type_to_tag(note_type: &NoteType) -> &'static str {
+    match note_type {
+        NoteType::Normal => ":",
+        NoteType::Golden => "*",
+        NoteType::Freestyle => "F",
+        NoteType::Rap => "R",
+        NoteType::RapGolden => "G",
+    }
+}
+
+fn format_multi_value_field(
+    values: &[String],
+    property: &str,
+    supports_multi_value: bool,
+) -> Result<String, AppError> {
+    if supports_multi_value {
+        if let Some(value) = values.iter().find(|value| value.contains(',')) {
+            return Err(AppError::UltrastarError(format!(
+                "Value for {} must not contain a comma: {}",
+                property, value
+            )));
+        }
+        Ok(values.join(", "))
+    } else if values.len() > 1 {
+        Err(AppError::UltrastarError(format!(
+            "Multiple values for {} require version 1.1.0 or later",
+            property
+        )))
+    } else {
+        Ok(values.join(""))
+    }
+}
+
+/// Formats a millisecond value the way the parser expects it for the given
+/// version. Versions before 2.0.0 store seconds, so the written value is
+/// nudged by a few ulps until reading it back yields the exact same number.
+fn format_time_value(value: f64, uses_milliseconds: bool) -> String {
+    if uses_milliseconds {
+        return value.to_string();
+    }
+
+    let seconds = value / 1000.0;
+    (0..=8)
+        .flat_map(|step: i64| [step, -step])
+        .map(|step| f64::from_bits((seconds.to_bits() as i64 + step) as u64))
+        .find(|candidate| {
+            candidate.is_finite()
+                && candidate
+                    .to_string()
+                    .parse::<f64>()
+                    .ok()
+                    .map(|s| s * 1000.0)
+                    == Some(value)
+        })
+        .unwrap_or(seconds)
+        .to_string()
+}
+
+/// Whether the `-` line ending the last phrase of a voice has to be written.
+/// The parser closes a voice's last phrase implicitly at `last note end + 1`,
+/// so the line is only needed when that default would not reproduce it.
+fn needs_trailing_line_break(phrase: &Phrase) -> bool {
+    match phrase.notes.last() {
+        Some(last_note) => {
+            phrase.next_start_beat.is_some()
+                || phrase.disappear_beat != last_note.start_beat + last_note.length + 1
+        }
+        None => true,
+    }
+}
+
+/// Writes the notes and line breaks of a voice's phrases, with the tempo changes placed
+/// in front of the first note they affect. Beats are stored absolute on
+/// [`Song`], so for relative songs they are converted back to offsets from the
+/// start of the current line.
+fn write_voice(
+    output: &mut String,
+    phrases: &[Phrase],
+    tempo_changes: &[TempoChange],
+    relative: bool,
+) {
+    let mut beat_offset = 0;
+    let mut tempo_changes = tempo_changes.iter().peekable();
+
+    for (index, phrase) in phrases.iter().enumerate() {
+        for note in &phrase.notes {
+            while let Some(change) = tempo_changes.next_if(|change| change.beat <= note.start_beat)
+            {
+                let _ = writeln!(output, "B {} {}", change.beat - beat_offset, change.bpm);
+            }
+            let _ = writeln!(
+                output,
+                "{} {} {} {} {}",
+                note_type_to_tag(&note.note_type),
+                note.start_beat - beat_offset,
+                note.length,
+                note.txt_pitch,
+                note.text
+            );
+        }
+
+        let is_last = index == phrases.len() - 1;
+        if !is_last || needs_trailing_line_break(phrase) {
+            let disappear_beat = phrase.disappear_beat - beat_offset;
+            match phrase.next_start_beat {
+                Some(next_start_beat) => {
+                    let _ = writeln!(
+                        output,
+                        "- {} {}",
+                        disappear_beat,
+                        next_start_beat - beat_offset
+                    );
+                }
+                None => {
+                    let _ = writeln!(output, "- {}", disappear_beat);
+                }
+            }
+
+            if relative {
+                beat_offset = phrase.next_start_beat.unwrap_or(phrase.disappear_beat);
+            }
+        }
+    }
+
+    for change in tempo_changes {
+        let _ = writeln!(output, "B {} {}", change.beat - beat_offset, change.bpm);
+    }
+}
+
+/// Serializes a [`Song`] into the UltraStar txt format of its `version`.
+///
+/// The output is written in canonical form. Parsing it with
+/// [`parse_ultrastar_txt`](crate::ultrastar::parser::parse_ultrastar_txt)
+/// yields the same song, hash included, however the song was formatted.
+/// Duets are written as `P1`, `P2` and a shared `P3` section, songs with more
+/// voices number them `P1` to `P<n>`.
+pub fn write_ultrastar_txt(song: &Song) -> Result<String, AppError> {
+    let version_str = song.version.as_deref().unwrap_or("1.0.0");
+    let version = Version::parse(version_str)
+        .map_err(|_| AppError::UltrastarError(format!("Invalid version: {}", version_str)))?;
+    let supports_multi_value = version >= Version::parse("1.1.0").unwrap();
+    let uses_milliseconds = version >= Version::parse("2.0.0").unwrap();
+
+    let mut output = String::new();
+    let mut header = |property: &str, value: &str| {
+        let _ = writeln!(output, "#{}:{}", property, value);
+    };
+
+    header("VERSION", version_str);
+    header("TITLE", &song.title);
+    header("ARTIST", &song.artist);
+
+    if let Some(album) = &song.album {
+        header("ALBUM", album);
+    }
+
+    let multi_value_fields = [
+        ("LANGUAGE", &song.language),
+        ("EDITION", &song.edition),
+        ("GENRE", &song.genre),
+        ("TAGS", &song.tags),
+        ("CREATOR", &song.creator),
+    ];
+    for (property, values) in multi_value_fields {
+        if let Some(values) = values.as_ref().filter(|values| !values.is_empty()) {
+            header(
+                property,
+                &format_multi_value_field(values, property, supports_multi_value)?,
+            );
+        }
+    }
+
+    if let Some(year) = song.year {
+        header("YEAR", &year.to_string());
+    }
+    if let Some(audio) = &song.audio {
+        // 1.1.0 introduced #AUDIO, #MP3 is kept for older players until 2.0.0
+        if version < Version::parse("2.0.0").unwrap() {
+            header("MP3", audio);
+        }
+        if supports_multi_value {
+            header("AUDIO", audio);
+        }
+    }
+    if let Some(instrumental) = &song.instrumental {
+        header("INSTRUMENTAL", instrumental);
+    }
+    if let Some(cover) = &song.cover {
+        header("COVER", cover);
+    }
+    if let Some(background) = &song.background {
+        header("BACKGROUND", background);
+    }
+    if let Some(video) = &song.video {
+        header("VIDEO", video);
+    }
+    if song.video_gap != 0.0 {
+        header(
+            "VIDEOGAP",
+            &format_time_value(song.video_gap, uses_milliseconds),
+        );
+    }
+
+    header("BPM", &song.bpm.to_string());
+    header("GAP", &song.gap.to_string());
+    if let Some(resolution) = song.resolution {
+        header("RESOLUTION", &resolution.to_string());
+    }
+    if let Some(notes_gap) = song.notes_gap {
+        header("NOTESGAP", &notes_gap.to_string());
+    }
+
+    if let Some(start) = song.start {
+        header("START", &format_time_value(start, uses_milliseconds));
+    }
+    if let Some(end) = song.end {
+        header("END", &end.to_string());
+    }
+    if let Some(preview_start) = song.preview_start {
+        header(
+            "PREVIEWSTART",
+            &format_time_value(preview_start, uses_milliseconds),
+        );
+    }
+    if let Some(medley_start_beat) = song.medley_start_beat {
+        header("MEDLEYSTARTBEAT", &medley_start_beat.to_string());
+    }
+    if let Some(medley_end_beat) = song.medley_end_beat {
+        header("MEDLEYENDBEAT", &medley_end_beat.to_string());
+    }
+    if let Some(medley_start) = song.medley_start {
+        header(
+            "MEDLEYSTART",
+            &format_time_value(medley_start, uses_milliseconds),
+        );
+    }
+    if let Some(medley_end) = song.medley_end {
+        header(
+            "MEDLEYEND",
+            &format_time_value(medley_end, uses_milliseconds),
+        );
+    }
+    if let Some(calc_medley) = song.calc_medley {
+        header("CALCMEDLEY", if calc_medley { "on" } else { "off" });
+    }
+    if song.relative == Some(true) {
+        header("RELATIVE", "yes");
+    }
+    if let Some(p1) = &song.p1 {
+        header("P1", p1);
+    }
+    if let Some(p2) = &song.p2 {
+        header("P2", p2);
+    }
+    // Naming a third singer, even without a name, makes the parser read `P3`
+    // as the third voice instead of the shared section of a duet
+    for (index, voice) in song.voices.iter().enumerate().skip(2) {
+        header(
+            &format!("P{}", index + 1),
+            voice.name.as_deref().unwrap_or_default(),
+        );
+    }
+
+    let optional_fields = [
+        ("VOCALS", &song.vocals),
+        ("COMMENT", &song.comment),
+        ("PROVIDEDBY", &song.provided_by),
+        ("ENCODING", &song.encoding),
+        ("AUDIOURL", &song.audio_web_url),
+        ("VIDEOURL", &song.video_web_url),
+        ("COVERURL", &song.cover_web_url),
+        ("BACKGROUNDURL", &song.background_web_url),
+    ];
+    for (property, value) in optional_fields {
+        if let Some(value) = value {
+            header(property, value);
+        }
+    }
+    for (property, value) in &song.custom_tags {
+        header(property, value);
+    }
+
+    let relative = song.relative == Some(true);
+    if song.voices.len() > 1 {
+        let sections: Vec<Vec<Phrase>> = if let [first, second] = song.voices.as_slice() {
+            // Duets keep the lines sung by both singers in a shared `P3` section
+            let phrases = |voice: &Voice, shared: bool| {
+                voice
+                    .phrases
+                    .iter()
+                    .filter(|phrase| phrase.shared == shared)
+                    .cloned()
+                    .collect()
+            };
+            vec![
+                phrases(first, false),
+                phrases(second, false),
+                phrases(first, true),
+            ]
+        } else {
+            song.voices
+                .iter()
+                .map(|voice| voice.phrases.clone())
+                .collect()
+        };
+        // Tempo changes apply to the whole song, so only the first section carries them
+        let mut tempo_changes = &song.tempo_changes[..];
+        for (index, phrases) in sections.iter().enumerate() {
+            if phrases.is_empty() {
+                continue;
+            }
+            let _ = writeln!(output, "P{}", index + 1);
+            write_voice(&mut output, phrases, tempo_changes, relative);
+            tempo_changes = &[];
+        }
+    } else {
+        let phrases = song.voices.first().map_or(&[][..], |voice| &voice.phrases);
+        write_voice(&mut output, phrases, &song.tempo_changes, relative);
+    }
+
+    output.push_str("E\n");
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ultrastar::parser::parse_ultrastar_txt;
+
+    /// Parses a canonically formatted file, checks that writing it back
+    /// gives the same text and that parsing the output gives the same song.
+    fn assert_round_trip(content: &str) -> Song {
+        let (song, diagnostics) = parse_ultrastar_txt(content).unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let written = write_ultrastar_txt(&song).unwrap();
+        assert_eq!(written, content);
+        let (reparsed, _) = parse_ultrastar_txt(&written).unwrap();
+        assert_eq!(reparsed, song);
+        song
+    }
+
+    /// Parses a file in any format, checks that parsing the written song
+    /// gives the same song and returns the written text.
+    fn assert_song_round_trip(content: &str) -> String {
+        let (song, _) = parse_ultrastar_txt(content).unwrap();
+        let written = write_ultrastar_txt(&song).unwrap();
+        let (reparsed, diagnostics) = parse_ultrastar_txt(&written).unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(reparsed, song);
+        written
+    }
+
+    #[test]
+    fn round_trips_version_1_0_0() {
+        let song = assert_round_trip(
+            "#VERSION:1.0.0\n\
+             #TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #LANGUAGE:English\n\
+             #YEAR:1999\n\
+             #MP3:song.mp3\n\
+             #COVER:cover.jpg\n\
+             #VIDEO:video.mp4\n\
+             #VIDEOGAP:1.5\n\
+             #BPM:300\n\
+             #GAP:1000\n\
+             #RESOLUTION:4\n\
+             #NOTESGAP:0\n\
+             #START:12.5\n\
+             #PREVIEWSTART:30.25\n\
+             #CALCMEDLEY:off\n\
+             : 0 4 5 Hel\n\
+             * 4 4 7 lo\n\
+             - 10\n\
+             F 12 2 0  world\n\
+             R 16 2 0  rap\n\
+             G 20 2 -3  gold\n\
+             E\n",
+        );
+        assert_eq!(song.start, Some(12500.0));
+        assert_eq!(song.video_gap, 1500.0);
+        assert_eq!(song.calc_medley, Some(false));
+    }
+
+    #[test]
+    fn round_trips_version_1_1_0() {
+        let song = assert_round_trip(
+            "#VERSION:1.1.0\n\
+             #TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #LANGUAGE:English, German\n\
+             #GENRE:Pop\n\
+             #CREATOR:First, Second\n\
+             #MP3:song.mp3\n\
+             #AUDIO:song.mp3\n\
+             #INSTRUMENTAL:instrumental.mp3\n\
+             #BPM:240.5\n\
+             #GAP:500\n\
+             #MEDLEYSTARTBEAT:4\n\
+             #MEDLEYENDBEAT:12\n\
+             #COMMENT:Comment\n\
+             : 0 2 0 One\n\
+             : 4 2 2  two\n\
+             - 8 10\n\
+             : 10 2 4 Three\n\
+             E\n",
+        );
+        assert_eq!(
+            song.language,
+            Some(vec!["English".to_string(), "German".to_string()])
+        );
+    }
+
+    #[test]
+    fn round_trips_version_2_0_0() {
+        let song = assert_round_trip(
+            "#VERSION:2.0.0\n\
+             #TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #TAGS:Live\n\
+             #AUDIO:song.ogg\n\
+             #VIDEOGAP:250\n\
+             #BPM:200\n\
+             #GAP:1200\n\
+             #START:8000\n\
+             #END:60000\n\
+             #MEDLEYSTARTBEAT:115\n\
+             #MEDLEYENDBEAT:355\n\
+             #MEDLEYSTART:15000\n\
+             #MEDLEYEND:45000\n\
+             #PROVIDEDBY:Provider\n\
+             : 0 2 0 One\n\
+             B 4 150\n\
+             : 4 2 2  two\n\
+             - 8\n\
+             : 10 2 4 Three\n\
+             B 20 120\n\
+             E\n",
+        );
+        assert_eq!(song.tempo_changes.len(), 2);
+        assert_eq!(song.video_gap, 250.0);
+    }
+
+    #[test]
+    fn round_trips_duets_with_shared_sections() {
+        let song = assert_round_trip(
+            "#VERSION:1.0.0\n\
+             #TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #MP3:song.mp3\n\
+             #BPM:300\n\
+             #GAP:0\n\
+             #P1:First\n\
+             #P2:Second\n\
+             P1\n\
+             : 0 2 0 One\n\
+             - 4\n\
+             : 20 2 0 Four\n\
+             P2\n\
+             : 6 2 0 Two\n\
+             P3\n\
+             : 12 2 0 Three\n\
+             E\n",
+        );
+        assert_eq!(song.voices.len(), 2);
+        assert_eq!(song.voices[0].phrases.len(), 3);
+        assert_eq!(song.voices[1].phrases.len(), 2);
+        assert!(song.voices[0].phrases[1].shared);
+        assert_eq!(song.voices[0].phrases[1], song.voices[1].phrases[1]);
+    }
+
+    #[test]
+    fn round_trips_more_than_two_voices() {
+        let song = assert_round_trip(
+            "#VERSION:1.0.0\n\
+             #TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #MP3:song.mp3\n\
+             #BPM:300\n\
+             #GAP:0\n\
+             #P1:First\n\
+             #P3:\n\
+             P1\n\
+             : 0 2 0 One\n\
+             P2\n\
+             : 0 2 4 One\n\
+             P3\n\
+             : 0 2 7 One\n\
+             E\n",
+        );
+        assert_eq!(song.voices.len(), 3);
+        assert_eq!(song.voices[2].name, None);
+    }
+
+    #[test]
+    fn round_trips_relative_songs() {
+        let song = assert_round_trip(
+            "#VERSION:1.0.0\n\
+             #TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #MP3:song.mp3\n\
+             #BPM:300\n\
+             #GAP:0\n\
+             #RELATIVE:yes\n\
+             : 0 2 0 One\n\
+             - 4 6\n\
+             : 0 2 0 Two\n\
+             B 2 150\n\
+             : 2 2 0  three\n\
+             - 6\n\
+             : 1 2 0 Four\n\
+             E\n",
+        );
+        let starts: Vec<i32> = song.voices[0]
+            .phrases
+            .iter()
+            .flat_map(|phrase| phrase.notes.iter().map(|note| note.start_beat))
+            .collect();
+        assert_eq!(starts, vec![0, 6, 8, 13]);
+        assert_eq!(song.tempo_changes[0].beat, 8);
+    }
+
+    #[test]
+    fn round_trips_non_canonical_files() {
+        let written = assert_song_round_trip(
+            "#title: Title \n\
+             #Artist:Artist\n\
+             #MP3:song.mp3\n\
+             #BPM:300,0\n\
+             #GAP:0\n\
+             : 000 02 05 One\n\
+                : 4 2 7  two\n\
+             -  008  \n\
+             * 010 2 -01 Three\n\
+             -12 14\n\
+             : 14 2 0 Four\n\
+             E\n",
+        );
+        assert!(written.contains(": 0 2 5 One\n: 4 2 7  two\n- 8\n* 10 2 -1 Three\n- 12 14\n"));
+    }
+
+    #[test]
+    fn round_trips_single_voice_with_player_line() {
+        let written = assert_song_round_trip(
+            "#TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #MP3:song.mp3\n\
+             #BPM:300\n\
+             #GAP:0\n\
+             P1\n\
+             : 0 2 0 One\n\
+             - 4\n\
+             : 6 2 0 Two\n\
+             E\n",
+        );
+        assert!(!written.contains("P1"));
+    }
+
+    #[test]
+    fn keeps_the_hash_when_writing_relative_songs_as_absolute() {
+        let (mut song, _) = parse_ultrastar_txt(
+            "#TITLE:Title\n\
+             #ARTIST:Artist\n\
+             #MP3:song.mp3\n\
+             #BPM:300\n\
+             #GAP:0\n\
+             #RELATIVE:yes\n\
+             : 0 2 0 One\n\
+             - 4 6\n\
+             : 0 2 0 Two\n\
+             E\n",
+        )
+        .unwrap();
+        song.relative = Some(false);
+        let (reparsed, _) = parse_ultrastar_txt(&write_ultrastar_txt(&song).unwrap()).unwrap();
+        assert_eq!(reparsed.hash, song.hash);
+        assert_eq!(reparsed.voices, song.voices);
+    }
+}
@@ -6,6 +6,7 @@ use tauri::AppHandle;
 use tauri_specta::Event;
 
 use crate::error::AppError;
+use crate::ultrastar::diagnostics::ParseDiagnostic;
 use crate::ultrastar::parser::parse_ultrastar_txt;
 use crate::usdb::commands::UsdbSyncProgressEvent;
 use crate::usdb::models::{UsdbSearchEntry, UsdbSong, UsdbSongPreview};
@@ -368,7 +369,7 @@ impl UsdbClient {
             }
         }
 
-        let mut song = parse_ultrastar_txt(&txt_content).or_else(|e| {
+        let (mut song, _) = parse_ultrastar_txt(&txt_content).or_else(|e| {
             log::warn!("Standard parsing failed, trying relaxed parse: {}", e);
             parse_ultrastar_txt_relaxed(&txt_content)
         })?;
@@ -483,7 +484,7 @@ fn extract_audio_video_ids(value: &str) -> (Option<String>, Option<String>) {
 /// Injects a dummy #AUDIO tag so the parser doesn't reject songs without audio/video.
 fn parse_ultrastar_txt_relaxed(
     content: &str,
-) -> Result<crate::ultrastar::song::Song, AppError> {
+) -> Result<(crate::ultrastar::song::Song, Vec<ParseDiagnostic>), AppError> {
     let mut modified_content = String::new();
     let mut has_audio = false;
     let mut has_video = false;
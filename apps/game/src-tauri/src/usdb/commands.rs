@@ -9,6 +9,7 @@ use crate::AppState;
 
 /// Emitted after each catalog page is fetched so the UI can show sync progress.
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+#[serde(rename_all = "camelCase")]
 pub struct UsdbSyncProgressEvent {
     pub fetched: u32,
     pub total: u32,
@@ -11,7 +11,47 @@ export const commands = {
 	getPitches: (windowMs: number | null) => typedError<(number | null)[], AppError>(__TAURI_INVOKE("get_pitches", { windowMs })),
 	getAudioLevels: () => typedError<(number | null)[], AppError>(__TAURI_INVOKE("get_audio_levels")),
 	getMediaServerBaseUrl: () => typedError<string | null, string>(__TAURI_INVOKE("get_media_server_base_url")),
-	parseSongsFromPaths: (paths: string[]) => typedError<SongGroup[], AppError>(__TAURI_INVOKE("parse_songs_from_paths", { paths })),
+	parseSongsFromPaths: (paths: string[], maxDepth: number | null) => typedError<SongScanReport, AppError>(__TAURI_INVOKE("parse_songs_from_paths", { paths, maxDepth })),
+	/**
+	 *  Stops the running `parse_songs_from_paths` scan, which then returns the
+	 *  songs parsed so far.
+	 */
+	cancelSongScan: () => typedError<null, AppError>(__TAURI_INVOKE("cancel_song_scan")),
+	/**  Writes a song back to an UltraStar txt file, e.g. after fixing it in the UI. */
+	saveSongTxt: (path: string, song: Song) => typedError<null, AppError>(__TAURI_INVOKE("save_song_txt", { path, song })),
+	/**
+	 *  Rewrites a song's txt file as UTF-8, keeping the original next to it as
+	 *  `<file>.bak`. Returns the path of the backup, or `None` if the file was
+	 *  already UTF-8.
+	 */
+	convertSongTxtToUtf8: (path: string) => typedError<string | null, AppError>(__TAURI_INVOKE("convert_song_txt_to_utf8", { path })),
+	/**  Turns a song into per-voice line and note events with absolute times in milliseconds. */
+	getSongTimeline: (song: Song) => typedError<SongTimeline, AppError>(__TAURI_INVOKE("get_song_timeline", { song })),
+	/**  Renders the lyrics of a song as LRC, enhanced LRC, SRT, WebVTT or ASS. */
+	exportSongLyrics: (source: LyricsSource, format: LyricsFormat) => typedError<string, AppError>(__TAURI_INVOKE("export_song_lyrics", { source, format })),
+	/**  Writes the vocal notes of a song to a Standard MIDI File, one track per voice. */
+	exportSongMidi: (song: Song, path: string) => typedError<null, AppError>(__TAURI_INVOKE("export_song_midi", { song, path })),
+	/**  Lints single song files or every song below the given directories. */
+	lintSongs: (paths: string[]) => typedError<SongLintReport[], AppError>(__TAURI_INVOKE("lint_songs", { paths })),
+	/**
+	 *  Watches the song paths for songs being added, changed or removed and emits
+	 *  `SongAddedEvent`, `SongUpdatedEvent` and `SongRemovedEvent` for them.
+	 *  Replaces the paths watched before.
+	 */
+	watchSongPaths: (paths: string[], maxDepth: number | null) => typedError<null, AppError>(__TAURI_INVOKE("watch_song_paths", { paths, maxDepth })),
+	stopWatchingSongPaths: () => typedError<null, AppError>(__TAURI_INVOKE("stop_watching_song_paths")),
+	/**
+	 *  Measures the loudness of the songs below the given paths that have no
+	 *  ReplayGain tags per EBU R128 and stores it as their ReplayGain values in
+	 *  the library index. Songs are analysed one at a time, so the game stays
+	 *  responsive while it runs in the background.
+	 */
+	analyzeSongLoudness: (paths: string[]) => typedError<LoudnessAnalysisReport, AppError>(__TAURI_INVOKE("analyze_song_loudness", { paths })),
+	/**
+	 *  Stops the running `analyze_song_loudness` and returns once it saved the
+	 *  songs analysed so far.
+	 */
+	cancelLoudnessAnalysis: () => typedError<null, AppError>(__TAURI_INVOKE("cancel_loudness_analysis")),
 	webrtcCreateAnswer: (userId: string, offerSdp: string, iceServers: IceServerConfig_Deserialize[]) => typedError<string, AppError>(__TAURI_INVOKE("webrtc_create_answer", { userId, offerSdp, iceServers })),
 	webrtcAddIceCandidate: (userId: string, candidate: string) => typedError<null, AppError>(__TAURI_INVOKE("webrtc_add_ice_candidate", { userId, candidate })),
 	webrtcSendMessage: (userId: string, label: string, data: string) => typedError<null, AppError>(__TAURI_INVOKE("webrtc_send_message", { userId, label, data })),
@@ -33,13 +73,17 @@ export const events = {
 	channelOpenEvent: makeEvent<ChannelOpenEvent>("channel-open-event"),
 	connectionStateEvent: makeEvent<ConnectionStateEvent>("connection-state-event"),
 	iceCandidateEvent: makeEvent<IceCandidateEvent>("ice-candidate-event"),
+	loudnessProgressEvent: makeEvent<LoudnessProgressEvent>("loudness-progress-event"),
 	progressEvent: makeEvent<ProgressEvent>("progress-event"),
+	songAddedEvent: makeEvent<SongAddedEvent>("song-added-event"),
+	songRemovedEvent: makeEvent<SongRemovedEvent>("song-removed-event"),
+	songUpdatedEvent: makeEvent<SongUpdatedEvent>("song-updated-event"),
 	startParsingEvent: makeEvent<StartParsingEvent>("start-parsing-event"),
 	usdbSyncProgressEvent: makeEvent<UsdbSyncProgressEvent>("usdb-sync-progress-event"),
 };
 
 /* Types */
-export type AppError = { type: "IoError"; data: string } | { type: "LoftyError"; data: string } | { type: "RecorderError"; data: string } | { type: "ProcessorError"; data: string } | { type: "CpalError"; data: string } | { type: "UltrastarError"; data: string } | { type: "WebRTCError"; data: string } | { type: "UsdbError"; data: string };
+export type AppError = { type: "IoError"; data: string } | { type: "LoftyError"; data: string } | { type: "RecorderError"; data: string } | { type: "ProcessorError"; data: string } | { type: "CpalError"; data: string } | { type: "UltrastarError"; data: string } | { type: "UltrastarParseError"; data: ParseDiagnostic } | { type: "WebRTCError"; data: string } | { type: "UsdbError"; data: string } | { type: "ArchiveError"; data: string } | { type: "WatcherError"; data: string } | { type: "DecodeError"; data: string } | { type: "ImageError"; data: string };
 
 export type ChannelCloseEvent = {
 	userId: string,
@@ -62,6 +106,22 @@ export type ConnectionStateEvent = {
 	state: string,
 };
 
+export type DiagnosticCode = "InvalidVersion" | "InvalidNumber" | "MissingTitle" | "MissingArtist" | "MissingBpm" | "MissingEnd" | "MissingMedia" | "MalformedHeader" | "UnknownNoteType" | "IncompleteLine" | "UnknownLine" | "ContentAfterEnd" | "EmptyMediaFile" | "NotesAfterAudioEnd" | "StartAfterAudioEnd";
+
+/**
+ *  `Error` means the song could not be parsed, `Warning` that part of the
+ *  file was ignored or guessed.
+ */
+export type DiagnosticSeverity = "Error" | "Warning";
+
+/**
+ *  How the text encoding of a txt file was determined. `Certain` means a byte
+ *  order mark, plain ASCII or valid UTF-8, `Declared` that it was taken from the
+ *  `#ENCODING` header and `Guessed` that it was guessed from the bytes, which
+ *  can go wrong for short files with few special characters.
+ */
+export type EncodingConfidence = "Certain" | "Declared" | "Guessed";
+
 export type IceCandidateEvent = {
 	userId: string,
 	candidate: string,
@@ -83,17 +143,130 @@ export type IceServerConfig_Serialize = {
 
 export type IceServerUrls = string | string[];
 
+export type LintCategory = "Timing" | "Pitch" | "Structure";
+
+export type LintCode = "OverlappingNotes" | "NotesOutOfOrder" | "ZeroLengthNote" | "EarlyLineBreak" | "PitchOutlier" | "MissingEnd";
+
+/**
+ *  A musical or structural problem in a parsed song. Indices point into
+ *  `Song::voices`, `Voice::phrases` and `Phrase::notes`.
+ */
+export type LintFinding = {
+	category: LintCategory,
+	code: LintCode,
+	voice: number | null,
+	phrase: number | null,
+	note: number | null,
+	beat: number | null,
+	message: string,
+};
+
 export type LocalSong = {
 	audioUrl: string | null,
 	instrumentalUrl: string | null,
 	videoUrl: string | null,
 	coverUrl: string | null,
+	coverThumbnailUrls: ThumbnailUrls | null,
 	backgroundUrl: string | null,
+	/**
+	 *  Graphics of a CD+G song, `<cdgUrl>?time=<ms>` returns the frame at
+	 *  that time of the audio as a BMP.
+	 */
+	cdgUrl: string | null,
 	replayGainTrackGain: number | null,
 	replayGainTrackPeak: number | null,
 	createdAt: number | null,
+	/**  Warnings from parsing the txt file and from checking it against its media. */
+	diagnostics: ParseDiagnostic[],
+	/**  Name of the encoding the txt file was read with, e.g. `windows-1252`. */
+	textEncoding: string,
+	textEncodingConfidence: EncodingConfidence,
+	/**
+	 *  Media files that were not found under the name the song gives and
+	 *  were replaced by a similar file in the folder.
+	 */
+	mediaSubstitutions: MediaSubstitution[],
+	/**
+	 *  Media the song does not name, or names but is missing, that was found
+	 *  by the conventional file names, like `[CO]` for the cover.
+	 */
+	inferredMedia: MediaTag[],
+	/**  Properties of the audio file, `None` if it could not be probed. */
+	audioInfo: MediaInfo | null,
+	/**  Properties of the video file, which can only be probed for MP4. */
+	videoInfo: MediaInfo | null,
 } & Song;
 
+export type LoudnessAnalysisReport = {
+	analyzed: number,
+	failures: SongScanFailure[],
+	/**
+	 *  Set if the analysis was cancelled, the songs analysed until then keep
+	 *  their values.
+	 */
+	cancelled: boolean,
+};
+
+/**
+ *  Sent after each song of a loudness analysis. The gain and peak are `None`
+ *  if the song's audio could not be analysed.
+ */
+export type LoudnessProgressEvent = {
+	hash: string,
+	replayGainTrackGain: number | null,
+	replayGainTrackPeak: number | null,
+	processed: number,
+	total: number,
+};
+
+/**
+ *  `Lrc` has one timestamp per line, `EnhancedLrc` adds one per syllable,
+ *  `Srt` and `WebVtt` have one cue per line and `Ass` has karaoke timing
+ *  with `\k` tags.
+ */
+export type LyricsFormat = "Lrc" | "EnhancedLrc" | "Srt" | "WebVtt" | "Ass";
+
+/**  A song to export lyrics for, either already parsed or as the path of its song file. */
+export type LyricsSource = { type: "Song"; data: Song } | { type: "Path"; data: string };
+
+/**  Stream properties of an audio or video file. */
+export type MediaInfo = {
+	durationMs: number | null,
+	sampleRate: number | null,
+	channels: number | null,
+	/**
+	 *  The container format of the file, e.g. `Mpeg`, `Opus`, `Flac` or
+	 *  `Mp4`. This is not the codec, an `Mp4` file can hold AAC or ALAC.
+	 */
+	container: string,
+};
+
+/**
+ *  Why a file was used in place of the one named in the song, from the
+ *  most to the least certain.
+ */
+export type MediaMatch = 
+/**  Same name with another extension, e.g. `song.m4a` for `song.mp3`. */
+"OtherExtension" | 
+/**  The only audio file in the folder. */
+"OnlyAudioFile" | 
+/**  Same name when ignoring case, whitespace and punctuation. */
+"SimilarName";
+
+/**
+ *  A media file the song names that was not found, and the file used instead.
+ *  The UI can offer to rewrite the tag to `found`.
+ */
+export type MediaSubstitution = {
+	tag: MediaTag,
+	specified: string,
+	found: string,
+	match: MediaMatch,
+};
+
+/**  The header tag a media file was named in. */
+export type MediaTag = "Audio" | "Instrumental" | "Video" | "Cover" | "Background";
+
 export type Microphone = {
 	/**
 	 *  Stable device ID (cpal `DeviceId` serialized via `Display`). Preferred for
@@ -138,19 +311,43 @@ export type Note = {
 
 export type NoteType = "Normal" | "Golden" | "Freestyle" | "Rap" | "RapGolden";
 
+/**  A problem found while parsing an UltraStar txt file. */
+export type ParseDiagnostic = {
+	severity: DiagnosticSeverity,
+	/**  1-based line number, `None` for problems with the song as a whole. */
+	line: number | null,
+	code: DiagnosticCode,
+	message: string,
+};
+
 export type Phrase = {
 	disappearBeat: number,
+	/**
+	 *  Optional second value of the `-` line that ends this phrase, the beat
+	 *  at which the following line starts.
+	 */
+	nextStartBeat: number | null,
+	/**  Sung by both duet singers, from a `P3` section. */
+	shared: boolean,
 	notes: Note[],
 };
 
+/**  Sent at most every `PROGRESS_INTERVAL` while songs are parsed. */
 export type ProgressEvent = {
+	/**  The song parsed last. */
 	song: string,
+	processed: number,
+	total: number,
+	/**  Estimated time until all songs are parsed. */
+	etaMs: number,
 };
 
 export type Song = {
 	title: string,
 	artist: string,
 	bpm: number | null,
+	/**  Tempo changes sorted by beat. `bpm` applies until the first one. */
+	tempoChanges: TempoChange[],
 	gap: number | null,
 	videoGap: number | null,
 	start: number | null,
@@ -177,18 +374,144 @@ export type Song = {
 	medleyEndBeat: number | null,
 	medleyStart: number | null,
 	medleyEnd: number | null,
+	/**  Beats per quarter note from `#RESOLUTION`, only kept for re-saving. */
+	resolution: number | null,
+	/**  `#NOTESGAP`, only kept for re-saving. */
+	notesGap: number | null,
+	/**  `#CALCMEDLEY`, `false` if the song opts out of finding a medley window. */
+	calcMedley: boolean | null,
+	vocals: string | null,
+	comment: string | null,
+	providedBy: string | null,
+	/**  Text encoding declared by `#ENCODING`, legacy files only. */
+	encoding: string | null,
+	/**  Where the audio can be found online, from `#AUDIOURL`. */
+	audioWebUrl: string | null,
+	videoWebUrl: string | null,
+	coverWebUrl: string | null,
+	backgroundWebUrl: string | null,
+	/**  Header tags without a typed field, in file order, so they survive re-saving. */
+	customTags: ([string, string])[],
 	voices: Voice[],
 };
 
+/**
+ *  A song was added below one of the watched paths. `group_path` is the
+ *  watched path it belongs to, like `SongGroup::path`.
+ */
+export type SongAddedEvent = {
+	groupPath: string,
+	path: string,
+	song: LocalSong,
+};
+
 export type SongGroup = {
 	path: string,
 	songs: LocalSong[],
 };
 
+export type SongLintReport = {
+	path: string,
+	findings: LintFinding[],
+	/**
+	 *  Set if the song could not be parsed or its folder could not be read,
+	 *  in which case there are no findings.
+	 */
+	error: string | null,
+};
+
+/**  A song was deleted or can no longer be parsed. */
+export type SongRemovedEvent = {
+	groupPath: string,
+	path: string,
+	hash: string,
+};
+
+/**  A song that could not be loaded. */
+export type SongScanFailure = {
+	path: string,
+	/**  The `AppError` variant, e.g. `UltrastarParseError`. */
+	kind: string,
+	message: string,
+};
+
+export type SongScanReport = {
+	groups: SongGroup[],
+	failures: SongScanFailure[],
+	warnings: SongScanWarning[],
+	/**
+	 *  Set if the scan was cancelled, the groups then only hold the songs
+	 *  parsed until then.
+	 */
+	cancelled: boolean,
+};
+
+/**  A folder below the song paths that could not be read. */
+export type SongScanWarning = {
+	path: string,
+	message: string,
+};
+
+/**
+ *  The playable part of a song in milliseconds. `startMs` and `endMs` come
+ *  from `#START` and `#END`, or from the audio start and the last event.
+ */
+export type SongTimeline = {
+	startMs: number | null,
+	endMs: number | null,
+	durationMs: number | null,
+	voices: VoiceTimeline[],
+};
+
+/**  A song was changed, it replaces the song with `previous_hash`. */
+export type SongUpdatedEvent = {
+	groupPath: string,
+	path: string,
+	previousHash: string,
+	song: LocalSong,
+};
+
 export type StartParsingEvent = {
-	total_songs: number,
+	totalSongs: number,
+};
+
+/**  A `B <beat> <bpm>` line in the note body, switching the tempo from `beat` on. */
+export type TempoChange = {
+	beat: number,
+	bpm: number | null,
+};
+
+/**
+ *  URLs of an image scaled down to each of [`THUMBNAIL_SIZES`], served by the
+ *  media server as `/thumb/<size>/<path>`.
+ */
+export type ThumbnailUrls = {
+	small: string,
+	medium: string,
+	large: string,
+};
+
+/**
+ *  A line or note of a voice with absolute times in milliseconds from the
+ *  start of the audio. `phrase` and `note` index into `Voice::phrases` and
+ *  `Phrase::notes`.
+ */
+export type TimelineEvent = {
+	kind: TimelineEventKind,
+	phrase: number,
+	note: number | null,
+	startBeat: number,
+	endBeat: number,
+	startMs: number | null,
+	endMs: number | null,
+	noteType: NoteType | null,
+	midiNote: number | null,
+	text: string | null,
 };
 
+/**  A `Line` spans a phrase from its first note to the line break that removes it. */
+export type TimelineEventKind = "Line" | "Note";
+
 /**  Lightweight entry from USDB search results (no note data). */
 export type UsdbSearchEntry = {
 	songId: number,
@@ -234,9 +557,16 @@ export type UsdbSyncProgressEvent = {
 };
 
 export type Voice = {
+	/**  Singer name from `#P<n>`/`#DUETSINGERP<n>`. */
+	name: string | null,
 	phrases: Phrase[],
 };
 
+export type VoiceTimeline = {
+	/**  Sorted by start time, a line comes before the notes it contains. */
+	events: TimelineEvent[],
+};
+
 /* Tauri Specta runtime */
 async function typedError<T, E>(result: Promise<T>): Promise<{ status: "ok"; data: T } | { status: "error"; error: E }> {
     try {
@@ -30,7 +30,7 @@ export default function Lyrics(props: LyricsProps) {
       return;
     }
 
-    const percentage = ((beat - startBeat) * -100) / msToBeatWithoutGap(song, 3000);
+    const percentage = ((beat - startBeat) * -100) / msToBeatWithoutGap(song, 3000, startBeat);
     return {
       end: percentage,
       start: percentage + 30,
@@ -94,13 +94,15 @@ export default function SongPlayer(props: SongPlayerProps) {
   // Persistent media elements to avoid repeated decoder/createMediaElementSource setup (leaks on macOS/WebKit)
   let audioElementRef!: HTMLAudioElement;
   let videoElementRef!: HTMLVideoElement;
+  let cdgCanvasRef!: HTMLCanvasElement;
 
   const videoActive = () => !!currentVideoUrl() && !videoError();
+  const cdgActive = () => !!props.song?.cdgUrl && !videoActive();
 
   // Fallback visual: background image or cover art when video is not active
   const fallbackVisual = createMemo(() => {
     const song = props.song;
-    if (!song || videoActive()) return null;
+    if (!song || videoActive() || cdgActive()) return null;
     if (song.backgroundUrl) return { type: "background" as const, url: song.backgroundUrl };
     if (song.coverUrl) return { type: "cover" as const, url: song.coverUrl };
     return null;
@@ -216,6 +218,42 @@ export default function SongPlayer(props: SongPlayerProps) {
     }
   });
 
+  // CD+G frames are rendered by the media server for the current audio time
+  createEffect(() => {
+    const cdgUrl = cdgActive() ? props.song?.cdgUrl : undefined;
+    const context = cdgCanvasRef.getContext("2d");
+    if (!cdgUrl || !context) return;
+
+    let stopped = false;
+    let retryTimeout: ReturnType<typeof setTimeout> | undefined;
+
+    // The next frame is only requested once the previous one is drawn
+    const drawFrame = async () => {
+      if (stopped) return;
+
+      try {
+        const time = Math.round(audioElementRef.currentTime * 1000);
+        const response = await fetch(`${cdgUrl}?time=${time}`);
+        const frame = await createImageBitmap(await response.blob());
+        if (!stopped) {
+          context.drawImage(frame, 0, 0, cdgCanvasRef.width, cdgCanvasRef.height);
+        }
+        frame.close();
+        requestAnimationFrame(drawFrame);
+      } catch (error) {
+        console.warn("Failed to load CD+G frame:", error);
+        retryTimeout = setTimeout(drawFrame, 1000);
+      }
+    };
+
+    requestAnimationFrame(drawFrame);
+
+    onCleanup(() => {
+      stopped = true;
+      clearTimeout(retryTimeout);
+    });
+  });
+
   // Video volume (only when there's no separate audio track)
   createEffect(() => {
     if (!currentVideoUrl()) return;
@@ -628,6 +666,16 @@ export default function SongPlayer(props: SongPlayerProps) {
         onError={handleVideoError}
       />
 
+      <canvas
+        ref={cdgCanvasRef}
+        aria-label="Song karaoke graphics"
+        width={300}
+        height={216}
+        class="h-full w-full object-contain"
+        classList={{ hidden: !cdgActive() }}
+        style={{ "image-rendering": "pixelated" }}
+      />
+
       <Show when={fallbackVisual()}>
         {(visual) => (
           <Show
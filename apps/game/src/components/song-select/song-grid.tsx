@@ -308,7 +308,11 @@ function SongGridCard(props: SongGridCardProps) {
       class="relative aspect-square w-full cursor-pointer overflow-hidden rounded-lg shadow-md transition-all duration-150 active:scale-95"
       classList={{ "scale-105 ring-4 ring-white": props.selected }}
     >
-      <img class="h-full w-full object-cover" src={props.song.coverUrl ?? ""} alt={props.song.title} />
+      <img
+        class="h-full w-full object-cover"
+        src={props.song.coverThumbnailUrls?.medium ?? props.song.coverUrl ?? ""}
+        alt={props.song.title}
+      />
       <div class="absolute inset-0 -z-1 bg-black" />
     </div>
   );
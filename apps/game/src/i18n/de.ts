@@ -202,6 +202,7 @@ const de = {
           normal: "Normal",
           preferInstrumental: "Instrumental bevorzugen",
         },
+        analyzeLoudness: "Songlautstärke angleichen",
         songSelectStyle: "Songauswahl-Stil",
         songSelectStyleOptions: {
           coverflow: "Coverflow",
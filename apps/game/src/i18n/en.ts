@@ -202,6 +202,7 @@ const en = {
           normal: "Normal",
           preferInstrumental: "Prefer Instrumental",
         },
+        analyzeLoudness: "Normalize Song Volume",
         songSelectStyle: "Song Select Style",
         songSelectStyleOptions: {
           coverflow: "Coverflow",
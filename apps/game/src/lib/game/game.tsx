@@ -157,7 +157,7 @@ export function createGame(options: Accessor<CreateGameOptions>) {
     if (!song) return;
 
     // One beat as the analysis window; Rust converts to samples and clamps it.
-    const windowMs = beatToMsWithoutGap(song, 1);
+    const windowMs = beatToMsWithoutGap(song, 1, beat());
 
     void (async () => {
       const result = await commands.getPitches(windowMs);
@@ -76,7 +76,7 @@ export function createPlayer(options: Accessor<CreatePlayerOptions>) {
     if (!song) {
       return 0;
     }
-    const delayInBeats = msToBeatWithoutGap(song, microphone().delay);
+    const delayInBeats = msToBeatWithoutGap(song, microphone().delay, game.beat());
 
     return game.beat() - delayInBeats;
   });
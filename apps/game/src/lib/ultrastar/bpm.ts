@@ -1,24 +1,38 @@
 import type { Song } from "./song";
 
 export function msToBeat(song: Song, ms: number) {
-  return msToBeatWithoutGap(song, ms - song.gap);
+  // Find the last tempo change before `ms`, the tempo stays constant from there on
+  let position = 0;
+  let bpm = song.bpm;
+  for (const change of song.tempoChanges) {
+    if (beatToMs(song, change.beat) >= ms) break;
+    position = change.beat;
+    bpm = change.bpm;
+  }
+
+  return position + ((ms - beatToMs(song, position)) * bpm * 4) / 1000.0 / 60.0;
 }
 
-export function msToBeatWithoutGap(song: Song, ms: number) {
-  const bpm = getBpm(song);
-  return (bpm * ms) / 1000.0 / 60.0;
+/** Number of beats in the `ms` milliseconds leading up to `beat`, following the tempo changes in between */
+export function msToBeatWithoutGap(song: Song, ms: number, beat: number) {
+  return beat - msToBeat(song, beatToMs(song, beat) - ms);
 }
 
 export function beatToMs(song: Song, beat: number) {
-  return beatToMsWithoutGap(song, beat) + song.gap;
-}
+  let ms = song.gap;
+  let position = 0;
+  let bpm = song.bpm;
+  for (const change of song.tempoChanges) {
+    if (change.beat >= beat) break;
+    ms += ((change.beat - position) * 1000.0 * 60.0) / (bpm * 4);
+    position = change.beat;
+    bpm = change.bpm;
+  }
 
-export function beatToMsWithoutGap(song: Song, beat: number) {
-  const bpm = getBpm(song);
-  return (beat * 1000.0 * 60.0) / bpm;
+  return ms + ((beat - position) * 1000.0 * 60.0) / (bpm * 4);
 }
 
-function getBpm(song: Song) {
-  // Multiply by 4 because UltraStar songs use bars per minute instead of beats per minute
-  return song.bpm * 4;
+/** Milliseconds that `beats` beats starting at `beat` take, following the tempo changes in between */
+export function beatToMsWithoutGap(song: Song, beats: number, beat: number) {
+  return beatToMs(song, beat + beats) - beatToMs(song, beat);
 }
@@ -40,12 +40,13 @@ function LoadingComponent() {
   });
 
   const onProgress = (event: Event<ProgressEvent>) => {
-    setCurrentSongs((currentSongs) => currentSongs + 1);
+    setCurrentSongs(event.payload.processed);
+    setTotalSongs(event.payload.total);
     setCurrentSong(event.payload.song);
   };
 
   const onStartParsing = (event: Event<StartParsingEvent>) => {
-    setTotalSongs(event.payload.total_songs);
+    setTotalSongs(event.payload.totalSongs);
   };
 
   onMount(() => {
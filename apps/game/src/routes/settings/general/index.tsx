@@ -79,6 +79,14 @@ function GeneralSettingsComponent() {
       onChange: (value) => setGeneral({ ...general(), micPlaybackEnabled: value === "yes" }),
       renderValue: (value) => t(`common.${value as "yes" | "no"}`),
     },
+    {
+      type: "select-string",
+      label: t("settings.sections.general.analyzeLoudness"),
+      value: () => (general().analyzeLoudness ? "yes" : "no"),
+      options: ["no", "yes"],
+      onChange: (value) => setGeneral({ ...general(), analyzeLoudness: value === "yes" }),
+      renderValue: (value) => t(`common.${value as "yes" | "no"}`),
+    },
     {
       type: "select-string",
       label: t("settings.sections.general.songSelectStyle"),
@@ -14,6 +14,7 @@ const settingsStoreSchema = v.object({
     micPlaybackEnabled: v.fallback(v.boolean(), false),
     songSelectStyle: v.fallback(v.picklist(["coverflow", "grid"]), "coverflow"),
     outputLatency: v.fallback(v.number(), 0),
+    analyzeLoudness: v.fallback(v.boolean(), false),
   }),
   volume: v.object({
     master: v.number(),
@@ -53,6 +54,7 @@ const defaultSettings: SettingsStore = {
     micPlaybackEnabled: false,
     songSelectStyle: "coverflow",
     outputLatency: 0,
+    analyzeLoudness: false,
   },
   volume: {
     master: 1,
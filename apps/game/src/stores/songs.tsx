@@ -1,7 +1,7 @@
 import { ReactiveMap } from "@solid-primitives/map";
-import { createMemo } from "solid-js";
+import { createMemo, createSignal } from "solid-js";
 
-import { commands } from "~/bindings";
+import { commands, events, type SongScanFailure, type SongScanWarning } from "~/bindings";
 import type { LocalSong } from "~/lib/ultrastar/song";
 
 import { settings, updateSettings } from "./settings";
@@ -10,30 +10,97 @@ function createSongsStore() {
   const paths = () => settings().songs.paths;
 
   const localSongs = new ReactiveMap<string, LocalSong[]>();
+  const [scanFailures, setScanFailures] = createSignal<SongScanFailure[]>([]);
+  const [scanWarnings, setScanWarnings] = createSignal<SongScanWarning[]>([]);
 
   const addSongPath = (path: string) => {
     updateSettings("songs", "paths", (prev: string[]) => [...prev, path]);
   };
 
+  const watchLocalSongs = async () => {
+    const result = await commands.watchSongPaths(Array.from(localSongs.keys()), null);
+
+    if (result.status === "error") {
+      console.error("Failed to watch local songs:", result.error);
+    }
+  };
+
   const removeSongPath = (path: string) => {
     updateSettings("songs", "paths", (prev: string[]) => prev.filter((p: string) => p !== path));
     localSongs.delete(path);
+    watchLocalSongs();
+  };
+
+  const replaceSong = (groupPath: string, hash: string | null, song: LocalSong | null) => {
+    const songs = localSongs.get(groupPath);
+    if (!songs) {
+      return;
+    }
+
+    const remaining = hash === null ? songs : songs.filter((s) => s.hash !== hash);
+    localSongs.set(groupPath, song ? [...remaining, song] : remaining);
+  };
+
+  events.songAddedEvent.listen((event) => replaceSong(event.payload.groupPath, null, event.payload.song));
+  events.songUpdatedEvent.listen((event) =>
+    replaceSong(event.payload.groupPath, event.payload.previousHash, event.payload.song),
+  );
+  events.songRemovedEvent.listen((event) => replaceSong(event.payload.groupPath, event.payload.hash, null));
+
+  events.loudnessProgressEvent.listen((event) => {
+    const { hash, replayGainTrackGain, replayGainTrackPeak } = event.payload;
+    if (replayGainTrackGain === null) {
+      return;
+    }
+
+    for (const [groupPath, songs] of localSongs.entries()) {
+      if (songs.some((song) => song.hash === hash)) {
+        localSongs.set(
+          groupPath,
+          songs.map((song) => (song.hash === hash ? { ...song, replayGainTrackGain, replayGainTrackPeak } : song)),
+        );
+      }
+    }
+  });
+
+  const analyzeLoudness = async () => {
+    if (!settings().general.analyzeLoudness) {
+      return;
+    }
+
+    const result = await commands.analyzeSongLoudness(Array.from(localSongs.keys()));
+
+    if (result.status === "error") {
+      console.error("Failed to analyze song loudness:", result.error);
+    }
   };
 
   const updateLocalSongs = async (paths: string[]) => {
     try {
       const pathsToUpdate = paths.filter((path: string) => !localSongs.has(path));
 
-      const result = await commands.parseSongsFromPaths(pathsToUpdate);
+      // The analysis works on the library index the scan is about to replace
+      await commands.cancelLoudnessAnalysis();
+      const result = await commands.parseSongsFromPaths(pathsToUpdate, null);
 
       if (result.status === "error") {
         console.error("Failed to update local songs:", result.error);
         return;
       }
 
-      for (const group of result.data) {
+      setScanFailures(result.data.failures);
+      setScanWarnings(result.data.warnings);
+
+      if (result.data.cancelled) {
+        return;
+      }
+
+      for (const group of result.data.groups) {
         localSongs.set(group.path, group.songs);
       }
+
+      await watchLocalSongs();
+      analyzeLoudness();
     } catch (error) {
       console.error("Failed to update local songs:", error);
     }
@@ -63,6 +130,8 @@ function createSongsStore() {
     updateLocalSongs,
     needsUpdate,
     songs,
+    scanFailures,
+    scanWarnings,
   };
 }
 